@@ -1,6 +1,8 @@
 #![no_std]
 
-use syscall::{ModuleRequest, RawModuleRequest};
+use core::ops::Range;
+use memory::page::Frame;
+use syscall::ModuleRequest;
 
 extern crate alloc;
 
@@ -8,22 +10,16 @@ pub trait Device: Send + Sync {
     fn name(&self) -> &'static str;
     fn read(&self, offset: usize, buf: &mut [u8]) -> Option<usize>;
     fn write(&self, offset: usize, buf: &[u8]) -> Option<usize>;
+    /// Frames of the device registers, for shared mappings of the device node.
+    fn registers(&self) -> Option<Range<Frame>> {
+        None
+    }
 }
 
+#[derive(ModuleRequest)]
 pub enum DevRequest<'a> {
+    #[capability(SYS_MODULE)]
     RegisterDev(&'a &'static dyn Device),
-}
-
-impl<'a> ModuleRequest<'a> for DevRequest<'a> {
-    fn as_raw(&'a self) -> RawModuleRequest<'a> {
-        match self {
-            Self::RegisterDev(dev) => RawModuleRequest::new(0, dev, &(), &()),
-        }
-    }
-    fn from_raw(raw: RawModuleRequest<'a>) -> Self {
-        match raw.id() {
-            0 => Self::RegisterDev(raw.arg(0)),
-            _ => panic!("Unknown request"),
-        }
-    }
+    #[capability(SYS_MODULE)]
+    UnregisterDev(&'a str),
 }
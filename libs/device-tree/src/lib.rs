@@ -52,6 +52,19 @@ impl<'buf, 'index> DeviceTree<'buf, 'index> {
         }
         None
     }
+
+    /// Find all nodes with the given `device_type` property, e.g. `"cpu"` or `"memory"`.
+    pub fn device_type<'x>(&'x self, ty: &'x str) -> impl Iterator<Item = Node> + 'x {
+        self.index
+            .nodes()
+            .filter(move |n| {
+                n.props()
+                    .find(|p| p.name() == Ok("device_type"))
+                    .and_then(|p| p.str().ok())
+                    == Some(ty)
+            })
+            .map(|node| Node { node })
+    }
 }
 
 #[derive(Clone)]
@@ -92,6 +105,21 @@ impl<'a, 'index: 'a, 'buf: 'index> Node<'a, 'index, 'buf> {
         self.node.parent().map(|node| Node { node })
     }
 
+    pub fn name(&self) -> Option<&'buf str> {
+        self.node.name().ok()
+    }
+
+    /// Read a string property. Returns the first string for string-list properties.
+    pub fn prop_str(&self, name: &str) -> Option<&'buf str> {
+        let prop = self.node.props().find(|p| p.name() == Ok(name))?;
+        prop.str().ok()
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        let prop = self.node.props().find(|p| p.name() == Ok(name))?;
+        prop.u32(0).ok()
+    }
+
     pub fn ranges<'x>(
         &'x self,
     ) -> Option<impl Iterator<Item = (Address<P>, Address<P>, usize)> + 'x> {
@@ -145,6 +173,7 @@ impl<'a, 'index: 'a, 'buf: 'index> Node<'a, 'index, 'buf> {
                 _ => unreachable!(),
             };
             let size = match size_cells {
+                0 => 0,
                 1 => Self::read_u32(&mut buf) as usize,
                 2 => Self::read_u64(&mut buf) as usize,
                 _ => unreachable!(),
@@ -10,10 +10,12 @@ use core::ops::Range;
 
 use memory::address::{Address, V};
 use memory::page::{Page, PageSize, Size4K};
-use xmas_elf::sections::ShType;
+use xmas_elf::header::{self, Class};
+use xmas_elf::sections::{SectionData, SectionHeader_, ShType};
+use xmas_elf::symbol_table::{Binding, Entry};
 use xmas_elf::{
     dynamic,
-    program::{ProgramHeader, SegmentData, Type},
+    program::{ProgramHeader, ProgramHeader64, SegmentData, Type},
     sections::Rela,
     ElfFile,
 };
@@ -24,6 +26,9 @@ pub struct ELFLoader<'a, 'b, 'c> {
     vaddr_offset: isize,
     map_pages: &'b mut dyn FnMut(Range<Page>) -> Range<Page>,
     translate: Option<&'c dyn Fn(Address) -> Address>,
+    resolve: Option<&'c mut dyn FnMut(&str) -> Option<Address>>,
+    /// Maps the segments instead of copying them
+    map_segment: Option<&'c mut dyn FnMut(Segment) -> Result<(), &'static str>>,
 }
 
 impl<'a, 'b, 'c> ELFLoader<'a, 'b, 'c> {
@@ -31,14 +36,18 @@ impl<'a, 'b, 'c> ELFLoader<'a, 'b, 'c> {
         data: &'a [u8],
         map_pages: &'b mut dyn FnMut(Range<Page>) -> Range<Page>,
         translate: Option<&'c dyn Fn(Address) -> Address>,
-    ) -> Self {
-        ELFLoader {
+        resolve: Option<&'c mut dyn FnMut(&str) -> Option<Address>>,
+        map_segment: Option<&'c mut dyn FnMut(Segment) -> Result<(), &'static str>>,
+    ) -> Result<Self, &'static str> {
+        Ok(ELFLoader {
             data: data,
-            elf: ElfFile::new(data).unwrap(),
+            elf: ElfFile::new(data)?,
             vaddr_offset: 0,
             map_pages,
             translate,
-        }
+            resolve,
+            map_segment,
+        })
     }
 
     fn addr(&self, a: Address) -> Address {
@@ -169,6 +178,55 @@ impl<'a, 'b, 'c> ELFLoader<'a, 'b, 'c> {
         Ok(())
     }
 
+    /// The pages of a segment, and the part of the file they are mapped from.
+    fn segment(&self, ph: ProgramHeader) -> Segment {
+        let start: Address = Address::from(ph.virtual_addr() as usize) + self.vaddr_offset;
+        let end = start + ph.mem_size() as usize;
+        let pages = Page::containing(start)..Page::new(end.align_up(Size4K::BYTES));
+        Segment {
+            pages,
+            offset: ph.offset() as usize - (start - Page::<Size4K>::align(start)),
+            file_end: (ph.offset() + ph.file_size()) as usize,
+            readable: ph.flags().is_read(),
+            writable: ph.flags().is_write(),
+            executable: ph.flags().is_execute(),
+        }
+    }
+
+    /// Check that each segment can be mapped from the file by pages: Its contents are in the file, its file offset
+    /// is aligned like its address, and no other segment uses its pages.
+    fn check_segments(&self) -> Result<(), &'static str> {
+        let loads = || {
+            self.elf
+                .program_iter()
+                .filter(|ph| ph.get_type() == Ok(Type::Load))
+        };
+        if loads().next().is_none() {
+            return Err("no loadable segment");
+        }
+        for (i, a) in loads().enumerate() {
+            if a.file_size() > a.mem_size() {
+                return Err("segment is larger in the file than in memory");
+            }
+            if a.virtual_addr()
+                .checked_add(a.mem_size())
+                .map_or(true, |end| end > MAX_VADDR)
+            {
+                return Err("segment is out of the address space");
+            }
+            if (a.offset() ^ a.virtual_addr()) & Size4K::MASK as u64 != 0 {
+                return Err("segment is not aligned");
+            }
+            let a = self.segment(a);
+            for b in loads().skip(i + 1).map(|b| self.segment(b)) {
+                if a.pages.start < b.pages.end && b.pages.start < a.pages.end {
+                    return Err("segments share a page");
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn flush_segment(&self, ph: ProgramHeader) -> Result<(), &'static str> {
         let start: Address = Address::from(ph.virtual_addr() as usize) + self.vaddr_offset;
         let bytes = ph.file_size() as usize;
@@ -176,62 +234,217 @@ impl<'a, 'b, 'c> ELFLoader<'a, 'b, 'c> {
         Ok(())
     }
 
-    fn apply_relocation(&self, ph: ProgramHeader) -> Result<(), &'static str> {
+    /// Address of a dynamic symbol. Undefined symbols are looked up by `resolve`.
+    fn symbol_address(&mut self, index: u32) -> Result<Address, &'static str> {
+        let symbols = match self
+            .elf
+            .find_section_by_name(".dynsym")
+            .ok_or("dynsym not found")?
+            .get_data(&self.elf)?
+        {
+            SectionData::DynSymbolTable64(symbols) => symbols,
+            _ => return Err("bad dynsym"),
+        };
+        let symbol = symbols.get(index as usize).ok_or("bad symbol index")?;
+        if symbol.shndx() != 0 {
+            return Ok(Address::from(symbol.value() as usize) + self.vaddr_offset);
+        }
+        let name = symbol.get_name(&self.elf)?;
+        if let Some(address) = self.resolve.as_mut().and_then(|resolve| resolve(name)) {
+            return Ok(address);
+        }
+        if symbol.get_binding() == Ok(Binding::Weak) {
+            return Ok(Address::ZERO);
+        }
+        log!("unresolved symbol: {}", name);
+        Err("unresolved symbol")
+    }
+
+    fn relocate(&mut self, rela: &Rela<u64>) -> Result<(), &'static str> {
+        let slot = self.addr(Address::from(rela.get_offset() as usize) + self.vaddr_offset);
+        match rela.get_type() {
+            8 /* R_AMD64_RELATIVE */ | 1027 /* R_AARCH64_RELATIVE */ => {
+                let value = Address::<V>::from(rela.get_addend() as usize) + self.vaddr_offset;
+                unsafe { slot.store(value) }
+            }
+            1 /* R_AMD64_64 */ | 6 /* R_AMD64_GLOB_DAT */ | 7 /* R_AMD64_JUMP_SLOT */ |
+            257 /* R_AARCH64_ABS64 */ | 1025 /* R_AARCH64_GLOB_DAT */ | 1026 /* R_AARCH64_JUMP_SLOT */ => {
+                let symbol = self.symbol_address(rela.get_symbol_table_index())?;
+                let value = symbol + rela.get_addend() as usize;
+                unsafe { slot.store(value) }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// File offsets and lengths of the relocation tables of the dynamic segment `ph`.
+    fn relocation_tables(
+        &self,
+        ph: ProgramHeader,
+    ) -> Result<[Option<(usize, usize)>; 2], &'static str> {
         let data = match ph.get_data(&self.elf)? {
             SegmentData::Dynamic64(data) => data,
             _ => unreachable!(),
         };
-        let rela = data.iter().find_map(|x| {
-            if x.get_tag().ok()? == dynamic::Tag::Rela {
-                Some(x.get_ptr().ok()? as usize)
-            } else {
-                None
-            }
-        });
-        let rela_offset = match rela {
-            Some(x) => x,
-            _ => return Ok(()),
-        };
-        let rela_size = data
-            .iter()
-            .find_map(|x| {
-                if x.get_tag().ok()? == dynamic::Tag::RelaSize {
-                    Some(x.get_val().ok()? as usize)
+        let find = |tag: dynamic::Tag<u64>| {
+            data.iter().find_map(|x| {
+                if x.get_tag().ok()? == tag {
+                    x.get_val()
+                        .or_else(|_| x.get_ptr())
+                        .ok()
+                        .map(|x| x as usize)
                 } else {
                     None
                 }
             })
-            .ok_or("relasize not found")?;
-        let rela_ent = data
-            .iter()
-            .find_map(|x| {
-                if x.get_tag().ok()? == dynamic::Tag::RelaEnt {
-                    Some(x.get_val().ok()? as usize)
-                } else {
-                    None
-                }
-            })
-            .ok_or("relaent not found")?;
-        let relas = unsafe {
-            core::slice::from_raw_parts(
-                &self.data[rela_offset] as *const u8 as *const Rela<u64>,
-                rela_size / rela_ent,
-            )
         };
-        for rela in relas {
-            match rela.get_type() {
-                8 /* R_AMD64_RELATIVE */ | 1027 /* R_AARCH64_RELATIVE */ => {
-                    let slot = self.addr(Address::from(rela.get_offset() as usize)+ self.vaddr_offset);
-                    let value = Address::<V>::from(rela.get_addend() as usize) + self.vaddr_offset;
-                    unsafe { slot.store(value) }
+        // `DT_RELA` holds the data relocations, and `DT_JMPREL` the PLT relocations
+        let mut tables = [None, None];
+        if let Some(rela_offset) = find(dynamic::Tag::Rela) {
+            let rela_size = find(dynamic::Tag::RelaSize).ok_or("relasize not found")?;
+            let rela_ent = find(dynamic::Tag::RelaEnt).ok_or("relaent not found")?;
+            if rela_ent != core::mem::size_of::<Rela<u64>>() {
+                return Err("bad relaent");
+            }
+            tables[0] = Some((rela_offset, rela_size / rela_ent));
+        }
+        if let Some(rela_offset) = find(dynamic::Tag::JmpRel) {
+            let rela_size = find(dynamic::Tag::PltRelSize).ok_or("pltrelsz not found")?;
+            tables[1] = Some((rela_offset, rela_size / core::mem::size_of::<Rela<u64>>()));
+        }
+        Ok(tables)
+    }
+
+    /// The relocations in a table returned by `relocation_tables`.
+    fn relocations(&self, (offset, len): (usize, usize)) -> &'a [Rela<u64>] {
+        let elf_data: &'a [u8] = self.data;
+        unsafe {
+            core::slice::from_raw_parts(&elf_data[offset] as *const u8 as *const Rela<u64>, len)
+        }
+    }
+
+    fn apply_relocation(&mut self, ph: ProgramHeader) -> Result<(), &'static str> {
+        for table in self.relocation_tables(ph)?.into_iter().flatten() {
+            for rela in self.relocations(table) {
+                self.relocate(rela)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `start..start + size` is in a writable loaded segment, at the linked addresses.
+    fn writable(&self, start: u64, size: u64) -> bool {
+        self.elf.program_iter().any(|ph| {
+            ph.get_type() == Ok(Type::Load)
+                && ph.flags().is_write()
+                && start >= ph.virtual_addr()
+                && start
+                    .checked_add(size)
+                    .map_or(false, |end| end <= ph.virtual_addr() + ph.mem_size())
+        })
+    }
+
+    /// Check that the program and section headers, and the contents of the segments, are in the file.
+    fn check_headers(&self) -> Result<(), &'static str> {
+        let pt2 = &self.elf.header.pt2;
+        let table_end = |offset: u64, count: u16, size: u16| {
+            offset
+                .checked_add(count as u64 * size as u64)
+                .map_or(false, |end| end <= self.data.len() as u64)
+        };
+        if pt2.ph_entry_size() as usize != core::mem::size_of::<ProgramHeader64>()
+            || !table_end(pt2.ph_offset(), pt2.ph_count(), pt2.ph_entry_size())
+        {
+            return Err("bad program headers");
+        }
+        if pt2.sh_count() != 0
+            && (pt2.sh_entry_size() as usize != core::mem::size_of::<SectionHeader_<u64>>()
+                || !table_end(pt2.sh_offset(), pt2.sh_count(), pt2.sh_entry_size()))
+        {
+            return Err("bad section headers");
+        }
+        for ph in self.elf.program_iter() {
+            if ph
+                .offset()
+                .checked_add(ph.file_size())
+                .map_or(true, |end| end > self.data.len() as u64)
+            {
+                return Err("segment is out of the file");
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the relocations only patch writable loaded memory, without resolving symbols.
+    fn check_relocations(&self) -> Result<(), &'static str> {
+        let dynamic = self
+            .elf
+            .program_iter()
+            .find(|ph| ph.get_type() == Ok(Type::Dynamic));
+        let dynamic = match dynamic {
+            Some(ph)
+                if ph.file_size() % core::mem::size_of::<dynamic::Dynamic<u64>>() as u64 != 0 =>
+            {
+                return Err("bad dynamic segment")
+            }
+            Some(ph) => ph,
+            None => return Ok(()),
+        };
+        for (offset, len) in self.relocation_tables(dynamic)?.into_iter().flatten() {
+            let size = len * core::mem::size_of::<Rela<u64>>();
+            if offset % core::mem::align_of::<Rela<u64>>() != 0
+                || offset
+                    .checked_add(size)
+                    .map_or(true, |end| end > self.data.len())
+            {
+                return Err("relocation table is out of the file");
+            }
+            for rela in self.relocations((offset, len)) {
+                match rela.get_type() {
+                    8 /* R_AMD64_RELATIVE */ | 1027 /* R_AARCH64_RELATIVE */ => {}
+                    _ => return Err("unsupported relocation"),
+                }
+                if !self.writable(rela.get_offset(), core::mem::size_of::<u64>() as u64) {
+                    return Err("relocation is out of the writable segments");
                 }
-                _ => {}
             }
         }
         Ok(())
     }
 
+    /// Check that the thread-local storage image is in a loaded segment.
+    fn check_tls(&self) -> Result<(), &'static str> {
+        let tls = match self
+            .elf
+            .program_iter()
+            .find(|ph| ph.get_type() == Ok(Type::Tls))
+        {
+            Some(tls) => tls,
+            None => return Ok(()),
+        };
+        if tls.file_size() > tls.mem_size() || !(tls.align() == 0 || tls.align().is_power_of_two())
+        {
+            return Err("bad tls segment");
+        }
+        let start = tls.virtual_addr();
+        let loaded = self.elf.program_iter().any(|ph| {
+            ph.get_type() == Ok(Type::Load)
+                && start >= ph.virtual_addr()
+                && start
+                    .checked_add(tls.file_size())
+                    .map_or(false, |end| end <= ph.virtual_addr() + ph.mem_size())
+        });
+        if !loaded {
+            return Err("tls image is not loaded");
+        }
+        Ok(())
+    }
+
     fn do_load(&mut self) -> Result<ELFEntry<'a>, &'static str> {
+        if self.map_segment.is_some() {
+            self.check_segments()?;
+        }
         self.map_memory()?;
         for ph in self
             .elf
@@ -239,20 +452,25 @@ impl<'a, 'b, 'c> ELFLoader<'a, 'b, 'c> {
             .filter(|ph| ph.get_type() == Ok(Type::Load))
         {
             // log!("Load {:?}", ph);
-            self.load_segment(ph)?;
+            let segment = self.segment(ph);
+            match self.map_segment.as_mut() {
+                Some(map_segment) => map_segment(segment)?,
+                None => self.load_segment(ph)?,
+            }
         }
-        for ph in self
+        let dynamic = self
             .elf
             .program_iter()
-            .filter(|ph| ph.get_type() == Ok(Type::Dynamic))
-        {
+            .find(|ph| ph.get_type() == Ok(Type::Dynamic));
+        if let Some(ph) = dynamic {
             // log!("Relo {:?}", ph);
             self.apply_relocation(ph)?;
         }
+        // Mapped segments are flushed when their pages are populated
         for ph in self
             .elf
             .program_iter()
-            .filter(|ph| ph.get_type() == Ok(Type::Load))
+            .filter(|ph| ph.get_type() == Ok(Type::Load) && self.map_segment.is_none())
         {
             self.flush_segment(ph)?;
         }
@@ -268,14 +486,29 @@ impl<'a, 'b, 'c> ELFLoader<'a, 'b, 'c> {
                 None
             }
         });
-        Ok(ELFEntry { entry, init_array })
+        let tls = self
+            .elf
+            .program_iter()
+            .find(|ph| ph.get_type() == Ok(Type::Tls))
+            .map(|ph| TLSTemplate {
+                start: Address::from(ph.virtual_addr() as usize) + self.vaddr_offset,
+                file_size: ph.file_size() as usize,
+                mem_size: ph.mem_size() as usize,
+                align: ph.align() as usize,
+            });
+        Ok(ELFEntry {
+            entry,
+            init_array,
+            tls,
+            vaddr_offset: self.vaddr_offset,
+        })
     }
 
     pub fn load(
         data: &'a [u8],
         map_pages: &'b mut dyn FnMut(Range<Page>) -> Range<Page>,
     ) -> Result<ELFEntry<'a>, &'static str> {
-        ELFLoader::new(data, map_pages, None).do_load()
+        ELFLoader::new(data, map_pages, None, None, None)?.do_load()
     }
 
     pub fn load_with_address_translation(
@@ -283,11 +516,101 @@ impl<'a, 'b, 'c> ELFLoader<'a, 'b, 'c> {
         map_pages: &'b mut dyn FnMut(Range<Page>) -> Range<Page>,
         translate: &'c dyn Fn(Address) -> Address,
     ) -> Result<ELFEntry<'a>, &'static str> {
-        ELFLoader::new(data, map_pages, Some(translate)).do_load()
+        ELFLoader::new(data, map_pages, Some(translate), None, None)?.do_load()
+    }
+
+    /// Load a shared object, and resolve its undefined symbols with `resolve`.
+    pub fn load_with_symbols(
+        data: &'a [u8],
+        map_pages: &'b mut dyn FnMut(Range<Page>) -> Range<Page>,
+        resolve: &'c mut dyn FnMut(&str) -> Option<Address>,
+    ) -> Result<ELFEntry<'a>, &'static str> {
+        ELFLoader::new(data, map_pages, None, Some(resolve), None)?.do_load()
+    }
+
+    /// Load an executable without copying its segments. `map_segment` maps each of them from the file, e.g. to be
+    /// populated on demand. Relocations are then applied through the mapped pages.
+    ///
+    /// Fails before mapping anything if the segments cannot be mapped by pages.
+    /// Untrusted executables should be checked by [`check_executable`] first.
+    pub fn load_in_place(
+        data: &'a [u8],
+        map_pages: &'b mut dyn FnMut(Range<Page>) -> Range<Page>,
+        map_segment: &'c mut dyn FnMut(Segment) -> Result<(), &'static str>,
+    ) -> Result<ELFEntry<'a>, &'static str> {
+        ELFLoader::new(data, map_pages, None, None, Some(map_segment))?.do_load()
     }
 }
 
+/// Highest linked address of a loaded segment
+const MAX_VADDR: u64 = 1 << 47;
+
+/// Check that an untrusted executable can be loaded by [`ELFLoader::load_in_place`] without faulting:
+/// It is a 64-bit executable whose headers and segments are in the file, whose segments can be mapped by pages,
+/// and whose relocations and thread-local storage image are in its loaded segments.
+pub fn check_executable(data: &[u8]) -> Result<(), &'static str> {
+    let elf = ElfFile::new(data)?;
+    if elf.header.pt1.class() != Class::SixtyFour {
+        return Err("not a 64-bit ELF");
+    }
+    match elf.header.pt2.type_().as_type() {
+        header::Type::Executable | header::Type::SharedObject => {}
+        _ => return Err("not an executable"),
+    }
+    let mut map_pages = |pages| pages;
+    let loader = ELFLoader::new(data, &mut map_pages, None, None, None)?;
+    loader.check_headers()?;
+    loader.check_segments()?;
+    loader.check_relocations()?;
+    loader.check_tls()
+}
+
+/// Get the linked address range of a section by name.
+pub fn find_section_range(data: &[u8], name: &str) -> Option<Range<Address>> {
+    let elf = ElfFile::new(data).ok()?;
+    let section = elf.find_section_by_name(name)?;
+    let start = Address::from(section.address() as usize);
+    Some(start..start + section.size() as usize)
+}
+
+/// Get the contents of a section by name, without loading the ELF.
+pub fn find_section<'a>(data: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    let elf = ElfFile::new(data).ok()?;
+    let section = elf.find_section_by_name(name)?;
+    Some(section.raw_data(&elf))
+}
+
 pub struct ELFEntry<'a> {
     pub entry: Address,
     pub init_array: Option<&'a [Address]>,
+    /// Initialization image of the thread-local storage
+    pub tls: Option<TLSTemplate>,
+    /// Offset from the linked addresses to the loaded addresses
+    pub vaddr_offset: isize,
+}
+
+/// A `PT_LOAD` segment of a loaded ELF.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// The loaded pages
+    pub pages: Range<Page>,
+    /// Offset in the file of the first page
+    pub offset: usize,
+    /// Offset in the file of the end of the segment contents. The rest of the pages is zeroed.
+    pub file_end: usize,
+    pub readable: bool,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+/// The `PT_TLS` segment of a loaded ELF.
+///
+/// Each thread's TLS block starts with a copy of the `file_size` bytes at `start`,
+/// followed by zeros up to `mem_size`.
+#[derive(Debug, Clone, Copy)]
+pub struct TLSTemplate {
+    pub start: Address,
+    pub file_size: usize,
+    pub mem_size: usize,
+    pub align: usize,
 }
@@ -167,6 +167,9 @@ pub trait InterruptController {
     fn get_irq_handler(&self, irq: usize) -> Option<&IRQHandler>;
     /// Register an IRQ handler.
     fn set_irq_handler(&self, irq: usize, handler: IRQHandler);
+    /// Remove the IRQ handler of a given IRQ.
+    /// Other cores may still be running the returned handler.
+    fn remove_irq_handler(&self, irq: usize) -> Option<IRQHandler>;
 }
 
 /// Timer controller. For initializing and handling timer interrupts.
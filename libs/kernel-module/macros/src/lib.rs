@@ -1,23 +1,136 @@
 use proc_macro::TokenStream;
 use quote::quote;
 
+/// Must match `kernel_module::ModuleInfo`.
+const NOTE_SECTION: &str = ".note.sophon.module";
+const NOTE_OWNER: &str = "Sophon";
+const NOTE_TYPE: u32 = 1;
+
+struct ModuleInfo {
+    name: String,
+    depends: Vec<String>,
+    provides: Vec<String>,
+}
+
+impl ModuleInfo {
+    fn parse(args: syn::AttributeArgs) -> syn::Result<Self> {
+        let mut info = ModuleInfo {
+            name: std::env::var("CARGO_PKG_NAME").unwrap(),
+            depends: vec![],
+            provides: vec![],
+        };
+        for arg in args {
+            match arg {
+                syn::NestedMeta::Meta(syn::Meta::NameValue(nv)) if nv.path.is_ident("name") => {
+                    match nv.lit {
+                        syn::Lit::Str(s) => info.name = s.value(),
+                        lit => return Err(syn::Error::new_spanned(lit, "expected a string")),
+                    }
+                }
+                syn::NestedMeta::Meta(syn::Meta::List(list))
+                    if list.path.is_ident("depends") || list.path.is_ident("provides") =>
+                {
+                    let mut names = vec![];
+                    for nested in list.nested {
+                        match nested {
+                            syn::NestedMeta::Lit(syn::Lit::Str(s)) => names.push(s.value()),
+                            x => return Err(syn::Error::new_spanned(x, "expected a string")),
+                        }
+                    }
+                    if list.path.is_ident("depends") {
+                        info.depends = names;
+                    } else {
+                        info.provides = names;
+                    }
+                }
+                x => {
+                    return Err(syn::Error::new_spanned(
+                        x,
+                        "expected `name = \"..\"`, `depends(..)` or `provides(..)`",
+                    ))
+                }
+            }
+        }
+        Ok(info)
+    }
+
+    /// The strings of the note descriptor: name, version, dependencies and provided services,
+    /// each terminated by a NUL. Lists are comma-separated.
+    fn strings(&self) -> Vec<u8> {
+        let version = std::env::var("CARGO_PKG_VERSION").unwrap();
+        let mut desc = vec![];
+        for field in [
+            &self.name,
+            &version,
+            &self.depends.join(","),
+            &self.provides.join(","),
+        ] {
+            desc.extend_from_slice(field.as_bytes());
+            desc.push(0);
+        }
+        desc
+    }
+}
+
+/// Declare the module instance.
+///
+/// `#[kernel_module(name = "..", depends(..), provides(..))]` records the module metadata.
+/// Dependencies are names of other modules, or services they provide (e.g. `"vfs"`, `"scheduler"`).
+/// The name defaults to the package name.
 #[proc_macro_attribute]
-pub fn kernel_module(_attr: TokenStream, item: TokenStream) -> TokenStream {
+pub fn kernel_module(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = syn::parse_macro_input!(attr as syn::AttributeArgs);
     let input = syn::parse_macro_input!(item as syn::ItemStatic);
     let name = &input.ident;
+    let info = match ModuleInfo::parse(args) {
+        Ok(info) => info,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    // ELF note: owner size, descriptor size and type, then the padded owner and descriptor.
+    // The descriptor starts with the service ABI version and table size.
+    let mut strings = info.strings();
+    let desc_size = (8 + strings.len()) as u32;
+    strings.resize((strings.len() + 3) & !3, 0);
+    let strings_len = strings.len();
+    let mut owner = NOTE_OWNER.as_bytes().to_vec();
+    owner.push(0);
+    let owner_size = owner.len() as u32;
+    owner.resize((owner.len() + 3) & !3, 0);
+    let owner_len = owner.len();
     let result = quote! {
         #input
 
+        #[repr(C, align(4))]
+        struct __ModuleInfoNote {
+            header: [u32; 3],
+            owner: [u8; #owner_len],
+            abi: [u32; 2],
+            strings: [u8; #strings_len],
+        }
+
+        #[used]
+        #[doc(hidden)]
+        #[link_section = #NOTE_SECTION]
+        static __MODULE_INFO: __ModuleInfoNote = __ModuleInfoNote {
+            header: [#owner_size, #desc_size, #NOTE_TYPE],
+            owner: [#(#owner),*],
+            abi: [
+                kernel_module::SERVICE_ABI_VERSION,
+                core::mem::size_of::<kernel_module::KernelServiceTable>() as u32,
+            ],
+            strings: [#(#strings),*],
+        };
+
         #[global_allocator]
         static ALLOCATOR: kernel_module::KernelModuleAllocator = kernel_module::KernelModuleAllocator;
 
         #[no_mangle]
         #[allow(unused)]
         pub extern "C" fn _start(service: kernel_module::KernelServiceWrapper) -> isize {
-            if kernel_module::init_kernel_module(service, unsafe { &#name }).is_err() {
-                return -1;
+            match kernel_module::init_kernel_module(service, unsafe { &#name }) {
+                Ok(()) => 0,
+                Err(e) => e.code(),
             }
-            0
         }
 
         #[panic_handler]
@@ -30,6 +143,149 @@ pub fn kernel_module(_attr: TokenStream, item: TokenStream) -> TokenStream {
     result.into()
 }
 
+/// Generate a `#[repr(C)]` function table for a service trait, and implement the trait for the table.
+///
+/// The table starts with `version` (taken from `SERVICE_ABI_VERSION` in scope) and `size`,
+/// followed by one entry per method, in declaration order.
+#[proc_macro_attribute]
+pub fn service_table(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let table = syn::parse_macro_input!(attr as syn::Ident);
+    let input = syn::parse_macro_input!(item as syn::ItemTrait);
+    let trait_name = &input.ident;
+    let mut fields = vec![];
+    let mut thunks = vec![];
+    let mut methods = vec![];
+    for item in &input.items {
+        let method = match item {
+            syn::TraitItem::Method(m) => m,
+            x => {
+                return syn::Error::new_spanned(x, "service traits can only have methods")
+                    .to_compile_error()
+                    .into()
+            }
+        };
+        let sig = &method.sig;
+        let name = &sig.ident;
+        if sig.generics.type_params().next().is_some()
+            || sig.generics.const_params().next().is_some()
+        {
+            return syn::Error::new_spanned(sig, "service methods cannot be generic over types")
+                .to_compile_error()
+                .into();
+        }
+        let lifetimes = sig.generics.lifetimes().collect::<Vec<_>>();
+        let types = sig
+            .inputs
+            .iter()
+            .filter_map(|arg| match arg {
+                syn::FnArg::Typed(arg) => Some(&arg.ty),
+                syn::FnArg::Receiver(_) => None,
+            })
+            .collect::<Vec<_>>();
+        let args = (0..types.len())
+            .map(|i| quote::format_ident!("__a{}", i))
+            .collect::<Vec<_>>();
+        let output = &sig.output;
+        let unsafety = &sig.unsafety;
+        let binder = if lifetimes.is_empty() {
+            quote!()
+        } else {
+            quote!(for<#(#lifetimes),*>)
+        };
+        fields.push(quote! {
+            #name: #binder unsafe fn(*const () #(, #types)*) #output
+        });
+        thunks.push(quote! {
+            #name: {
+                unsafe fn thunk<#(#lifetimes,)* T: #trait_name>(this: *const () #(, #args: #types)*) #output {
+                    (*(this as *const T)).#name(#(#args),*)
+                }
+                thunk::<T>
+            }
+        });
+        methods.push(quote! {
+            #unsafety fn #name<#(#lifetimes),*>(&self #(, #args: #types)*) #output {
+                unsafe { (self.#name)(self.this #(, #args)*) }
+            }
+        });
+    }
+    let doc = format!("Function table of [`{}`], passed to modules.", trait_name);
+    let result = quote! {
+        #input
+
+        #[doc = #doc]
+        #[repr(C)]
+        pub struct #table {
+            /// The ABI version the table is built for
+            pub version: u32,
+            /// Size of the table in bytes. Newer tables append entries.
+            pub size: u32,
+            this: *const (),
+            #(#fields,)*
+        }
+
+        unsafe impl Send for #table {}
+        unsafe impl Sync for #table {}
+
+        impl #table {
+            pub fn new<T: #trait_name>(this: &'static T) -> Self {
+                Self {
+                    version: SERVICE_ABI_VERSION,
+                    size: core::mem::size_of::<Self>() as u32,
+                    this: this as *const T as *const (),
+                    #(#thunks,)*
+                }
+            }
+        }
+
+        impl #trait_name for #table {
+            #(#methods)*
+        }
+    };
+    result.into()
+}
+
+/// Export a function or static by its name. Modules import it with an `extern "Rust"` block,
+/// and the kernel resolves it when loading them.
+///
+/// The crate must enable `#![feature(used_with_arg)]`, so the symbol survives `--gc-sections`.
+#[proc_macro_attribute]
+pub fn export_symbol(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(item as syn::Item);
+    let (name, address) = match &input {
+        syn::Item::Fn(f) if f.sig.generics.params.is_empty() => {
+            let name = &f.sig.ident;
+            (name, quote!(#name as *const ()))
+        }
+        syn::Item::Static(s) => {
+            let name = &s.ident;
+            (name, quote!(unsafe { &#name } as *const _ as *const ()))
+        }
+        x => {
+            return syn::Error::new_spanned(
+                x,
+                "only non-generic functions and statics can be exported",
+            )
+            .to_compile_error()
+            .into()
+        }
+    };
+    let name_str = name.to_string();
+    let result = quote! {
+        #input
+
+        const _: () = {
+            #[used(linker)]
+            #[link_section = "sophon_ksymtab"]
+            static SYMBOL: ::kernel_module::KernelSymbol = ::kernel_module::KernelSymbol {
+                name: #name_str,
+                address: #address,
+            };
+        };
+    };
+    result.into()
+}
+
 #[proc_macro_attribute]
 pub fn test(_attr: TokenStream, item: TokenStream) -> TokenStream {
     let input = syn::parse_macro_input!(item as syn::ItemFn);
@@ -1,10 +1,32 @@
 use crate::KernelModule;
 use alloc::boxed::Box;
 use core::intrinsics::type_id;
-use syscall::{ModuleRequest, RawModuleRequest};
+use syscall::{CallContext, Error, ModuleRequest, RawModuleRequest};
 
 pub trait ModuleCallHandler: Send + Sync {
-    fn handle<'a>(&self, privileged: bool, request: RawModuleRequest<'a>) -> isize;
+    fn handle<'a>(&self, ctx: &CallContext, request: RawModuleRequest<'a>) -> isize;
+}
+
+pub trait ModuleDeinitHandler: Send + Sync {
+    /// Returns `EBUSY` if the module refuses to be unloaded.
+    fn deinit(&self) -> Result<(), Error>;
+}
+
+pub(crate) fn register_deinit<T: KernelModule>(module: &'static T) {
+    struct DeinitImpl<T: KernelModule> {
+        module: &'static T,
+    }
+    impl<T: KernelModule> ModuleDeinitHandler for DeinitImpl<T> {
+        fn deinit(&self) -> Result<(), Error> {
+            let module = unsafe { &mut *(self.module as *const T as *mut T) };
+            module.deinit().map_err(|e| {
+                ::log::log!("{}: {}", T::NAME, e);
+                Error::EBUSY
+            })
+        }
+    }
+    let handler: &'static DeinitImpl<T> = Box::leak(Box::new(DeinitImpl { module }));
+    crate::SERVICE.register_deinit_handler(handler);
 }
 
 pub(crate) fn register_module_call<T: KernelModule>(module: &'static T) {
@@ -15,9 +37,15 @@ pub(crate) fn register_module_call<T: KernelModule>(module: &'static T) {
         module: &'static T,
     }
     impl<T: KernelModule> ModuleCallHandler for HandlerImpl<T> {
-        fn handle<'a>(&'a self, privileged: bool, raw: RawModuleRequest<'a>) -> isize {
-            let request = <T::ModuleRequest<'a> as ModuleRequest>::from_raw(raw);
-            self.module.handle_module_call(privileged, request)
+        fn handle<'a>(&'a self, ctx: &CallContext, raw: RawModuleRequest<'a>) -> isize {
+            let caps = <T::ModuleRequest<'a> as ModuleRequest>::required_capabilities(raw.id());
+            if !ctx.credentials.can(caps) {
+                return Error::EPERM.into();
+            }
+            match <T::ModuleRequest<'a> as ModuleRequest>::from_raw(raw) {
+                Ok(request) => self.module.handle_module_call(ctx, request),
+                Err(e) => e.into(),
+            }
         }
     }
     let handler: &'static HandlerImpl<T> = Box::leak(Box::new(HandlerImpl { module }));
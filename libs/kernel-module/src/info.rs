@@ -0,0 +1,61 @@
+//! Module metadata, recorded by `#[kernel_module]` in an ELF note section.
+//!
+//! The note descriptor holds the service ABI version and table size as two `u32`s, followed by the
+//! NUL-terminated name, version, dependencies and provided services.
+
+use alloc::vec::Vec;
+use core::str;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleInfo<'a> {
+    /// `SERVICE_ABI_VERSION` the module is built against
+    pub abi_version: u32,
+    /// Size of the `KernelServiceTable` the module is built against
+    pub service_table_size: u32,
+    pub name: &'a str,
+    pub version: &'a str,
+    /// Modules, or services provided by other modules, that must be initialized first
+    pub depends: Vec<&'a str>,
+    /// Services registered by the module's `init`, e.g. `vfs` or `scheduler`
+    pub provides: Vec<&'a str>,
+}
+
+impl<'a> ModuleInfo<'a> {
+    pub const NOTE_SECTION: &'static str = ".note.sophon.module";
+    const NOTE_OWNER: &'static [u8] = b"Sophon\0";
+    const NOTE_TYPE: u32 = 1;
+
+    /// Decode the contents of the note section.
+    pub fn parse(note: &'a [u8]) -> Option<Self> {
+        let word = |i: usize| -> Option<usize> {
+            let bytes = note.get(i * 4..i * 4 + 4)?;
+            Some(u32::from_le_bytes(bytes.try_into().ok()?) as usize)
+        };
+        let (owner_size, desc_size, ty) = (word(0)?, word(1)?, word(2)?);
+        let owner_end = 12 + owner_size;
+        if ty != Self::NOTE_TYPE as usize || note.get(12..owner_end)? != Self::NOTE_OWNER {
+            return None;
+        }
+        let desc_start = (owner_end + 3) & !3;
+        let (abi_version, service_table_size) = (word(desc_start / 4)?, word(desc_start / 4 + 1)?);
+        let strings = note.get(desc_start + 8..desc_start + desc_size)?;
+        let mut fields = str::from_utf8(strings).ok()?.split('\0');
+        Some(Self {
+            abi_version: abi_version as _,
+            service_table_size: service_table_size as _,
+            name: fields.next()?,
+            version: fields.next()?,
+            depends: Self::list(fields.next()?),
+            provides: Self::list(fields.next()?),
+        })
+    }
+
+    fn list(s: &'a str) -> Vec<&'a str> {
+        s.split(',').filter(|s| !s.is_empty()).collect()
+    }
+
+    /// Whether this module is the dependency `name`.
+    pub fn satisfies(&self, name: &str) -> bool {
+        self.name == name || self.provides.contains(&name)
+    }
+}
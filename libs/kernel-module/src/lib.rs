@@ -5,24 +5,29 @@
 #![feature(generic_associated_types)]
 #![feature(never_type)]
 #![feature(core_intrinsics)]
+#![feature(format_args_nl)]
 
 extern crate alloc;
 
 mod call;
 mod heap;
+mod info;
 mod log;
 mod service;
+mod symbol;
 
 pub use ::log::*;
-pub use call::ModuleCallHandler;
+pub use call::{ModuleCallHandler, ModuleDeinitHandler};
 pub use heap::KernelModuleAllocator;
-pub use kernel_module_macros::{kernel_module, test};
-pub use service::{KernelService, KernelServiceWrapper};
+pub use info::ModuleInfo;
+pub use kernel_module_macros::{export_symbol, kernel_module, test};
+pub use service::{KernelService, KernelServiceTable, KernelServiceWrapper, SERVICE_ABI_VERSION};
+pub use symbol::{KernelSymbol, SYMBOL_SECTION};
 pub use testing;
 
 use alloc::vec::Vec;
 use core::ops::Deref;
-use syscall::ModuleRequest;
+use syscall::{CallContext, Error, ModuleRequest};
 use testing::Tests;
 
 static mut SERVICE_OPT: Option<&'static dyn KernelService> = None;
@@ -42,15 +47,22 @@ pub fn module_call<'a>(module: &str, request: &'a impl ModuleRequest<'a>) -> isi
     SERVICE.module_call(module, request.as_raw())
 }
 
+/// Returns `ENOEXEC` if the kernel's service ABI is incompatible, or `EIO` if `init` fails.
 pub fn init_kernel_module<T: KernelModule>(
     service: KernelServiceWrapper,
     instance: &'static T,
-) -> anyhow::Result<()> {
+) -> Result<(), Error> {
+    // None of the service can be used before the ABI is checked
+    service.check_abi()?;
     init_kernel_service(service);
     call::register_module_call::<T>(instance);
+    call::register_deinit::<T>(instance);
     let instance_mut = unsafe { &mut *(instance as *const T as *mut T) };
     // Initialize the module
-    let result = instance_mut.init()?;
+    if let Err(e) = instance_mut.init() {
+        ::log::log!("{}: {}", T::NAME, e);
+        return Err(Error::EIO);
+    }
     // Register any tests
     if cfg!(sophon_test) {
         let mut guard = testing::TESTS.write();
@@ -58,7 +70,7 @@ pub fn init_kernel_module<T: KernelModule>(
         core::mem::swap(&mut tests, &mut guard);
         SERVICE.register_tests(tests);
     }
-    Ok(result)
+    Ok(())
 }
 
 pub trait KernelModule: 'static + Send + Sync {
@@ -68,12 +80,19 @@ pub trait KernelModule: 'static + Send + Sync {
 
     fn init(&'static mut self) -> anyhow::Result<()>;
 
+    /// Undo `init` before the module is unloaded: unregister its devices and file systems.
+    /// IRQ handlers registered through `KernelService::set_irq_handler` are removed by the kernel.
+    /// Modules that do not override this cannot be unloaded.
+    fn deinit(&'static mut self) -> anyhow::Result<()> {
+        anyhow::bail!("unloading is not supported")
+    }
+
     fn handle_module_call<'a>(
         &self,
-        _privileged: bool,
+        _ctx: &CallContext,
         _request: Self::ModuleRequest<'a>,
     ) -> isize {
-        -1
+        Error::ENOSYS.into()
     }
 }
 
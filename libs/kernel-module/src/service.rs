@@ -3,15 +3,26 @@ use core::alloc::Layout;
 use core::any::Any;
 use core::ops::{Deref, Range};
 use device_tree::DeviceTree;
-use interrupt::{InterruptController, TimerController};
+use interrupt::{IRQHandler, InterruptController, TimerController};
 use log::Logger;
 use memory::address::Address;
 use memory::page::{Frame, Page};
 use proc::TaskId;
 use sched::Scheduler;
-use syscall::RawModuleRequest;
+use syscall::{user_memory::UserMemory, Error, RawModuleRequest};
 use testing::Tests;
 
+/// Version of the service ABI.
+///
+/// Bump it when any existing method of [`KernelService`] changes. New methods must be added to the end of the
+/// trait instead, so that modules built against an older, shorter table keep working.
+///
+/// Traits reached through the service, e.g. [`InterruptController`], [`Scheduler`] and [`Logger`], are passed
+/// as Rust trait objects whose vtable layout is not stable. Bump the version when any of them changes,
+/// including when a method is added.
+pub const SERVICE_ABI_VERSION: u32 = 4;
+
+#[kernel_module_macros::service_table(KernelServiceTable)]
 pub trait KernelService: Send + Sync + 'static {
     // === Logging === //
     fn log(&self, s: &str);
@@ -23,6 +34,8 @@ pub trait KernelService: Send + Sync + 'static {
     // === Module calls === //
     fn register_module_call_handler(&self, handler: &'static dyn super::ModuleCallHandler);
     fn module_call<'a>(&self, module: &str, request: RawModuleRequest<'a>) -> isize;
+    /// Called before the module is unloaded.
+    fn register_deinit_handler(&self, handler: &'static dyn super::ModuleDeinitHandler);
 
     // === Heap === //
     fn alloc(&self, layout: Layout) -> Option<Address>;
@@ -54,6 +67,8 @@ pub trait KernelService: Send + Sync + 'static {
     fn interrupt_controller(&self) -> &'static dyn InterruptController;
     /// Set interrupt controller.
     fn set_interrupt_controller(&self, controller: &'static dyn InterruptController);
+    /// Register an IRQ handler. It is removed when the module is unloaded.
+    fn set_irq_handler(&self, irq: usize, handler: IRQHandler);
     /// Get timer controller.
     fn timer_controller(&self) -> &'static dyn TimerController;
     /// Set timer controller.
@@ -73,20 +88,48 @@ pub trait KernelService: Send + Sync + 'static {
     fn set_scheduler(&self, scheduler: &'static dyn Scheduler);
     /// Arch-dependent task context
     fn create_task_context(&self) -> Box<dyn Any>;
+
+    // === Memory mappings === //
+    /// Map `size` bytes of `node` from `offset` into the current process, with a `mman::PROT_*` protection
+    /// and a `mman::MAP_*` flag. Returns the address of the mapping.
+    fn map_file(
+        &self,
+        node: vfs::Node,
+        offset: usize,
+        size: usize,
+        prot: usize,
+        flags: usize,
+    ) -> Result<usize, Error>;
+
+    // === User memory === //
+    /// Memory of the current process. Slice arguments of unprivileged calls are already copied from it on entry.
+    fn user_memory(&self) -> &'static dyn UserMemory;
 }
 
-#[repr(C)]
+/// The service table passed to a module's `_start`.
+#[repr(transparent)]
 #[derive(Clone, Copy)]
-pub struct KernelServiceWrapper([usize; 2]);
+pub struct KernelServiceWrapper(&'static KernelServiceTable);
 
 impl KernelServiceWrapper {
-    #[inline(always)]
-    pub fn get_service(self) -> &'static dyn KernelService {
-        unsafe { core::mem::transmute(self) }
+    pub fn new(table: &'static KernelServiceTable) -> Self {
+        Self(table)
     }
 
-    pub fn from_service(service: &'static dyn KernelService) -> Self {
-        unsafe { core::mem::transmute(service) }
+    /// Check that the kernel's table is compatible with the one this module is built against.
+    /// Only the `version` and `size` header is read, as the rest of the layout may differ.
+    pub fn check_abi(self) -> Result<(), Error> {
+        if self.0.version != SERVICE_ABI_VERSION
+            || (self.0.size as usize) < core::mem::size_of::<KernelServiceTable>()
+        {
+            return Err(Error::ENOEXEC);
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn get_service(self) -> &'static dyn KernelService {
+        self.0
     }
 }
 
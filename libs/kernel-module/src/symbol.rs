@@ -0,0 +1,12 @@
+/// Section holding the symbols exported by the kernel or a module.
+/// Named as a C identifier, so the linker defines its `__start_` and `__stop_` symbols.
+pub const SYMBOL_SECTION: &str = "sophon_ksymtab";
+
+/// An exported symbol. Generated by `#[export_symbol]`.
+#[repr(C)]
+pub struct KernelSymbol {
+    pub name: &'static str,
+    pub address: *const (),
+}
+
+unsafe impl Sync for KernelSymbol {}
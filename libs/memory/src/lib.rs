@@ -32,3 +32,20 @@ pub fn sbrk(size: usize) -> Option<Address> {
         Some(Address::new(r as usize))
     }
 }
+
+/// Map zeroed, writable pages. They are backed by physical memory on first touch.
+pub fn mmap(size: usize) -> Option<Address> {
+    let prot = syscall::mman::PROT_READ | syscall::mman::PROT_WRITE;
+    let r = syscall::mmap(0, size, prot);
+    if r <= 0 {
+        None
+    } else {
+        Some(Address::new(r as usize))
+    }
+}
+
+/// Unmap pages, and return their memory to the kernel.
+pub fn munmap(start: Address, size: usize) {
+    let r = syscall::munmap(start.as_usize(), size);
+    debug_assert_eq!(r, 0);
+}
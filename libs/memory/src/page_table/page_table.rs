@@ -108,6 +108,31 @@ impl PageTable<L4> {
         }
     }
 
+    #[inline]
+    #[cfg(target_arch = "x86_64")]
+    pub fn invalidate_tlb<S: PageSize>(_page: Page<S>) {
+        unimplemented!()
+    }
+
+    /// Invalidate the cached translations of `page` on all cores, after its entry is changed or cleared.
+    #[inline]
+    #[cfg(target_arch = "aarch64")]
+    pub fn invalidate_tlb<S: PageSize>(page: Page<S>) {
+        use core::arch::asm;
+
+        unsafe {
+            asm! {
+                "
+                dsb ishst
+                tlbi vaae1is, {v}
+                dsb ish
+                isb
+            ",
+                v = in(reg) page.start().as_usize() >> 12
+            }
+        }
+    }
+
     #[inline]
     pub fn enable_temporarily(&self) -> impl Drop + DerefMut + Deref<Target = PageTable> {
         struct PageTables {
@@ -146,7 +171,8 @@ impl PageTable<L4> {
         x
     }
 
-    pub fn translate(&mut self, a: Address<V>) -> Option<Address<P>> {
+    /// Find the last-level entry mapping `a`, and the offset mask of the mapped page.
+    fn walk(&mut self, a: Address<V>) -> Option<(&'static mut PageTableEntry, usize)> {
         // P4
         let table = self;
         // P3
@@ -160,7 +186,7 @@ impl PageTable<L4> {
             return None;
         }
         if table[index].is_block() {
-            return Some(table[index].address() + (a.as_usize() & Page::<Size1G>::MASK));
+            return Some((&mut table[index], Page::<Size1G>::MASK));
         }
         // P2
         let table = table.get_next_table(index).unwrap();
@@ -169,15 +195,41 @@ impl PageTable<L4> {
             return None;
         }
         if table[index].is_block() {
-            return Some(table[index].address() + (a.as_usize() & Page::<Size2M>::MASK));
+            return Some((&mut table[index], Page::<Size2M>::MASK));
         }
         // P1
         let table = table.get_next_table(index).unwrap();
         let index = PageTable::<L1>::get_index(a);
         if table[index].is_empty() {
-            return None;
+            None
         } else {
-            return Some(table[index].address() + (a.as_usize() & Page::<Size4K>::MASK));
+            Some((&mut table[index], Page::<Size4K>::MASK))
+        }
+    }
+
+    pub fn translate(&mut self, a: Address<V>) -> Option<Address<P>> {
+        let (entry, mask) = self.walk(a)?;
+        Some(entry.address() + (a.as_usize() & mask))
+    }
+
+    /// Flags of the page mapping `a`
+    pub fn get_flags(&mut self, a: Address<V>) -> Option<PageFlags> {
+        self.walk(a).map(|(entry, _)| entry.flags())
+    }
+
+    /// Change the flags of the page mapping `a`. Returns `false` if `a` is not mapped.
+    pub fn update_flags(&mut self, a: Address<V>, flags: PageFlags) -> bool {
+        match self.walk(a) {
+            Some((entry, mask)) => {
+                let flags = if mask == Page::<Size4K>::MASK {
+                    flags | PageFlags::SMALL_PAGE
+                } else {
+                    flags
+                };
+                entry.update_flags(flags);
+                true
+            }
+            None => false,
         }
     }
 
@@ -8,6 +8,7 @@ use core::any::Any;
 use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use spin::Mutex;
 use sync::Monitor;
+use syscall::{Credentials, Error};
 
 #[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
 pub struct ProcId(pub usize);
@@ -37,6 +38,41 @@ pub trait ProcessManager {
     fn get_task_by_id(&self, id: TaskId) -> Option<Arc<dyn Task>>;
     /// Get the current task
     fn current_task(&self) -> Option<Arc<dyn Task>>;
+    /// Wait for a child of the current process to exit, and release it.
+    /// `None` waits for any child.
+    ///
+    /// Returns the child id and its exit status, `ECHILD` if there is no such child, or `EINTR` if a signal is
+    /// pending.
+    fn wait_for_child(&self, child: Option<ProcId>) -> Result<(ProcId, isize), Error>;
+    /// Send a signal to a process. Returns `false` if the process or the signal does not exist.
+    ///
+    /// Unless the signal is blocked or ignored, the threads of the process are woken up, so that interruptible
+    /// waits return `EINTR`.
+    fn send_signal(&self, proc: ProcId, signal: usize) -> bool;
+    /// Raise a fault signal on the current process. A blocked or ignored signal, or one whose handler is
+    /// running, is reset to the default action.
+    fn force_signal(&self, signal: usize);
+    /// Whether the current process has a pending signal that interrupts blocking calls.
+    fn signal_pending(&self) -> bool;
+    /// Take the next deliverable signal of the current process that has a user handler.
+    ///
+    /// Signals with a default action are handled in place.
+    /// This may terminate the current process, or block until it is continued.
+    fn dequeue_signal(&self) -> Option<SignalHandler>;
+    /// The process that receives keyboard interrupts
+    fn foreground_proc(&self) -> Option<ProcId>;
+}
+
+/// A signal to be delivered to a user handler
+#[derive(Debug, Clone, Copy)]
+pub struct SignalHandler {
+    pub signal: usize,
+    /// Entry of the handler: `extern "C" fn(signal: usize)`
+    pub handler: usize,
+    /// Return address of the handler, which calls `sigreturn`
+    pub restorer: usize,
+    /// Signal mask of the interrupted code, restored by `sigreturn`
+    pub mask: usize,
 }
 
 /// Abstruct task type
@@ -67,12 +103,16 @@ pub trait Proc: Send + Sync + Any {
     fn fs(&self) -> &dyn Any;
     /// Get process memory state
     fn mm(&self) -> &dyn Any;
+    /// Get the user and capabilities of the process
+    fn credentials(&self) -> Credentials;
     /// Get all the tasks in this process
     fn tasks(&self) -> &Mutex<Vec<TaskId>>;
     /// Spawn a task
     fn spawn_task(self: Arc<Self>, task: Box<dyn Runnable>) -> Arc<dyn Task>;
     /// Exit the process
-    fn exit(&self);
+    fn exit(&self, status: isize);
+    /// Exit status, or `None` if the process is still running
+    fn exit_status(&self) -> Option<isize>;
     /// Wait for the process to complete
     fn wait_for_completion(&self);
 }
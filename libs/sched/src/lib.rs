@@ -16,14 +16,21 @@ pub trait Scheduler: Send + Sync + 'static {
     fn register_new_task(&self, task: TaskId);
     /// Dereference a task.
     fn remove_task(&self, task: TaskId);
-    /// Sleep the current task.
+    /// Sleep the current task. A task marked by `prepare_to_sleep` sleeps unless it is already woken up.
     fn sleep(&self);
     /// Wake up a task.
     fn wake_up(&self, task: TaskId);
+    /// Set the nice value of a task, from -20 (highest priority) to 19 (lowest priority).
+    /// Schedulers without a notion of priority may ignore it.
+    fn set_priority(&self, _task: TaskId, _nice: isize) {}
     /// Switch to another task.
     fn schedule(&self) -> !;
     /// Tick the timer.
     fn timer_tick(&self) -> !;
+    /// Mark the current task as sleeping, and keep running it until `sleep`.
+    /// A `wake_up` in between cancels the sleep, so a task can queue itself as a waiter under a lock,
+    /// and sleep after releasing the lock without missing wake-ups.
+    fn prepare_to_sleep(&self);
 }
 
 #[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
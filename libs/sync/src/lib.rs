@@ -6,9 +6,9 @@ extern crate alloc;
 use core::{
     cell::UnsafeCell,
     ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, Ordering},
 };
-
-use syscall::module_calls::proc::{OpaqueCondvarPointer, OpaqueMutexPointer};
+use syscall::Error;
 
 pub trait AbstractRawMutex {
     fn lock(&self);
@@ -20,55 +20,82 @@ pub trait AbstractRawCondvar {
     fn notify_all(&self);
 }
 
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+/// Locked, and there may be threads blocked on the futex
+const CONTENDED: u32 = 2;
+
+/// A futex-based lock. Only the contended paths enter the kernel.
 pub struct RawMutex {
-    raw: OpaqueMutexPointer,
+    state: AtomicU32,
 }
 
 impl RawMutex {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
-            raw: syscall::mutex_create(),
+            state: AtomicU32::new(UNLOCKED),
         }
     }
 
     pub fn lock(&self) {
-        syscall::mutex_lock(self.raw);
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.lock_contended();
+        }
     }
 
-    pub fn unlock(&self) {
-        syscall::mutex_unlock(self.raw);
+    #[cold]
+    fn lock_contended(&self) {
+        while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+            syscall::futex_wait_uninterruptible(&self.state, CONTENDED, None);
+        }
     }
-}
 
-impl Drop for RawMutex {
-    fn drop(&mut self) {
-        syscall::mutex_destroy(self.raw);
+    pub fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            syscall::futex_wake(&self.state, 1);
+        }
     }
 }
 
 pub struct RawCondvar {
-    raw: OpaqueCondvarPointer,
+    /// Bumped by every notification
+    seq: AtomicU32,
 }
 
 impl RawCondvar {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
-            raw: syscall::condvar_create(),
+            seq: AtomicU32::new(0),
         }
     }
 
     pub fn wait(&self, mutex: &RawMutex) {
-        syscall::condvar_wait(self.raw, mutex.raw);
+        let seq = self.seq.load(Ordering::Relaxed);
+        mutex.unlock();
+        syscall::futex_wait_uninterruptible(&self.seq, seq, None);
+        mutex.lock();
     }
 
-    pub fn notify_all(&self) {
-        syscall::condvar_notify_all(self.raw);
+    /// Same as `wait`, but returns `EINTR` if interrupted by a signal.
+    /// The mutex is locked again in both cases.
+    pub fn wait_interruptible(&self, mutex: &RawMutex) -> Result<(), Error> {
+        let seq = self.seq.load(Ordering::Relaxed);
+        mutex.unlock();
+        let result = syscall::futex_wait(&self.seq, seq, None);
+        mutex.lock();
+        match Error::check(result) {
+            Err(Error::EINTR) => Err(Error::EINTR),
+            _ => Ok(()),
+        }
     }
-}
 
-impl Drop for RawCondvar {
-    fn drop(&mut self) {
-        syscall::condvar_destory(self.raw);
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        syscall::futex_wake(&self.seq, usize::MAX);
     }
 }
 
@@ -78,7 +105,7 @@ pub struct RawMonitor {
 }
 
 impl RawMonitor {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             lock: RawMutex::new(),
             cond: RawCondvar::new(),
@@ -97,6 +124,10 @@ impl RawMonitor {
         self.cond.wait(&self.lock);
     }
 
+    pub fn wait_interruptible(&self) -> Result<(), Error> {
+        self.cond.wait_interruptible(&self.lock)
+    }
+
     pub fn notify_all(&self) {
         self.cond.notify_all();
     }
@@ -107,8 +138,10 @@ pub struct Monitor<T> {
     data: UnsafeCell<T>,
 }
 
+unsafe impl<T: Send> Sync for Monitor<T> {}
+
 impl<T> Monitor<T> {
-    pub fn new(value: T) -> Self {
+    pub const fn new(value: T) -> Self {
         Self {
             raw: RawMonitor::new(),
             data: UnsafeCell::new(value),
@@ -125,6 +158,16 @@ impl<T> Monitor<T> {
         guard
     }
 
+    /// Same as `wait`, but fails with `EINTR` if interrupted by a signal.
+    /// The lock is released in that case.
+    pub fn wait_interruptible<'a>(
+        self: &'a Self,
+        guard: MonitorGuard<'a, T>,
+    ) -> Result<MonitorGuard<'a, T>, Error> {
+        self.raw.wait_interruptible()?;
+        Ok(guard)
+    }
+
     pub fn notify_all(&self) {
         self.raw.notify_all()
     }
@@ -158,8 +201,10 @@ pub struct Mutex<T> {
     data: UnsafeCell<T>,
 }
 
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
 impl<T> Mutex<T> {
-    pub fn new(value: T) -> Self {
+    pub const fn new(value: T) -> Self {
         Self {
             lock: RawMutex::new(),
             data: UnsafeCell::new(value),
@@ -200,7 +245,7 @@ pub struct Condvar {
 }
 
 impl Condvar {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             raw: RawCondvar::new(),
         }
@@ -0,0 +1,138 @@
+use proc_macro::TokenStream;
+use quote::{format_ident, quote};
+use std::collections::BTreeMap;
+use syn::punctuated::Punctuated;
+
+/// Request ids are derived from the variant names, so they do not change when variants are added or reordered.
+fn request_id(name: &str) -> usize {
+    // 32-bit FNV-1a
+    let mut hash = 0x811c9dc5u32;
+    for b in name.bytes() {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash as usize
+}
+
+/// Derive `ModuleRequest` for an enum whose variant fields all implement `Payload`.
+///
+/// Variants with more than three fields are encoded into a buffer, passed by its address and length.
+/// `#[capability(...)]` on a variant lists the `Capabilities` the caller must hold.
+#[proc_macro_derive(ModuleRequest, attributes(capability))]
+pub fn derive_module_request(item: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(item as syn::DeriveInput);
+    let name = &input.ident;
+    let data = match &input.data {
+        syn::Data::Enum(data) => data,
+        _ => {
+            return syn::Error::new_spanned(&input, "ModuleRequest can only be derived for enums")
+                .to_compile_error()
+                .into()
+        }
+    };
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    // The request borrows its payload for the trait's lifetime
+    let lifetime = match input.generics.lifetimes().next() {
+        Some(def) => def.lifetime.clone(),
+        None => syn::Lifetime::new("'__a", proc_macro::Span::call_site().into()),
+    };
+    let mut ids = BTreeMap::new();
+    let mut as_raw_arms = vec![];
+    let mut from_raw_arms = vec![];
+    let mut capability_arms = vec![];
+    for variant in &data.variants {
+        let ident = &variant.ident;
+        let id = request_id(&ident.to_string());
+        if let Some(other) = ids.insert(id, ident) {
+            return syn::Error::new_spanned(
+                ident,
+                format!("request id of `{}` collides with `{}`", ident, other),
+            )
+            .to_compile_error()
+            .into();
+        }
+        for attr in variant
+            .attrs
+            .iter()
+            .filter(|a| a.path.is_ident("capability"))
+        {
+            let caps = match attr
+                .parse_args_with(Punctuated::<syn::Ident, syn::Token![,]>::parse_terminated)
+            {
+                Ok(caps) => caps.into_iter(),
+                Err(e) => return e.to_compile_error().into(),
+            };
+            capability_arms.push(quote! {
+                #id => ::syscall::Capabilities::NONE #(| ::syscall::Capabilities::#caps)*,
+            });
+        }
+        let bindings = (0..variant.fields.len())
+            .map(|i| format_ident!("__f{}", i))
+            .collect::<Vec<_>>();
+        let pattern = match &variant.fields {
+            syn::Fields::Named(fields) => {
+                let names = fields.named.iter().map(|f| f.ident.as_ref().unwrap());
+                quote!(Self::#ident { #(#names: #bindings),* })
+            }
+            syn::Fields::Unnamed(_) => quote!(Self::#ident(#(#bindings),*)),
+            syn::Fields::Unit => quote!(Self::#ident),
+        };
+        let encode = if bindings.len() <= 3 {
+            let unit = quote!(&());
+            let args = bindings
+                .iter()
+                .map(|b| quote!(#b))
+                .chain(core::iter::repeat(unit))
+                .take(3);
+            quote!(::syscall::RawModuleRequest::new(#id, #(#args),*))
+        } else {
+            quote!(::syscall::RawModuleRequest::spill(
+                #id,
+                &[#(::syscall::Payload::encode(#bindings)),*],
+            ))
+        };
+        as_raw_arms.push(quote!(#pattern => #encode,));
+        let n = bindings.len();
+        let decode = if n <= 3 {
+            let indices = 0..n;
+            quote!(#(let #bindings = raw.arg(#indices)?;)*)
+        } else {
+            let indices = 0..n;
+            quote! {
+                let args = raw.spilled_args::<#n>()?;
+                #(let #bindings = raw.decode(args[#indices])?;)*
+            }
+        };
+        from_raw_arms.push(quote!(#id => { #decode #pattern }));
+    }
+    let ids = ids.keys();
+    let result = quote! {
+        impl<#lifetime> ::syscall::ModuleRequest<#lifetime> for #name #ty_generics {
+            const IDS: &'static [usize] = &[#(#ids),*];
+
+            fn as_raw(&#lifetime self) -> ::syscall::RawModuleRequest<#lifetime> {
+                match self {
+                    #(#as_raw_arms)*
+                }
+            }
+
+            fn from_raw(
+                raw: ::syscall::RawModuleRequest<#lifetime>,
+            ) -> Result<Self, ::syscall::Error> {
+                let request = match raw.id() {
+                    #(#from_raw_arms)*
+                    _ => return Err(::syscall::Error::ENOSYS),
+                };
+                Ok(request)
+            }
+
+            fn required_capabilities(id: usize) -> ::syscall::Capabilities {
+                match id {
+                    #(#capability_arms)*
+                    _ => ::syscall::Capabilities::NONE,
+                }
+            }
+        }
+    };
+    result.into()
+}
@@ -0,0 +1,327 @@
+#[allow(unused)]
+use core::arch::asm;
+use core::intrinsics::transmute;
+use core::sync::atomic::AtomicU32;
+use core::time::Duration;
+
+use crate::module_calls::{kmod::KmodRequest, proc::ProcRequest};
+use crate::ModuleRequest;
+
+#[repr(usize)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Syscall {
+    Log,
+    ModuleCall,
+    Wait,
+    Sbrk,
+    Exec,
+    Exit,
+    ThreadExit,
+    Halt,
+    Spawn,
+    WaitPid,
+    SigReturn,
+    ThreadSpawn,
+    ThreadJoin,
+    FutexWait,
+    FutexWake,
+    Mmap,
+    Munmap,
+    Mprotect,
+    Fork,
+}
+
+#[inline]
+#[cfg(target_arch = "x86_64")]
+pub fn syscall(_syscall: Syscall, _args: &[usize]) -> isize {
+    unimplemented!()
+}
+
+#[inline]
+#[cfg(target_arch = "aarch64")]
+pub fn syscall(syscall: Syscall, args: &[usize]) -> isize {
+    debug_assert!(args.len() <= 6);
+    let a: usize = args.get(0).cloned().unwrap_or(0);
+    let b: usize = args.get(1).cloned().unwrap_or(0);
+    let c: usize = args.get(2).cloned().unwrap_or(0);
+    let d: usize = args.get(3).cloned().unwrap_or(0);
+    let e: usize = args.get(4).cloned().unwrap_or(0);
+    let ret: isize;
+    unsafe {
+        asm!("svc #0",
+            inout("x0") syscall as usize => ret,
+            in("x1") a, in("x2") b, in("x3") c, in("x4") d, in("x5") e,
+        );
+    }
+    ret
+}
+
+#[inline]
+pub fn log(message: &str) {
+    syscall(Syscall::Log, &[&message as *const &str as usize]);
+}
+
+#[inline]
+pub fn module_call<'a>(module: &str, request: &'a impl ModuleRequest<'a>) -> isize {
+    raw_module_call(module, request.as_raw().as_buf())
+}
+
+/// Send an encoded request (id and three arguments) to a module.
+#[inline]
+pub fn raw_module_call(module: &str, args: [usize; 4]) -> isize {
+    let name = &module as *const &str;
+    syscall(
+        Syscall::ModuleCall,
+        &[name as usize, args[0], args[1], args[2], args[3]],
+    )
+}
+
+#[inline]
+pub fn wait() -> isize {
+    syscall(Syscall::Wait, &[])
+}
+
+/// Run a program and wait for it to exit.
+///
+/// Returns the id of the child and stores its exit status to `status`, or an error if the program cannot be started.
+#[inline]
+pub fn exec(path: &str, args: &[&str], status: &mut isize) -> isize {
+    let path = &path as *const &str;
+    let args = &args as *const &[&str];
+    unsafe {
+        syscall(
+            Syscall::Exec,
+            &[
+                transmute(path),
+                transmute(args),
+                status as *mut isize as usize,
+            ],
+        )
+    }
+}
+
+/// Start a new process and return its id without waiting for it.
+///
+/// `env` holds `KEY=VALUE` strings. `fds` lists the `(child_fd, parent_fd)` pairs the child inherits;
+/// `None` inherits all the parent's file descriptors.
+#[inline]
+pub fn spawn(path: &str, args: &[&str], env: &[&str], fds: Option<&[(u32, u32)]>) -> isize {
+    let path = &path as *const &str;
+    let args = &args as *const &[&str];
+    let env = &env as *const &[&str];
+    let fds = &fds as *const Option<&[(u32, u32)]>;
+    unsafe {
+        syscall(
+            Syscall::Spawn,
+            &[
+                transmute(path),
+                transmute(args),
+                transmute(env),
+                transmute(fds),
+            ],
+        )
+    }
+}
+
+/// Duplicate the calling process. Only the calling thread is duplicated, and memory is shared copy-on-write.
+///
+/// Returns the id of the child in the parent, and 0 in the child.
+#[inline]
+pub fn fork() -> isize {
+    syscall(Syscall::Fork, &[])
+}
+
+/// Wait for a child process to exit, and release it. `pid` 0 waits for any child.
+///
+/// Returns the id of the child and stores its exit status to `status`, `ECHILD` if there is no such child,
+/// or `EINTR` if interrupted by a signal.
+#[inline]
+pub fn waitpid(pid: usize, status: &mut isize) -> isize {
+    syscall(Syscall::WaitPid, &[pid, status as *mut isize as usize])
+}
+
+#[inline]
+pub fn exit(status: isize) -> ! {
+    syscall(Syscall::Exit, &[status as usize]);
+    unreachable!()
+}
+
+/// Start a thread in the current process, running `entry(arg)` on the stack below `stack_top`.
+/// If `stack_top` is 0, the kernel allocates a stack with a guard page, and releases it when the thread exits.
+/// Returns the thread id.
+#[inline]
+pub fn thread_spawn(entry: extern "C" fn(usize) -> !, arg: usize, stack_top: usize) -> isize {
+    syscall(Syscall::ThreadSpawn, &[entry as usize, arg, stack_top])
+}
+
+/// Wait for a thread of the current process to exit.
+#[inline]
+pub fn thread_join(tid: usize) -> isize {
+    syscall(Syscall::ThreadJoin, &[tid])
+}
+
+#[inline]
+pub fn thread_exit() -> ! {
+    syscall(Syscall::ThreadExit, &[]);
+    unreachable!()
+}
+
+/// Map `size` bytes of zeroed memory, with a `mman::PROT_*` protection. Returns the address of the mapping.
+///
+/// `addr` 0 lets the kernel choose the address. Otherwise the mapping is placed at `addr`, which must be
+/// page-aligned and free.
+#[inline]
+pub fn mmap(addr: usize, size: usize, prot: usize) -> isize {
+    syscall(Syscall::Mmap, &[addr, size, prot])
+}
+
+/// Unmap the pages in `addr..addr + size`, and release their memory.
+#[inline]
+pub fn munmap(addr: usize, size: usize) -> isize {
+    syscall(Syscall::Munmap, &[addr, size])
+}
+
+/// Change the protection of the pages in `addr..addr + size`. All of them must be mapped.
+#[inline]
+pub fn mprotect(addr: usize, size: usize, prot: usize) -> isize {
+    syscall(Syscall::Mprotect, &[addr, size, prot])
+}
+
+#[inline]
+pub fn halt(code: usize) -> ! {
+    syscall(Syscall::Halt, &[code]);
+    unreachable!()
+}
+
+/// Set the nice value (-20..=19) of a process. `pid == 0` refers to the calling process.
+#[inline]
+pub fn set_priority(pid: usize, nice: isize) -> isize {
+    module_call("pm", &ProcRequest::SetPriority(pid, nice))
+}
+
+/// Send a signal to a process. `pid == 0` refers to the calling process.
+#[inline]
+pub fn kill(pid: usize, sig: usize) -> isize {
+    module_call("pm", &ProcRequest::Kill(pid, sig))
+}
+
+/// Set the handler of a signal, or `SIG_DFL`/`SIG_IGN`.
+/// `restorer` is called when the handler returns, and must call `sigreturn`.
+#[inline]
+pub fn sigaction(sig: usize, handler: usize, restorer: usize) -> isize {
+    module_call("pm", &ProcRequest::SigAction(sig, handler, restorer))
+}
+
+#[inline]
+pub fn sigprocmask(how: usize, set: usize) -> isize {
+    module_call("pm", &ProcRequest::SigProcMask(how, set))
+}
+
+/// Set the process receiving keyboard interrupts. `pid == 0` refers to the calling process.
+#[inline]
+pub fn set_foreground(pid: usize) -> isize {
+    module_call("pm", &ProcRequest::SetForeground(pid))
+}
+
+#[inline]
+pub fn getuid() -> isize {
+    module_call("pm", &ProcRequest::GetUid)
+}
+
+#[inline]
+pub fn getgid() -> isize {
+    module_call("pm", &ProcRequest::GetGid)
+}
+
+/// Change the user id of the calling process. Requires `Capabilities::SETUID`.
+/// Switching to a non-root user drops all the capabilities.
+#[inline]
+pub fn setuid(uid: usize) -> isize {
+    module_call("pm", &ProcRequest::SetUid(uid))
+}
+
+/// Change the group id of the calling process. Requires `Capabilities::SETUID`.
+#[inline]
+pub fn setgid(gid: usize) -> isize {
+    module_call("pm", &ProcRequest::SetGid(gid))
+}
+
+/// Load a kernel module from a file. Requires `Capabilities::SYS_MODULE`.
+#[inline]
+pub fn insmod(path: &str) -> isize {
+    module_call("kmod", &KmodRequest::Load(path))
+}
+
+/// Unload a kernel module. Requires `Capabilities::SYS_MODULE`.
+#[inline]
+pub fn rmmod(name: &str) -> isize {
+    module_call("kmod", &KmodRequest::Unload(name))
+}
+
+/// List the loaded kernel modules. See [`KmodRequest::List`].
+#[inline]
+pub fn lsmod(buf: &mut [u8]) -> isize {
+    module_call("kmod", &KmodRequest::List(buf))
+}
+
+/// Return from a signal handler, and resume the interrupted code.
+#[inline]
+pub fn sigreturn() -> ! {
+    syscall(Syscall::SigReturn, &[]);
+    unreachable!()
+}
+
+/// Flag of `Syscall::FutexWait`: Not interrupted by signals. Only honored for the kernel.
+pub const FUTEX_UNINTERRUPTIBLE: usize = 1;
+
+/// Block the current thread if `*addr == expected`, until another thread calls `futex_wake` on the same word.
+/// A `timeout` of `None` waits forever.
+///
+/// Returns 0 when woken up, `EAGAIN` if the value did not match, `EFAULT` if the address is invalid,
+/// `EINTR` if a signal is pending, or `ETIMEDOUT`. Spurious wake-ups also return 0.
+#[inline]
+pub fn futex_wait(addr: &AtomicU32, expected: u32, timeout: Option<Duration>) -> isize {
+    futex_wait_with_flags(addr, expected, timeout, 0)
+}
+
+/// Same as [`futex_wait`], but never returns `EINTR` when called by the kernel.
+///
+/// User programs are always interrupted, since their signals are delivered before the syscall returns.
+#[inline]
+pub fn futex_wait_uninterruptible(
+    addr: &AtomicU32,
+    expected: u32,
+    timeout: Option<Duration>,
+) -> isize {
+    futex_wait_with_flags(addr, expected, timeout, FUTEX_UNINTERRUPTIBLE)
+}
+
+#[inline]
+fn futex_wait_with_flags(
+    addr: &AtomicU32,
+    expected: u32,
+    timeout: Option<Duration>,
+    flags: usize,
+) -> isize {
+    let timeout = timeout.map_or(usize::MAX, |t| {
+        t.as_nanos().min(usize::MAX as u128 - 1) as _
+    });
+    syscall(
+        Syscall::FutexWait,
+        &[
+            addr as *const AtomicU32 as usize,
+            expected as _,
+            timeout,
+            flags,
+        ],
+    )
+}
+
+/// Wake up at most `count` threads blocked on `addr`. Returns the number of woken threads.
+#[inline]
+pub fn futex_wake(addr: &AtomicU32, count: usize) -> isize {
+    syscall(
+        Syscall::FutexWake,
+        &[addr as *const AtomicU32 as usize, count],
+    )
+}
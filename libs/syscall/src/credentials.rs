@@ -0,0 +1,90 @@
+//! Process credentials, and the permission checks of module calls.
+
+use core::ops::BitOr;
+
+/// Operations a process is allowed to do beyond its own resources.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    /// Mount file systems
+    pub const SYS_ADMIN: Self = Self(1 << 0);
+    /// Register devices
+    pub const SYS_MODULE: Self = Self(1 << 1);
+    /// Send signals to processes of other users
+    pub const KILL: Self = Self(1 << 2);
+    /// Raise priorities, and change the priorities of processes of other users
+    pub const SYS_NICE: Self = Self(1 << 3);
+    /// Change user and group ids
+    pub const SETUID: Self = Self(1 << 4);
+    pub const ALL: Self = Self(0b11111);
+
+    pub const fn contains(self, caps: Self) -> bool {
+        self.0 & caps.0 == caps.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+    fn bitor(self, x: Self) -> Self {
+        Self(self.0 | x.0)
+    }
+}
+
+/// User and group of a process. Inherited by child processes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub caps: Capabilities,
+}
+
+impl Credentials {
+    /// The root user holds all the capabilities.
+    pub const ROOT: Self = Self {
+        uid: 0,
+        gid: 0,
+        caps: Capabilities::ALL,
+    };
+
+    /// Credentials of a user. Only the root user has capabilities.
+    pub const fn user(uid: u32, gid: u32) -> Self {
+        Self {
+            uid,
+            gid,
+            caps: if uid == 0 {
+                Capabilities::ALL
+            } else {
+                Capabilities::NONE
+            },
+        }
+    }
+
+    pub const fn can(&self, caps: Capabilities) -> bool {
+        self.caps.contains(caps)
+    }
+}
+
+/// The caller of a module call.
+#[derive(Debug, Clone, Copy)]
+pub struct CallContext {
+    /// Whether the call comes from the kernel or a kernel module. Their arguments are trusted.
+    pub privileged: bool,
+    pub credentials: Credentials,
+}
+
+impl CallContext {
+    pub const KERNEL: Self = Self {
+        privileged: true,
+        credentials: Credentials::ROOT,
+    };
+
+    /// A call from a user process.
+    pub const fn user(credentials: Credentials) -> Self {
+        Self {
+            privileged: false,
+            credentials,
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Error codes of syscalls and module calls. Same numbering as Linux.
+//!
+//! Failed calls return the negated error code.
+
+use core::fmt;
+
+#[repr(isize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    ENODEV = 19,
+    ENOTDIR = 20,
+    EINVAL = 22,
+    EMFILE = 24,
+    ERANGE = 34,
+    EDEADLK = 35,
+    ENOSYS = 38,
+    ETIMEDOUT = 110,
+}
+
+impl Error {
+    const ALL: [Self; 21] = [
+        Self::EPERM,
+        Self::ENOENT,
+        Self::ESRCH,
+        Self::EINTR,
+        Self::EIO,
+        Self::ENOEXEC,
+        Self::EBADF,
+        Self::ECHILD,
+        Self::EAGAIN,
+        Self::ENOMEM,
+        Self::EFAULT,
+        Self::EBUSY,
+        Self::EEXIST,
+        Self::ENODEV,
+        Self::ENOTDIR,
+        Self::EINVAL,
+        Self::EMFILE,
+        Self::ERANGE,
+        Self::EDEADLK,
+        Self::ENOSYS,
+        Self::ETIMEDOUT,
+    ];
+
+    /// The negative return value of a failed call.
+    pub const fn code(self) -> isize {
+        -(self as isize)
+    }
+
+    /// Decode the return value of a call. Unknown error codes are reported as `EINVAL`.
+    pub fn check(ret: isize) -> Result<usize, Self> {
+        if ret >= 0 {
+            return Ok(ret as _);
+        }
+        Err(Self::ALL
+            .iter()
+            .copied()
+            .find(|e| e.code() == ret)
+            .unwrap_or(Self::EINVAL))
+    }
+
+    pub const fn description(self) -> &'static str {
+        match self {
+            Self::EPERM => "Operation not permitted",
+            Self::ENOENT => "No such file or directory",
+            Self::ESRCH => "No such process",
+            Self::EINTR => "Interrupted system call",
+            Self::EIO => "Input/output error",
+            Self::ENOEXEC => "Exec format error",
+            Self::EBADF => "Bad file descriptor",
+            Self::ECHILD => "No child processes",
+            Self::EAGAIN => "Resource temporarily unavailable",
+            Self::ENOMEM => "Out of memory",
+            Self::EFAULT => "Bad address",
+            Self::EBUSY => "Device or resource busy",
+            Self::EEXIST => "File exists",
+            Self::ENODEV => "No such device",
+            Self::ENOTDIR => "Not a directory",
+            Self::EINVAL => "Invalid argument",
+            Self::EMFILE => "Too many open files",
+            Self::ERANGE => "Result too large",
+            Self::EDEADLK => "Resource deadlock avoided",
+            Self::ENOSYS => "Function not implemented",
+            Self::ETIMEDOUT => "Timed out",
+        }
+    }
+}
+
+impl From<Error> for isize {
+    fn from(e: Error) -> Self {
+        e.code()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
@@ -3,19 +3,34 @@
 #![feature(never_type)]
 
 extern crate alloc;
+// For the paths generated by `derive(ModuleRequest)`
+extern crate self as syscall;
 
 #[macro_use]
 mod log;
+mod calls;
+pub mod credentials;
+pub mod error;
+pub mod mman;
 pub mod module_calls;
-mod syscall;
-
-use core::marker::PhantomData;
+pub mod signal;
+pub mod user_memory;
 
 pub use crate::log::UserLogger;
-pub use syscall::*;
-
-pub trait Payload {
+use alloc::vec::Vec;
+pub use calls::*;
+pub use credentials::{CallContext, Capabilities, Credentials};
+pub use error::Error;
+pub use syscall_macros::ModuleRequest;
+use user_memory::{UserArgs, UserData};
+
+pub trait Payload: Sized {
     fn decode(data: usize) -> Self;
+    /// Decode an argument of an unprivileged caller. Any memory it refers to must be accessible to the caller,
+    /// and is copied into `args`.
+    fn decode_user(data: usize, _args: &UserArgs) -> Option<Self> {
+        Some(Self::decode(data))
+    }
     fn encode(&self) -> usize;
 }
 
@@ -28,6 +43,15 @@ impl Payload for usize {
     }
 }
 
+impl Payload for isize {
+    fn decode(data: usize) -> Self {
+        data as _
+    }
+    fn encode(&self) -> usize {
+        *self as _
+    }
+}
+
 impl Payload for u32 {
     fn decode(data: usize) -> Self {
         data as _
@@ -37,19 +61,27 @@ impl Payload for u32 {
     }
 }
 
+/// Only for privileged callers, as the pointee may hold arbitrary pointers.
 impl<T: Sized> Payload for &T {
     fn decode(data: usize) -> Self {
         unsafe { &*(data as *const T) }
     }
+    fn decode_user(_: usize, _: &UserArgs) -> Option<Self> {
+        None
+    }
     fn encode(&self) -> usize {
         *self as *const T as _
     }
 }
 
+/// Only for privileged callers, as the pointee may hold arbitrary pointers.
 impl<T: Sized> Payload for &mut T {
     fn decode(data: usize) -> Self {
         unsafe { &mut *(data as *mut T) }
     }
+    fn decode_user(_: usize, _: &UserArgs) -> Option<Self> {
+        None
+    }
     fn encode(&self) -> usize {
         *self as *const T as _
     }
@@ -59,24 +91,33 @@ impl Payload for &str {
     fn decode(data: usize) -> Self {
         unsafe { *(data as *const &str) }
     }
+    fn decode_user(data: usize, args: &UserArgs) -> Option<Self> {
+        user_memory::str_from_user(args, data)
+    }
     fn encode(&self) -> usize {
         self as *const &str as _
     }
 }
 
-impl<T> Payload for &[T] {
+impl<T: UserData> Payload for &[T] {
     fn decode(data: usize) -> Self {
         unsafe { *(data as *const &[T]) }
     }
+    fn decode_user(data: usize, args: &UserArgs) -> Option<Self> {
+        user_memory::slice_from_user(args, data)
+    }
     fn encode(&self) -> usize {
         self as *const &[T] as _
     }
 }
 
-impl<T> Payload for &mut [T] {
+impl<T: UserData> Payload for &mut [T] {
     fn decode(data: usize) -> Self {
         unsafe { *(data as *mut &mut [T]) }
     }
+    fn decode_user(data: usize, args: &UserArgs) -> Option<Self> {
+        user_memory::slice_from_user_mut(args, data)
+    }
     fn encode(&self) -> usize {
         self as *const &mut [T] as _
     }
@@ -91,18 +132,36 @@ impl Payload for () {
     }
 }
 
+/// Request id and arguments of a module call.
+/// Requests from unprivileged callers carry the caller's memory, to copy the arguments from.
 #[repr(C)]
-pub struct RawModuleRequest<'a>(pub usize, pub [usize; 3], PhantomData<&'a usize>);
+pub struct RawModuleRequest<'a>(
+    pub usize,
+    pub [usize; 3],
+    Option<&'a UserArgs<'a>>,
+    /// Encoded arguments of requests with more than three arguments
+    Vec<usize>,
+);
 
 impl<'a> RawModuleRequest<'a> {
     #[inline]
     pub fn new(id: usize, a: &'a impl Payload, b: &'a impl Payload, c: &'a impl Payload) -> Self {
         let buf = [a.encode(), b.encode(), c.encode()];
-        RawModuleRequest(id, buf, PhantomData)
+        RawModuleRequest(id, buf, None, Vec::new())
+    }
+    /// A request with more than three encoded arguments. The arguments are passed as the address and length of a buffer.
+    pub fn spill(id: usize, args: &[usize]) -> Self {
+        let args = args.to_vec();
+        RawModuleRequest(id, [args.as_ptr() as _, args.len(), 0], None, args)
     }
     #[inline]
     pub fn from_buf(x: [usize; 4]) -> Self {
-        Self(x[0], [x[1], x[2], x[3]], PhantomData)
+        Self(x[0], [x[1], x[2], x[3]], None, Vec::new())
+    }
+    /// A request from an unprivileged caller.
+    #[inline]
+    pub fn from_user_buf(x: [usize; 4], args: &'a UserArgs<'a>) -> Self {
+        Self(x[0], [x[1], x[2], x[3]], Some(args), Vec::new())
     }
     #[inline]
     pub fn as_buf(&self) -> [usize; 4] {
@@ -112,22 +171,52 @@ impl<'a> RawModuleRequest<'a> {
     pub fn id(&self) -> usize {
         self.0
     }
+    /// Decode the `i`th argument. Returns `EFAULT` if it refers to memory the caller cannot access.
+    #[inline]
+    pub fn arg<V: Payload>(&self, i: usize) -> Result<V, Error> {
+        self.decode(self.1[i])
+    }
+    /// Decode an encoded argument. Returns `EFAULT` if it refers to memory the caller cannot access.
     #[inline]
-    pub fn arg<V: Payload>(&self, i: usize) -> V {
-        V::decode(self.1[i])
+    pub fn decode<V: Payload>(&self, data: usize) -> Result<V, Error> {
+        match self.2 {
+            Some(args) => V::decode_user(data, args).ok_or(Error::EFAULT),
+            None => Ok(V::decode(data)),
+        }
+    }
+    /// Read the `N` encoded arguments of a request created by [`RawModuleRequest::spill`].
+    pub fn spilled_args<const N: usize>(&self) -> Result<[usize; N], Error> {
+        if self.1[1] != N {
+            return Err(Error::EINVAL);
+        }
+        match self.2 {
+            Some(args) => {
+                user_memory::copy_from_user(args.memory(), self.1[0]).ok_or(Error::EFAULT)
+            }
+            None => Ok(unsafe { *(self.1[0] as *const [usize; N]) }),
+        }
     }
 }
 
-pub trait ModuleRequest<'a> {
+/// Requests of a module call. Usually implemented with `#[derive(ModuleRequest)]`.
+pub trait ModuleRequest<'a>: Sized {
+    /// Ids of all the requests.
+    const IDS: &'static [usize];
     fn as_raw(&'a self) -> RawModuleRequest<'a>;
-    fn from_raw(raw: RawModuleRequest<'a>) -> Self;
+    /// Returns `ENOSYS` for unknown request ids, or `EFAULT` if an argument is not accessible to the caller.
+    fn from_raw(raw: RawModuleRequest<'a>) -> Result<Self, Error>;
+    /// Capabilities the caller must hold to make the request. Checked before decoding the arguments.
+    fn required_capabilities(_id: usize) -> Capabilities {
+        Capabilities::NONE
+    }
 }
 
 impl<'a> ModuleRequest<'a> for ! {
+    const IDS: &'static [usize] = &[];
     fn as_raw(&'a self) -> RawModuleRequest<'a> {
         unimplemented!()
     }
-    fn from_raw(_: RawModuleRequest<'a>) -> Self {
-        unimplemented!()
+    fn from_raw(_: RawModuleRequest<'a>) -> Result<Self, Error> {
+        Err(Error::ENOSYS)
     }
 }
@@ -12,7 +12,7 @@ impl UserLogger {
 impl Logger for UserLogger {
     #[inline]
     fn log(&self, s: &str) -> Result<(), fmt::Error> {
-        crate::syscall::log(s);
+        crate::calls::log(s);
         Ok(())
     }
 }
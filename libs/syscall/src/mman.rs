@@ -0,0 +1,12 @@
+//! Protection and flags of memory mappings. Same values as Linux.
+
+/// The pages cannot be accessed
+pub const PROT_NONE: usize = 0;
+pub const PROT_READ: usize = 1;
+pub const PROT_WRITE: usize = 2;
+pub const PROT_EXEC: usize = 4;
+
+/// Writes go to the mapped file. Only supported by files backed by memory, like device registers.
+pub const MAP_SHARED: usize = 1;
+/// Writes go to private copies of the file pages. Only supported by files that stay in memory, like init-fs files.
+pub const MAP_PRIVATE: usize = 2;
@@ -0,0 +1,15 @@
+use crate::ModuleRequest;
+
+/// Requests to the kernel's module manager, `kmod`.
+#[derive(ModuleRequest)]
+pub enum KmodRequest<'a> {
+    /// Load a module from a file. Its name is read from the module metadata. Returns the module id.
+    #[capability(SYS_MODULE)]
+    Load(&'a str),
+    /// Unload a module by name.
+    #[capability(SYS_MODULE)]
+    Unload(&'a str),
+    /// Write one `name id size calls` line per loaded module to the buffer. Returns the number of bytes written,
+    /// or `ERANGE` if the buffer is too small.
+    List(&'a mut [u8]),
+}
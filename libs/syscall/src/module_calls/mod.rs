@@ -1 +1,2 @@
+pub mod kmod;
 pub mod proc;
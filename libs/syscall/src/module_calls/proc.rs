@@ -1,84 +1,25 @@
-use crate::{ModuleRequest, Payload, RawModuleRequest};
-
-#[repr(transparent)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct OpaqueMutexPointer(pub *mut ());
-
-impl OpaqueMutexPointer {
-    pub const fn cast<T>(&self) -> &T {
-        unsafe { &*(self.0 as *const T) }
-    }
-    pub const fn cast_mut_ptr<T>(&self) -> *mut T {
-        self.0 as _
-    }
-}
-
-impl Payload for OpaqueMutexPointer {
-    fn decode(data: usize) -> Self {
-        Self(data as _)
-    }
-    fn encode(&self) -> usize {
-        self.0 as _
-    }
-}
-
-#[repr(transparent)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct OpaqueCondvarPointer(pub *mut ());
-
-impl OpaqueCondvarPointer {
-    pub const fn cast<T>(&self) -> &T {
-        unsafe { &*(self.0 as *const T) }
-    }
-    pub const fn cast_mut_ptr<T>(&self) -> *mut T {
-        self.0 as _
-    }
-}
-
-impl Payload for OpaqueCondvarPointer {
-    fn decode(data: usize) -> Self {
-        Self(data as _)
-    }
-    fn encode(&self) -> usize {
-        self.0 as _
-    }
-}
+use crate::ModuleRequest;
 
+#[derive(ModuleRequest)]
 pub enum ProcRequest {
-    MutexCreate,
-    MutexLock(OpaqueMutexPointer),
-    MutexUnlock(OpaqueMutexPointer),
-    MutexDestroy(OpaqueMutexPointer),
-    CondvarCreate,
-    CondvarWait(OpaqueCondvarPointer, OpaqueMutexPointer),
-    CondvarNotifyAll(OpaqueCondvarPointer),
-    CondvarDestroy(OpaqueCondvarPointer),
-}
-
-impl<'a> ModuleRequest<'a> for ProcRequest {
-    fn as_raw(&'a self) -> RawModuleRequest<'a> {
-        match self {
-            Self::MutexCreate => RawModuleRequest::new(1, &(), &(), &()),
-            Self::MutexLock(x) => RawModuleRequest::new(2, x, &(), &()),
-            Self::MutexUnlock(x) => RawModuleRequest::new(3, x, &(), &()),
-            Self::MutexDestroy(x) => RawModuleRequest::new(4, x, &(), &()),
-            Self::CondvarCreate => RawModuleRequest::new(5, &(), &(), &()),
-            Self::CondvarWait(x, y) => RawModuleRequest::new(6, x, y, &()),
-            Self::CondvarNotifyAll(x) => RawModuleRequest::new(7, x, &(), &()),
-            Self::CondvarDestroy(x) => RawModuleRequest::new(8, x, &(), &()),
-        }
-    }
-    fn from_raw(raw: RawModuleRequest<'a>) -> Self {
-        match raw.id() {
-            1 => Self::MutexCreate,
-            2 => Self::MutexLock(raw.arg(0)),
-            3 => Self::MutexUnlock(raw.arg(0)),
-            4 => Self::MutexDestroy(raw.arg(0)),
-            5 => Self::CondvarCreate,
-            6 => Self::CondvarWait(raw.arg(0), raw.arg(1)),
-            7 => Self::CondvarNotifyAll(raw.arg(0)),
-            8 => Self::CondvarDestroy(raw.arg(0)),
-            _ => panic!("Unknown request"),
-        }
-    }
+    /// Set the nice value of all the threads in a process. Process id `0` refers to the calling process.
+    SetPriority(usize, isize),
+    /// Send a signal to a process. Process id `0` refers to the calling process.
+    Kill(usize, usize),
+    /// Set the handler and restorer of a signal. Returns the previous handler.
+    SigAction(usize, usize, usize),
+    /// Update the blocked signal mask with `SIG_BLOCK`, `SIG_UNBLOCK` or `SIG_SETMASK`. Returns the previous mask.
+    SigProcMask(usize, usize),
+    /// Set the process receiving keyboard interrupts. Process id `0` refers to the calling process.
+    SetForeground(usize),
+    /// User id of the calling process.
+    GetUid,
+    /// Group id of the calling process.
+    GetGid,
+    /// Set the user id of the calling process. Non-root users have no capabilities.
+    #[capability(SETUID)]
+    SetUid(usize),
+    /// Set the group id of the calling process.
+    #[capability(SETUID)]
+    SetGid(usize),
 }
@@ -0,0 +1,59 @@
+//! Signal numbers and default actions. Same numbering as Linux.
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+
+/// Number of signals. Valid signals are `1..NSIG`.
+pub const NSIG: usize = 32;
+
+/// Handler value for the default action
+pub const SIG_DFL: usize = 0;
+/// Handler value to ignore a signal
+pub const SIG_IGN: usize = 1;
+
+/// `sigprocmask` operations
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+/// Signals that can never be caught, blocked or ignored.
+pub const UNCATCHABLE: usize = sigmask(SIGKILL) | sigmask(SIGSTOP);
+
+/// Bit of `sig` in a signal set.
+pub const fn sigmask(sig: usize) -> usize {
+    1 << sig
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+pub const fn default_action(sig: usize) -> DefaultAction {
+    match sig {
+        SIGCHLD => DefaultAction::Ignore,
+        SIGCONT => DefaultAction::Continue,
+        SIGSTOP | SIGTSTP => DefaultAction::Stop,
+        _ => DefaultAction::Terminate,
+    }
+}
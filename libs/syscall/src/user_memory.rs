@@ -0,0 +1,141 @@
+//! Checked access to the memory of unprivileged callers.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::{mem, slice};
+
+/// Access to the calling process's memory. Implemented by the kernel.
+pub trait UserMemory {
+    /// Whether `start..start + size` is mapped user memory of the calling process, and writable if `write` is set.
+    fn check(&self, start: usize, size: usize, write: bool) -> bool;
+    /// Copy the user memory at `addr` into `buf`.
+    /// Returns `false` if it is not accessible, including when another thread unmaps it during the copy.
+    fn read(&self, addr: usize, buf: &mut [u8]) -> bool;
+    /// Copy `data` to the user memory at `addr`.
+    /// Returns `false` if it is not writable, including when another thread unmaps it during the copy.
+    fn write(&self, addr: usize, data: &[u8]) -> bool;
+}
+
+/// Types that are valid for any bit pattern, and hold no pointers.
+///
+/// # Safety
+///
+/// Implementors must be plain data.
+pub unsafe trait UserData: Copy {}
+
+unsafe impl UserData for u8 {}
+unsafe impl UserData for u16 {}
+unsafe impl UserData for u32 {}
+unsafe impl UserData for u64 {}
+unsafe impl UserData for usize {}
+unsafe impl UserData for i8 {}
+unsafe impl UserData for i16 {}
+unsafe impl UserData for i32 {}
+unsafe impl UserData for i64 {}
+unsafe impl UserData for isize {}
+unsafe impl<T: UserData, const N: usize> UserData for [T; N] {}
+
+/// Read a value from user memory.
+pub fn copy_from_user<T: UserData>(memory: &dyn UserMemory, addr: usize) -> Option<T> {
+    let mut value: T = unsafe { mem::zeroed() };
+    let buf =
+        unsafe { slice::from_raw_parts_mut(&mut value as *mut T as *mut u8, mem::size_of::<T>()) };
+    memory.read(addr, buf).then_some(value)
+}
+
+/// Write a value to user memory.
+pub fn copy_to_user<T: UserData>(memory: &dyn UserMemory, addr: usize, value: T) -> Option<()> {
+    let data =
+        unsafe { slice::from_raw_parts(&value as *const T as *const u8, mem::size_of::<T>()) };
+    memory.write(addr, data).then_some(())
+}
+
+/// Kernel copies of the slice and string arguments of an unprivileged call.
+///
+/// The arguments are copied when decoded, so the callee never touches memory that other threads of the
+/// caller may unmap. Mutable slices are written back by [`UserArgs::copy_back`] after the call.
+pub struct UserArgs<'a> {
+    memory: &'a dyn UserMemory,
+    buffers: RefCell<Vec<UserBuffer>>,
+}
+
+struct UserBuffer {
+    addr: usize,
+    size: usize,
+    write: bool,
+    /// Aligned for any `UserData`
+    data: Box<[u64]>,
+}
+
+impl UserBuffer {
+    fn bytes(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.data.as_mut_ptr() as *mut u8, self.size) }
+    }
+}
+
+impl<'a> UserArgs<'a> {
+    pub fn new(memory: &'a dyn UserMemory) -> Self {
+        Self {
+            memory,
+            buffers: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn memory(&self) -> &'a dyn UserMemory {
+        self.memory
+    }
+
+    /// Copy a user slice of `len` elements. The copy lives as long as `self`.
+    fn copy_in<'b, T: UserData>(&self, ptr: usize, len: usize, write: bool) -> Option<&'b mut [T]> {
+        debug_assert!(mem::align_of::<T>() <= mem::align_of::<u64>());
+        let size = len.checked_mul(mem::size_of::<T>())?;
+        if ptr % mem::align_of::<T>() != 0 || !self.memory.check(ptr, size, write) {
+            return None;
+        }
+        let words = (size + mem::size_of::<u64>() - 1) / mem::size_of::<u64>();
+        let mut data = Vec::new();
+        data.try_reserve_exact(words).ok()?;
+        data.resize(words, 0u64);
+        let mut buffer = UserBuffer {
+            addr: ptr,
+            size,
+            write,
+            data: data.into_boxed_slice(),
+        };
+        if !self.memory.read(ptr, buffer.bytes()) {
+            return None;
+        }
+        // The boxed data does not move when the buffer does
+        let copy = unsafe { slice::from_raw_parts_mut(buffer.data.as_mut_ptr() as *mut T, len) };
+        self.buffers.borrow_mut().push(buffer);
+        Some(copy)
+    }
+
+    /// Write the mutable slices back to the caller. Returns `false` if any of them is no longer writable.
+    pub fn copy_back(self) -> bool {
+        let memory = self.memory;
+        self.buffers
+            .into_inner()
+            .iter_mut()
+            .filter(|b| b.write)
+            .all(|b| memory.write(b.addr, b.bytes()))
+    }
+}
+
+/// Copy a user slice given the address of its fat pointer.
+pub fn slice_from_user<'a, T: UserData>(args: &UserArgs, addr: usize) -> Option<&'a [T]> {
+    let [ptr, len] = copy_from_user::<[usize; 2]>(args.memory, addr)?;
+    args.copy_in(ptr, len, false).map(|s| &*s)
+}
+
+/// Copy a user slice given the address of its fat pointer. Changes are written back by [`UserArgs::copy_back`].
+pub fn slice_from_user_mut<'a, T: UserData>(args: &UserArgs, addr: usize) -> Option<&'a mut [T]> {
+    let [ptr, len] = copy_from_user::<[usize; 2]>(args.memory, addr)?;
+    args.copy_in(ptr, len, true)
+}
+
+/// Copy a user string given the address of its fat pointer. The string must be valid UTF-8.
+pub fn str_from_user<'a>(args: &UserArgs, addr: usize) -> Option<&'a str> {
+    core::str::from_utf8(slice_from_user(args, addr)?).ok()
+}
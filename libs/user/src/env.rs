@@ -0,0 +1,29 @@
+use core::ffi::CStr;
+
+/// Iterate over the `KEY=VALUE` entries of a null-terminated `envp` array.
+///
+/// # Safety
+///
+/// `envp` must be the environment pointer passed to `_start(argc, argv, envp)`.
+pub unsafe fn vars(envp: *const *const u8) -> impl Iterator<Item = (&'static str, &'static str)> {
+    let mut next = envp;
+    core::iter::from_fn(move || loop {
+        if next.is_null() || (*next).is_null() {
+            return None;
+        }
+        let entry = CStr::from_ptr(*next as _).to_str().ok();
+        next = next.add(1);
+        if let Some(kv) = entry.and_then(|s| s.split_once('=')) {
+            return Some(kv);
+        }
+    })
+}
+
+/// Get an environment variable.
+///
+/// # Safety
+///
+/// `envp` must be the environment pointer passed to `_start(argc, argv, envp)`.
+pub unsafe fn var(envp: *const *const u8, key: &str) -> Option<&'static str> {
+    vars(envp).find(|(k, _)| *k == key).map(|(_, v)| v)
+}
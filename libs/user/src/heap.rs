@@ -7,7 +7,7 @@ use core::{
 use memory::{
     address::V,
     free_list_allocator::FreeListAllocator,
-    page::{Page, PageResource, PageSize, Size1G},
+    page::{Page, PageResource, PageSize, Size1G, Size4K},
 };
 use spin::Mutex;
 
@@ -25,7 +25,7 @@ unsafe impl GlobalAlloc for NoAlloc {
 
 /// Userspace heap allocator.
 pub struct UserHeap {
-    fa: Mutex<FreeListAllocator<V, UserPageResource, { Size1G::LOG_BYTES + 1 }, false>>,
+    fa: Mutex<FreeListAllocator<V, UserPageResource, { Size1G::LOG_BYTES + 1 }>>,
 }
 
 impl UserHeap {
@@ -41,18 +41,29 @@ const USER_PAGE_RESOURCE: UserPageResource = UserPageResource;
 struct UserPageResource;
 
 impl PageResource<V> for UserPageResource {
-    /// Allocate virtual pages that are backed by physical memory.
+    /// Map virtual pages. They are backed by physical memory on first touch.
     fn acquire_pages<S: PageSize>(&self, pages: usize) -> Option<Range<Page<S>>> {
-        let addr = memory::sbrk(pages << S::LOG_BYTES)?;
-        assert!(addr.is_aligned_to(S::BYTES));
-        let start_page = Page::new(addr);
+        let size = pages << S::LOG_BYTES;
+        // Map extra pages, and unmap the unaligned head and tail
+        let extra = S::BYTES - Size4K::BYTES;
+        let addr = memory::mmap(size + extra)?;
+        let start = addr.align_up(S::BYTES);
+        let end = start + size;
+        if start != addr {
+            memory::munmap(addr, start - addr);
+        }
+        if end != addr + (size + extra) {
+            memory::munmap(end, addr + (size + extra) - end);
+        }
+        let start_page = Page::new(start);
         let end_page = Page::forward(start_page, pages);
         Some(start_page..end_page)
     }
 
-    /// Release and unmap virtual pages.
-    fn release_pages<S: PageSize>(&self, _pages: Range<Page<S>>) {
-        unimplemented!()
+    /// Unmap virtual pages, and return their memory to the kernel.
+    fn release_pages<S: PageSize>(&self, pages: Range<Page<S>>) {
+        let size = Page::steps_between(&pages.start, &pages.end).unwrap() << S::LOG_BYTES;
+        memory::munmap(pages.start.start(), size);
     }
 }
 
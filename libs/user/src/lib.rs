@@ -2,12 +2,16 @@
 #![feature(core_intrinsics)]
 #![feature(step_trait)]
 #![feature(const_mut_refs)]
+#![feature(box_syntax)]
 
 extern crate alloc;
 
 mod heap;
 
+pub mod env;
+pub mod signal;
 pub mod sys;
+pub mod thread;
 
 #[doc(hidden)]
 pub mod print;
@@ -18,5 +22,5 @@ static ALLOCATOR: heap::UserHeap = heap::UserHeap::new();
 #[panic_handler]
 fn panic(info: &::core::panic::PanicInfo) -> ! {
     println!("{}", info);
-    sys::exit()
+    sys::exit(-1)
 }
@@ -0,0 +1,41 @@
+pub use syscall::signal::*;
+use syscall::Error;
+
+pub enum SigHandler {
+    Default,
+    Ignore,
+    Handler(extern "C" fn(signal: usize)),
+}
+
+/// Signal handlers return here
+extern "C" fn restore() -> ! {
+    syscall::sigreturn()
+}
+
+/// Send a signal to a process. `pid == 0` refers to the calling process.
+#[inline]
+pub fn kill(pid: usize, sig: usize) -> Result<(), Error> {
+    Error::check(syscall::kill(pid, sig)).map(|_| ())
+}
+
+/// Set the process receiving keyboard interrupts. `pid == 0` refers to the calling process.
+#[inline]
+pub fn set_foreground(pid: usize) -> Result<(), Error> {
+    Error::check(syscall::set_foreground(pid)).map(|_| ())
+}
+
+/// Change the signal mask. Returns the previous mask.
+#[inline]
+pub fn sigprocmask(how: usize, set: usize) -> Result<usize, Error> {
+    Error::check(syscall::sigprocmask(how, set))
+}
+
+/// Set the handler of a signal. Returns the previous handler, or `EINVAL` if the signal cannot be caught.
+pub fn signal(sig: usize, handler: SigHandler) -> Result<usize, Error> {
+    let handler = match handler {
+        SigHandler::Default => SIG_DFL,
+        SigHandler::Ignore => SIG_IGN,
+        SigHandler::Handler(f) => f as usize,
+    };
+    Error::check(syscall::sigaction(sig, handler, restore as usize))
+}
@@ -1,7 +1,97 @@
-pub use syscall::{ModuleRequest, Payload, RawModuleRequest};
+pub use syscall::{exit, halt, log, module_call, raw_module_call, Error};
 
-pub use syscall::{exec, exit, halt, log, module_call, wait};
+pub use syscall::{ModuleRequest, Payload, RawModuleRequest};
 
 pub use vfs::{Fd, VFSRequest};
 
 pub use vfs::{chdir, close, cwd, open, read, readdir, write};
+
+/// Start a new process and return its id. See [`syscall::spawn`].
+#[inline]
+pub fn spawn(
+    path: &str,
+    args: &[&str],
+    env: &[&str],
+    fds: Option<&[(u32, u32)]>,
+) -> Result<usize, Error> {
+    Error::check(syscall::spawn(path, args, env, fds))
+}
+
+/// Run a program and wait for it to exit. Returns its exit status.
+pub fn exec(path: &str, args: &[&str]) -> Result<isize, Error> {
+    let pid = spawn(path, args, &[], None)?;
+    let mut status = 0;
+    waitpid(pid, &mut status)?;
+    Ok(status)
+}
+
+/// Duplicate the calling process. See [`syscall::fork`].
+///
+/// Returns the id of the child in the parent, and 0 in the child.
+#[inline]
+pub fn fork() -> Result<usize, Error> {
+    Error::check(syscall::fork())
+}
+
+/// Wait for a child process to exit, and release it. `pid` 0 waits for any child.
+///
+/// Returns the id of the child and stores its exit status to `status`.
+#[inline]
+pub fn waitpid(pid: usize, status: &mut isize) -> Result<usize, Error> {
+    Error::check(syscall::waitpid(pid, status))
+}
+
+/// Wait for any child process to exit. See [`waitpid`].
+#[inline]
+pub fn wait(status: &mut isize) -> Result<usize, Error> {
+    waitpid(0, status)
+}
+
+/// User id of the calling process.
+#[inline]
+pub fn getuid() -> u32 {
+    syscall::getuid() as _
+}
+
+/// Group id of the calling process.
+#[inline]
+pub fn getgid() -> u32 {
+    syscall::getgid() as _
+}
+
+/// Change the user id of the calling process. See [`syscall::setuid`].
+#[inline]
+pub fn setuid(uid: u32) -> Result<(), Error> {
+    Error::check(syscall::setuid(uid as _)).map(|_| ())
+}
+
+/// Change the group id of the calling process. See [`syscall::setgid`].
+#[inline]
+pub fn setgid(gid: u32) -> Result<(), Error> {
+    Error::check(syscall::setgid(gid as _)).map(|_| ())
+}
+
+/// Set the nice value (-20..=19) of a process. `pid == 0` refers to the calling process.
+#[inline]
+pub fn set_priority(pid: usize, nice: isize) -> Result<(), Error> {
+    Error::check(syscall::set_priority(pid, nice)).map(|_| ())
+}
+
+/// Load a kernel module from a file, and return its id.
+#[inline]
+pub fn insmod(path: &str) -> Result<usize, Error> {
+    Error::check(syscall::insmod(path))
+}
+
+/// Unload a kernel module by name.
+#[inline]
+pub fn rmmod(name: &str) -> Result<(), Error> {
+    Error::check(syscall::rmmod(name)).map(|_| ())
+}
+
+/// Write the list of loaded kernel modules to `buf`, one `name id size calls` line each.
+/// Returns the number of bytes written.
+#[inline]
+pub fn lsmod(buf: &mut [u8]) -> Result<usize, Error> {
+    Error::check(syscall::lsmod(buf))
+}
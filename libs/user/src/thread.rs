@@ -0,0 +1,59 @@
+use alloc::{boxed::Box, sync::Arc};
+use sync::Monitor;
+
+/// An owned permission to join on a thread.
+///
+/// Dropping the handle detaches the thread. Its stack is allocated by the kernel, and released when it exits.
+pub struct JoinHandle<T> {
+    tid: usize,
+    result: Arc<Monitor<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Thread id
+    pub fn id(&self) -> usize {
+        self.tid
+    }
+
+    /// Wait for the thread to finish, and return its result.
+    pub fn join(self) -> T {
+        let result = {
+            let mut result = self.result.lock();
+            while result.is_none() {
+                result = self.result.wait(result);
+            }
+            result.take().unwrap()
+        };
+        syscall::thread_join(self.tid);
+        result
+    }
+}
+
+/// Spawn a new thread in the current process.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Monitor::new(None));
+    let their_result = result.clone();
+    let main: Box<dyn FnOnce()> = box move || {
+        let value = f();
+        let mut result = their_result.lock();
+        *result = Some(value);
+        their_result.notify_all();
+    };
+    let main = Box::into_raw(box main);
+    let tid = syscall::thread_spawn(thread_start, main as usize, 0);
+    assert!(tid >= 0, "failed to spawn thread");
+    JoinHandle {
+        tid: tid as _,
+        result,
+    }
+}
+
+extern "C" fn thread_start(main: usize) -> ! {
+    let main = unsafe { Box::from_raw(main as *mut Box<dyn FnOnce()>) };
+    main();
+    syscall::thread_exit()
+}
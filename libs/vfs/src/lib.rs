@@ -7,9 +7,10 @@ use alloc::{
     string::String,
     vec::Vec,
 };
+use memory::page::Frame;
 use proc::ProcId;
 use ramfs::RamFS;
-use syscall::{ModuleRequest, RawModuleRequest};
+use syscall::{Error, ModuleRequest, Payload};
 
 extern crate alloc;
 
@@ -25,6 +26,15 @@ impl Fd {
     pub const STDERR: Self = Fd(2);
 }
 
+impl Payload for Fd {
+    fn decode(data: usize) -> Self {
+        Fd(data as _)
+    }
+    fn encode(&self) -> usize {
+        self.0 as _
+    }
+}
+
 #[derive(Clone)]
 pub struct Node {
     pub name: Cow<'static, str>,
@@ -53,18 +63,31 @@ pub trait FileSystem: Sync + Send {
     fn read_dir(&self, node: &Node) -> Option<Vec<String>>;
     // Mount
     fn mount(&self, parent: &Node, file: &str, key: usize) -> Option<Node>;
+    // Memory mappings
+    /// Contents of a file that stay in memory for the lifetime of the kernel, e.g. files of the init-fs.
+    /// Lets the kernel use the file without reading a copy of it.
+    fn resident(&self, _node: &Node) -> Option<&'static [u8]> {
+        None
+    }
+    /// The frame at `offset` of the memory backing a node, e.g. the registers of a device.
+    /// Only nodes with such memory can be mapped with `MAP_SHARED`.
+    fn shared_frame(&self, _node: &Node, _offset: usize) -> Option<Frame> {
+        None
+    }
 }
 
 // Possible syscalls:
 // open, close, read, write, link, unlink, stat, fstat, lseek, isatty
 // readdir, mkdir
 
+#[derive(ModuleRequest)]
 pub enum VFSRequest<'a> {
     Open(&'a str),
     Close(Fd),
     Read(Fd, &'a mut [u8]),
     Write(Fd, &'a [u8]),
     ReadDir(Fd, usize, &'a mut [u8]),
+    #[capability(SYS_ADMIN)]
     Mount {
         path: &'a str,
         dev: usize,
@@ -72,77 +95,39 @@ pub enum VFSRequest<'a> {
     },
     GetCwd(&'a mut [u8]),
     SetCwd(&'a str),
+    /// Map `size` bytes of a file from `offset`, at an address chosen by the kernel.
+    /// Shared mappings need `SYS_ADMIN`, as they expose the memory of devices.
+    /// Fails with `ENODEV` if the file does not support the kind of mapping.
+    Mmap {
+        fd: Fd,
+        offset: usize,
+        size: usize,
+        prot: usize,
+        flags: usize,
+    },
 }
 
-impl<'a> ModuleRequest<'a> for VFSRequest<'a> {
-    fn as_raw(&'a self) -> RawModuleRequest<'a> {
-        match self {
-            Self::Open(s) => RawModuleRequest::new(1, s, &(), &()),
-            Self::Close(fd) => RawModuleRequest::new(2, &fd.0, &(), &()),
-            Self::Read(fd, buf) => RawModuleRequest::new(3, &fd.0, buf, &()),
-            Self::Write(fd, buf) => RawModuleRequest::new(4, &fd.0, buf, &()),
-            Self::ReadDir(fd, i, buf) => RawModuleRequest::new(5, &fd.0, i, buf),
-            Self::Mount { path, dev, fs } => RawModuleRequest::new(6, path, dev, fs),
-            Self::GetCwd(buf) => RawModuleRequest::new(7, buf, &(), &()),
-            Self::SetCwd(s) => RawModuleRequest::new(8, s, &(), &()),
-        }
-    }
-    fn from_raw(raw: RawModuleRequest<'a>) -> Self {
-        match raw.id() {
-            1 => Self::Open(raw.arg(0)),
-            2 => Self::Close(Fd(raw.arg(0))),
-            3 => Self::Read(Fd(raw.arg(0)), raw.arg(1)),
-            4 => Self::Write(Fd(raw.arg(0)), raw.arg(1)),
-            5 => Self::ReadDir(Fd(raw.arg(0)), raw.arg(1), raw.arg(2)),
-            6 => Self::Mount {
-                path: raw.arg(0),
-                dev: raw.arg(1),
-                fs: raw.arg(2),
-            },
-            7 => Self::GetCwd(raw.arg(0)),
-            8 => Self::SetCwd(raw.arg(0)),
-            _ => panic!("Unknown request"),
-        }
-    }
-}
-
-pub fn open(path: &str) -> Option<Fd> {
+pub fn open(path: &str) -> Result<Fd, Error> {
     let ret = syscall::module_call("vfs", &VFSRequest::Open(path));
-    if ret < 0 {
-        None
-    } else {
-        Some(Fd(ret as u32))
-    }
+    Error::check(ret).map(|fd| Fd(fd as u32))
 }
 
-pub fn close(fd: Fd) {
-    syscall::module_call("vfs", &VFSRequest::Close(fd));
+pub fn close(fd: Fd) -> Result<(), Error> {
+    Error::check(syscall::module_call("vfs", &VFSRequest::Close(fd))).map(|_| ())
 }
 
-pub fn read(fd: Fd, buf: &mut [u8]) -> Result<usize, ()> {
-    let ret = syscall::module_call("vfs", &VFSRequest::Read(fd, buf));
-    if ret < 0 {
-        Err(())
-    } else {
-        Ok(ret as usize)
-    }
+pub fn read(fd: Fd, buf: &mut [u8]) -> Result<usize, Error> {
+    Error::check(syscall::module_call("vfs", &VFSRequest::Read(fd, buf)))
 }
 
-pub fn write(fd: Fd, buf: &[u8]) -> Result<usize, ()> {
-    let ret = syscall::module_call("vfs", &VFSRequest::Write(fd, buf));
-    if ret < 0 {
-        Err(())
-    } else {
-        Ok(ret as usize)
-    }
+pub fn write(fd: Fd, buf: &[u8]) -> Result<usize, Error> {
+    Error::check(syscall::module_call("vfs", &VFSRequest::Write(fd, buf)))
 }
 
-pub fn readdir(fd: Fd, i: usize) -> Result<Option<String>, ()> {
+pub fn readdir(fd: Fd, i: usize) -> Result<Option<String>, Error> {
     let mut buf = [0u8; 256];
     let ret = syscall::module_call("vfs", &VFSRequest::ReadDir(fd, i, &mut buf));
-    if ret < 0 {
-        Err(())
-    } else if ret == 0 {
+    if Error::check(ret)? == 0 {
         Ok(None)
     } else {
         let end = buf.iter().position(|&x| x == 0).unwrap_or(buf.len());
@@ -150,31 +135,48 @@ pub fn readdir(fd: Fd, i: usize) -> Result<Option<String>, ()> {
     }
 }
 
-pub fn cwd() -> Result<String, ()> {
+pub fn cwd() -> Result<String, Error> {
     let mut buf = [0u8; 256];
-    let ret = syscall::module_call("vfs", &VFSRequest::GetCwd(&mut buf));
-    if ret < 0 {
-        Err(())
-    } else {
-        let size = ret as usize;
-        core::str::from_utf8(&buf[..size])
-            .map(|s| s.to_owned())
-            .map_err(|_| ())
-    }
+    let size = Error::check(syscall::module_call("vfs", &VFSRequest::GetCwd(&mut buf)))?;
+    core::str::from_utf8(&buf[..size])
+        .map(|s| s.to_owned())
+        .map_err(|_| Error::EIO)
 }
 
-pub fn chdir(path: &str) -> Result<(), ()> {
-    let ret = syscall::module_call("vfs", &VFSRequest::SetCwd(path));
-    if ret < 0 {
-        Err(())
-    } else {
-        Ok(())
-    }
+pub fn chdir(path: &str) -> Result<(), Error> {
+    Error::check(syscall::module_call("vfs", &VFSRequest::SetCwd(path))).map(|_| ())
+}
+
+/// Map `size` bytes of `fd` from `offset`, with a `mman::PROT_*` protection and a `mman::MAP_*` flag.
+/// Returns the address of the mapping. Unmap it with `syscall::munmap`.
+pub fn mmap(fd: Fd, offset: usize, size: usize, prot: usize, flags: usize) -> Result<usize, Error> {
+    Error::check(syscall::module_call(
+        "vfs",
+        &VFSRequest::Mmap {
+            fd,
+            offset,
+            size,
+            prot,
+            flags,
+        },
+    ))
 }
 
 pub trait VFSManager {
     fn init(&self, ramfs: &'static mut RamFS);
+    /// Create the file system state of a new process. An empty `cwd` inherits the working directory of the
+    /// current process, i.e. the parent of a spawned or forked process.
     fn register_process(&self, proc: ProcId, cwd: String) -> Box<dyn core::any::Any>;
     fn deregister_process(&self, proc: ProcId);
+    /// Capture file descriptors of the current process, to be inherited by a new process.
+    /// `fds` lists `(child_fd, parent_fd)` pairs, or `None` to inherit all of them.
+    /// Returns `None` if any of the parent fds is not open.
+    fn capture_fds(&self, fds: Option<&[(Fd, Fd)]>) -> Option<Box<dyn core::any::Any>>;
+    /// Replace the file descriptors of the current process with the ones captured by `capture_fds`.
+    fn install_fds(&self, fds: Box<dyn core::any::Any>);
     fn register_fs(&self, fs: &'static dyn FileSystem);
+    /// Remove a file system. Returns `false` if it is still mounted.
+    fn unregister_fs(&self, name: &str) -> bool;
+    /// The node opened as `fd` by the current process.
+    fn file_node(&self, fd: Fd) -> Option<Node>;
 }
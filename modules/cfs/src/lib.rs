@@ -0,0 +1,275 @@
+#![feature(format_args_nl)]
+#![feature(default_alloc_error_handler)]
+#![feature(box_syntax)]
+#![feature(generic_associated_types)]
+#![feature(downcast_unchecked)]
+#![feature(const_btree_new)]
+#![no_std]
+
+#[macro_use]
+extern crate kernel_module;
+extern crate alloc;
+
+use alloc::{boxed::Box, collections::BTreeSet, vec::Vec};
+use atomic::{Atomic, Ordering};
+use core::any::Any;
+use core::sync::atomic::{AtomicIsize, AtomicU64};
+use interrupt::UninterruptibleMutex;
+use kernel_module::{kernel_module, KernelModule, SERVICE};
+use proc::TaskId;
+use sched::{RunState, Scheduler};
+use spin::Mutex;
+
+/// Load weight of each nice value, from -20 to 19. Same as Linux's `sched_prio_to_weight`.
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+const NICE_0_WEIGHT: u64 = 1024;
+/// Virtual runtime of one timer tick at nice 0.
+const TICK_VRUNTIME: u64 = 1024;
+/// Minimum virtual runtime lead before the current task gets preempted.
+const MIN_GRANULARITY: u64 = TICK_VRUNTIME;
+/// Maximum virtual runtime credit a task gets after sleeping.
+const SLEEPER_CREDIT: u64 = 3 * TICK_VRUNTIME;
+
+#[derive(Debug)]
+pub struct State {
+    run_state: Atomic<RunState>,
+    nice: AtomicIsize,
+    vruntime: AtomicU64,
+}
+
+impl State {
+    pub const fn new() -> Self {
+        Self {
+            run_state: Atomic::new(RunState::Ready),
+            nice: AtomicIsize::new(0),
+            vruntime: AtomicU64::new(0),
+        }
+    }
+
+    fn weight(&self) -> u64 {
+        NICE_TO_WEIGHT[(self.nice.load(Ordering::SeqCst) + 20) as usize]
+    }
+}
+
+#[kernel_module(provides("scheduler"))]
+pub static mut SCHEDULER: CompletelyFairScheduler = CompletelyFairScheduler::new();
+
+/// A CFS-style scheduler.
+///
+/// Ready tasks are ordered by their virtual runtime, which grows slower for tasks with lower nice values.
+/// The task with the smallest virtual runtime runs next.
+pub struct CompletelyFairScheduler {
+    current_task: Vec<Atomic<Option<TaskId>>>,
+    timeline: Mutex<BTreeSet<(u64, TaskId)>>,
+    min_vruntime: AtomicU64,
+}
+
+impl CompletelyFairScheduler {
+    const fn new() -> Self {
+        Self {
+            current_task: Vec::new(),
+            timeline: Mutex::new(BTreeSet::new()),
+            min_vruntime: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    fn set_current_task_id(&self, id: TaskId) {
+        self.current_task[SERVICE.current_core()].store(Some(id), Ordering::SeqCst);
+    }
+
+    #[inline]
+    fn get_state(&self, task: TaskId) -> &State {
+        let task = SERVICE.process_manager().get_task_by_id(task).unwrap();
+        debug_assert!(task.sched().is::<State>());
+        let state = task.sched() as *const dyn Any;
+        unsafe { (*(state as *const dyn Any)).downcast_ref_unchecked::<State>() }
+    }
+
+    #[inline]
+    fn enqueue(&self, task: TaskId, state: &State) {
+        let vruntime = state.vruntime.load(Ordering::SeqCst);
+        self.timeline
+            .lock_uninterruptible()
+            .insert((vruntime, task));
+    }
+
+    #[inline]
+    fn get_next_schedulable_task(&self) -> TaskId {
+        debug_assert!(!interrupt::is_enabled());
+        let mut timeline = self.timeline.lock();
+        if let Some(next) = timeline.iter().next().cloned() {
+            timeline.remove(&next);
+            self.min_vruntime.fetch_max(next.0, Ordering::SeqCst);
+            next.1
+        } else {
+            // We should at least have an `idle` task that is runnable
+            panic!("No more tasks to run!");
+        }
+    }
+
+    #[inline]
+    pub fn enqueue_current_task_as_ready(&self) {
+        debug_assert!(!interrupt::is_enabled());
+        let task = self.get_current_task_id().unwrap();
+        let state = self.get_state(task);
+        debug_assert_ne!(state.run_state.load(Ordering::SeqCst), RunState::Ready);
+        state.run_state.store(RunState::Ready, Ordering::SeqCst);
+        self.enqueue(task, state);
+    }
+}
+
+impl Scheduler for CompletelyFairScheduler {
+    fn new_state(&self) -> Box<dyn Any> {
+        Box::new(State::new())
+    }
+
+    fn get_current_task_id(&self) -> Option<TaskId> {
+        self.current_task[SERVICE.current_core()].load(Ordering::SeqCst)
+    }
+
+    fn register_new_task(&self, task: TaskId) {
+        let _guard = interrupt::uninterruptible();
+        let state = self.get_state(task);
+        if state.run_state.load(Ordering::SeqCst) == RunState::Ready {
+            debug_assert!(!interrupt::is_enabled());
+            // Start at the head of the timeline, but behind all the existing tasks
+            let vruntime = self.min_vruntime.load(Ordering::SeqCst);
+            state.vruntime.store(vruntime, Ordering::SeqCst);
+            self.enqueue(task, state);
+        }
+    }
+
+    fn remove_task(&self, task: TaskId) {
+        debug_assert!(!interrupt::is_enabled());
+        let _ = self.current_task[SERVICE.current_core()].fetch_update(
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+            |curr| {
+                if curr == Some(task) {
+                    Some(None)
+                } else {
+                    None
+                }
+            },
+        );
+    }
+
+    fn sleep(&self) {
+        let _guard = interrupt::uninterruptible();
+        let task = self.get_current_task_id().unwrap();
+        let state = self.get_state(task);
+        // Already `Sleeping` after `prepare_to_sleep`, or `Ready` if woken up since then
+        let _ = state.run_state.compare_exchange(
+            RunState::Running,
+            RunState::Sleeping,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+        self.schedule();
+    }
+
+    fn prepare_to_sleep(&self) {
+        let _guard = interrupt::uninterruptible();
+        let task = self.get_current_task_id().unwrap();
+        let state = self.get_state(task);
+        assert_eq!(state.run_state.load(Ordering::SeqCst), RunState::Running);
+        state.run_state.store(RunState::Sleeping, Ordering::SeqCst);
+    }
+
+    fn wake_up(&self, task: TaskId) {
+        let _guard = interrupt::uninterruptible();
+        let state = self.get_state(task);
+        let old = state
+            .run_state
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |old| {
+                if old == RunState::Sleeping {
+                    Some(RunState::Ready)
+                } else {
+                    None
+                }
+            });
+        if old == Ok(RunState::Sleeping) {
+            // Interactive tasks sleep a lot. Give them a bounded credit so they run soon,
+            // without letting a long sleeper monopolize the core.
+            let floor = self
+                .min_vruntime
+                .load(Ordering::SeqCst)
+                .saturating_sub(SLEEPER_CREDIT);
+            state.vruntime.fetch_max(floor, Ordering::SeqCst);
+            self.enqueue(task, state);
+        }
+    }
+
+    fn set_priority(&self, task: TaskId, nice: isize) {
+        let state = self.get_state(task);
+        state.nice.store(nice.clamp(-20, 19), Ordering::SeqCst);
+    }
+
+    fn schedule(&self) -> ! {
+        interrupt::disable();
+
+        let current_task = self.get_current_task_id();
+        let state = current_task.map(|t| self.get_state(t));
+
+        if current_task.is_some()
+            && state.unwrap().run_state.load(Ordering::SeqCst) == RunState::Running
+        {
+            // Continue with this task
+            unsafe { SERVICE.return_to_user(current_task.unwrap()) }
+        } else {
+            // No current task or the current Task is blocked, switch to a new task.
+            let next_task = self.get_next_schedulable_task();
+            {
+                let state = self.get_state(next_task);
+                debug_assert_eq!(state.run_state.load(Ordering::SeqCst), RunState::Ready);
+                state.run_state.store(RunState::Running, Ordering::SeqCst);
+            }
+            self.set_current_task_id(next_task);
+            atomic::fence(Ordering::SeqCst);
+            // Return to user
+            unsafe { SERVICE.return_to_user(next_task) }
+        }
+    }
+
+    fn timer_tick(&self) -> ! {
+        debug_assert!(!interrupt::is_enabled());
+        let current_task = self.get_current_task_id().unwrap();
+        let state = self.get_state(current_task);
+        debug_assert_eq!(state.run_state.load(Ordering::SeqCst), RunState::Running);
+
+        let delta = TICK_VRUNTIME * NICE_0_WEIGHT / state.weight();
+        let vruntime = state.vruntime.fetch_add(delta, Ordering::SeqCst) + delta;
+        let leftmost = self.timeline.lock().iter().next().map(|(v, _)| *v);
+        match leftmost {
+            Some(v) if vruntime > v + MIN_GRANULARITY => {
+                self.enqueue_current_task_as_ready();
+                self.schedule();
+            }
+            _ => unsafe { SERVICE.return_to_user(current_task) },
+        }
+    }
+}
+
+impl KernelModule for CompletelyFairScheduler {
+    fn init(&'static mut self) -> anyhow::Result<()> {
+        self.current_task
+            .resize_with(SERVICE.num_cores(), || Atomic::new(None));
+        SERVICE.set_scheduler(self);
+        Ok(())
+    }
+}
+
+#[test]
+fn nice_to_weight() {
+    let state = State::new();
+    assert_eq!(state.weight(), NICE_0_WEIGHT);
+    state.nice.store(-20, Ordering::SeqCst);
+    assert_eq!(state.weight(), 88761);
+    state.nice.store(19, Ordering::SeqCst);
+    assert_eq!(state.weight(), 15);
+}
@@ -11,10 +11,12 @@ extern crate alloc;
 use alloc::{borrow::ToOwned, collections::BTreeMap, format, string::String, vec::Vec};
 use dev::{DevRequest, Device};
 use kernel_module::{kernel_module, KernelModule, SERVICE};
+use memory::page::{Frame, PageSize, Size4K};
 use spin::{Lazy, RwLock};
+use syscall::{CallContext, Error};
 use vfs::{FileSystem, Node, Stat, VFSRequest};
 
-#[kernel_module]
+#[kernel_module(name = "dev", depends("vfs"))]
 pub static DEV: DEV = DEV {};
 
 pub struct DEV {}
@@ -36,13 +38,20 @@ impl KernelModule for DEV {
         Ok(())
     }
 
-    fn handle_module_call<'a>(&self, privileged: bool, request: Self::ModuleRequest<'a>) -> isize {
+    fn handle_module_call<'a>(
+        &self,
+        _ctx: &CallContext,
+        request: Self::ModuleRequest<'a>,
+    ) -> isize {
         match request {
             DevRequest::RegisterDev(dev) => {
-                assert!(privileged);
                 DEV_FS.devices.write().insert(dev.name().to_owned(), *dev);
                 0
             }
+            DevRequest::UnregisterDev(name) => match DEV_FS.devices.write().remove(name) {
+                Some(_) => 0,
+                None => Error::ENOENT.into(),
+            },
         }
     }
 }
@@ -122,4 +131,14 @@ impl FileSystem for DevFS {
     fn mount(&self, _parent: &Node, _file: &str, _key: usize) -> Option<Node> {
         unimplemented!()
     }
+    fn shared_frame(&self, node: &Node, offset: usize) -> Option<Frame> {
+        let devices = self.devices.read();
+        let registers = devices.get(node.name.as_ref())?.registers()?;
+        let start = registers
+            .start
+            .start()
+            .as_usize()
+            .checked_add(offset & !Size4K::MASK)?;
+        Some(Frame::new(start.into())).filter(|frame| *frame < registers.end)
+    }
 }
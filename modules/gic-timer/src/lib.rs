@@ -16,7 +16,7 @@ use tock_registers::interfaces::{Readable, Writeable};
 
 const TIMER_INTERRUPT_FREQUENCY: usize = 60; // Hz
 
-#[kernel_module]
+#[kernel_module(depends("interrupt_controller"), provides("timer_controller"))]
 pub static mut GIC_TIMER: GICTimer = GICTimer { irq: 0 };
 
 pub struct GICTimer {
@@ -35,7 +35,7 @@ impl GICTimer {
     }
 
     fn set_timer_handler(&self, irq: usize) {
-        SERVICE.interrupt_controller().set_irq_handler(irq, box || {
+        SERVICE.set_irq_handler(irq, box || {
             // Update compare value
             let step = CNTFRQ_EL0.get() as u64 / TIMER_INTERRUPT_FREQUENCY as u64;
             CNTP_TVAL_EL0.set(step as u64);
@@ -74,7 +74,8 @@ impl TimerController for GICTimer {
         if bsp {
             // pass
         } else {
-            unimplemented!()
+            // The timer and its PPI are banked per core
+            self.start_timer(self.irq);
         }
     }
 }
@@ -99,6 +99,11 @@ unsafe impl Send for GIC {}
 unsafe impl Sync for GIC {}
 
 impl GIC {
+    const DEFAULT_PRIORITIES: u32 = GICD::IPRIORITYRAULT
+        | GICD::IPRIORITYRAULT << 8
+        | GICD::IPRIORITYRAULT << 16
+        | GICD::IPRIORITYRAULT << 24;
+
     const fn new() -> Self {
         Self {
             GICD: UnsafeCell::new(core::ptr::null_mut()),
@@ -129,30 +134,31 @@ impl GIC {
         let (GICD, GICC) = (self.gicd(), self.gicc());
         unsafe { barrier::dsb(barrier::SY) };
         unsafe {
-            // Disable all interrupts
-            GICD.CTLR.set(GICD::CTLR_DISABLE);
             if bsp {
+                // Disable all interrupts
+                GICD.CTLR.set(GICD::CTLR_DISABLE);
                 for n in 0..(IRQ_LINES / 32) {
                     GICD.ICENABLER[n].set(!0);
                     GICD.ICPENDR[n].set(!0);
                     GICD.ICACTIVER[n].set(!0);
                 }
+                // Set priority
+                for n in 0..(IRQ_LINES / 4) {
+                    GICD.IPRIORITYR[n].set(Self::DEFAULT_PRIORITIES);
+                }
+                // set all interrupts to level triggered
+                for n in 0..(IRQ_LINES / 16) {
+                    GICD.ICFGR[n].set(0);
+                }
+                // Enable GIC
+                GICD.CTLR.set(GICD::CTLR_ENABLE);
+            } else {
+                // SGI and PPI priorities are banked per core
+                for n in 0..(32 / 4) {
+                    GICD.IPRIORITYR[n].set(Self::DEFAULT_PRIORITIES);
+                }
             }
-            // Set priority
-            for n in 0..(IRQ_LINES / 4) {
-                GICD.IPRIORITYR[n].set(
-                    GICD::IPRIORITYRAULT
-                        | GICD::IPRIORITYRAULT << 8
-                        | GICD::IPRIORITYRAULT << 16
-                        | GICD::IPRIORITYRAULT << 24,
-                );
-            }
-            // set all interrupts to level triggered
-            for n in 0..(IRQ_LINES / 16) {
-                GICD.ICFGR[n].set(0);
-            }
-            // Enable GIC
-            GICD.CTLR.set(GICD::CTLR_ENABLE);
+            // Enable CPU interface
             GICC.PMR.set(GICC::PMR_PRIORITY);
             GICC.CTLR.set(GICC::CTLR_ENABLE);
             barrier::dmb(barrier::SY);
@@ -160,7 +166,7 @@ impl GIC {
     }
 }
 
-#[kernel_module]
+#[kernel_module(provides("interrupt_controller"))]
 pub static mut GIC: GIC = GIC::new();
 
 impl KernelModule for GIC {
@@ -214,8 +220,12 @@ impl InterruptController for GIC {
         }
     }
 
-    fn disable_irq(&self, _irq: usize) {
-        unimplemented!()
+    fn disable_irq(&self, irq: usize) {
+        unsafe {
+            asm!("dsb SY");
+            GIC.gicd().ICENABLER[irq / 32].set(1 << (irq & (32 - 1)));
+            asm!("dmb SY");
+        }
     }
 
     fn interrupt_begin(&self) {
@@ -238,4 +248,8 @@ impl InterruptController for GIC {
             IRQ_HANDLERS[irq] = Some(handler);
         }
     }
+
+    fn remove_irq_handler(&self, irq: usize) -> Option<IRQHandler> {
+        unsafe { IRQ_HANDLERS[irq].take() }
+    }
 }
@@ -1,21 +1,39 @@
 #![feature(format_args_nl)]
 #![feature(default_alloc_error_handler)]
+#![feature(used_with_arg)]
 #![no_std]
 
 #[macro_use]
 extern crate kernel_module;
 extern crate alloc;
 
-use kernel_module::{kernel_module, KernelModule};
+use kernel_module::{export_symbol, kernel_module, KernelModule};
 
-#[kernel_module]
+extern "Rust" {
+    /// Exported by the kernel
+    fn kernel_version() -> &'static str;
+}
+
+#[kernel_module(name = "hello")]
 pub static HELLO: Hello = Hello;
 
 pub struct Hello;
 
+#[export_symbol]
+pub fn hello_greeting() -> &'static str {
+    "Hello, Kernel Module!"
+}
+
 impl KernelModule for Hello {
     fn init(&mut self) -> anyhow::Result<()> {
-        log!("Hello, Kernel Module!");
+        log!("{} (kernel v{})", hello_greeting(), unsafe {
+            kernel_version()
+        });
+        Ok(())
+    }
+
+    fn deinit(&mut self) -> anyhow::Result<()> {
+        log!("Goodbye, Kernel Module!");
         Ok(())
     }
 }
@@ -9,6 +9,8 @@ extern crate log;
 extern crate alloc;
 
 use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use crossbeam::queue::SegQueue;
 use dev::{DevRequest, Device};
 use kernel_module::{kernel_module, KernelModule, SERVICE};
@@ -16,12 +18,20 @@ use log::Logger;
 use memory::{page::Frame, volatile::Volatile};
 use spin::{Lazy, RwLock};
 use sync::Monitor;
+use syscall::signal::SIGINT;
 
-#[kernel_module]
+/// Ctrl-C
+const INTERRUPT_KEY: u8 = 0x03;
+/// Set in `PL011::readers` once the device is being unloaded
+const UNLOADING: usize = 1 << (usize::BITS - 1);
+
+#[kernel_module(depends("dev", "interrupt_controller"))]
 pub static PL011: PL011 = PL011 {
     uart: RwLock::new(core::ptr::null_mut()),
+    registers: RwLock::new(None),
     buffer: SegQueue::new(),
     monitor: Lazy::new(|| Monitor::new(())),
+    readers: AtomicUsize::new(0),
 };
 
 unsafe impl Send for PL011 {}
@@ -29,8 +39,12 @@ unsafe impl Sync for PL011 {}
 
 pub struct PL011 {
     pub uart: RwLock<*mut UART0>,
+    /// Physical frame of `uart`
+    registers: RwLock<Option<Frame>>,
     pub buffer: SegQueue<u8>,
     monitor: Lazy<Monitor<()>>,
+    /// Tasks blocked in `read`, or `UNLOADING`
+    readers: AtomicUsize,
 }
 
 impl PL011 {
@@ -48,13 +62,21 @@ impl KernelModule for PL011 {
         let uart = unsafe { &mut *(uart_page.start().as_mut_ptr() as *mut UART0) };
         uart.init();
         *self.uart.write() = uart;
+        *self.registers.write() = Some(Frame::new(uart_frame));
         SERVICE.set_sys_logger(&UART_LOGGER);
         // Initialize interrupts
         let irq = node.interrupts().unwrap().next().unwrap().0;
-        SERVICE.interrupt_controller().set_irq_handler(irq, box || {
+        SERVICE.set_irq_handler(irq, box || {
             let _guard = PL011.monitor.lock();
             while !self.uart().receive_fifo_empty() {
                 let c = self.uart().dr.get() as u8;
+                if c == INTERRUPT_KEY {
+                    let pm = SERVICE.process_manager();
+                    if let Some(proc) = pm.foreground_proc() {
+                        pm.send_signal(proc, SIGINT);
+                    }
+                    continue;
+                }
                 self.buffer.push(c);
             }
             PL011.monitor.notify_all();
@@ -67,6 +89,21 @@ impl KernelModule for PL011 {
         );
         Ok(())
     }
+
+    fn deinit(&'static mut self) -> anyhow::Result<()> {
+        // Fails if a read is in progress, and stops new reads from starting.
+        if self
+            .readers
+            .compare_exchange(0, UNLOADING, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            anyhow::bail!("tty.serial is being read");
+        }
+        kernel_module::module_call("dev", &DevRequest::UnregisterDev(self.name()));
+        // Mask the receive interrupt
+        self.uart().imsc.set(0);
+        Ok(())
+    }
 }
 
 impl Device for PL011 {
@@ -74,11 +111,24 @@ impl Device for PL011 {
         "tty.serial"
     }
 
+    /// Blocks until `buf` is filled. Returns the bytes read so far if interrupted by a signal,
+    /// or `None` if nothing was read.
     fn read(&self, _offset: usize, buf: &mut [u8]) -> Option<usize> {
-        for i in 0..buf.len() {
-            buf[i] = self.uart().getchar(true).unwrap() as _;
+        self.readers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |readers| {
+                (readers & UNLOADING == 0).then_some(readers + 1)
+            })
+            .ok()?;
+        let mut len = 0;
+        while len < buf.len() {
+            match self.uart().getchar(true) {
+                Some(c) => buf[len] = c as _,
+                None => break,
+            }
+            len += 1;
         }
-        Some(buf.len())
+        self.readers.fetch_sub(1, Ordering::SeqCst);
+        (len != 0 || buf.is_empty()).then_some(len)
     }
 
     fn write(&self, _offset: usize, buf: &[u8]) -> Option<usize> {
@@ -87,6 +137,11 @@ impl Device for PL011 {
         }
         Some(buf.len())
     }
+
+    fn registers(&self) -> Option<Range<Frame>> {
+        let frame = (*self.registers.read())?;
+        Some(frame..Frame::new(frame.end()))
+    }
 }
 
 #[repr(C)]
@@ -118,6 +173,7 @@ impl UART0 {
         self.fr.get() & (1 << 4) != 0
     }
 
+    /// Returns `None` if the buffer is empty and `block` is not set, or if interrupted by a signal.
     fn getchar(&mut self, block: bool) -> Option<char> {
         if PL011.buffer.is_empty() {
             if !block {
@@ -125,7 +181,7 @@ impl UART0 {
             } else {
                 let mut guard = PL011.monitor.lock();
                 while PL011.buffer.is_empty() {
-                    guard = PL011.monitor.wait(guard);
+                    guard = PL011.monitor.wait_interruptible(guard).ok()?;
                 }
             }
         }
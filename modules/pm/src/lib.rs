@@ -12,19 +12,26 @@
 extern crate log;
 extern crate alloc;
 
-mod locks;
 mod proc;
+mod signal;
 mod task;
 
-use ::proc::{Proc, ProcId, Runnable, TaskId};
+use ::proc::{Proc, ProcId, Runnable, SignalHandler, TaskId};
 use alloc::{boxed::Box, sync::Arc};
+use atomic::Ordering;
+use core::sync::atomic::AtomicUsize;
 use kernel_module::{kernel_module, KernelModule, SERVICE};
-use locks::{RawCondvar, RawMutex};
+use signal::Delivery;
 use syscall::module_calls::proc::ProcRequest;
+use syscall::signal::NSIG;
+use syscall::{CallContext, Capabilities, Credentials, Error};
 
 use crate::proc::Process;
 
-#[kernel_module]
+/// The process that receives keyboard interrupts
+static FOREGROUND: AtomicUsize = AtomicUsize::new(0);
+
+#[kernel_module(provides("process_manager"))]
 pub static mut PM: ProcessManager = ProcessManager;
 
 pub struct ProcessManager;
@@ -48,6 +55,74 @@ impl ::proc::ProcessManager for ProcessManager {
     fn current_task(&self) -> Option<Arc<dyn ::proc::Task>> {
         task::Task::current().map(|t| t.as_dyn())
     }
+    fn wait_for_child(&self, child: Option<ProcId>) -> Result<(ProcId, isize), Error> {
+        let proc = Process::current().ok_or(Error::ECHILD)?;
+        let is_target = |c: &Arc<Process>| child.map(|id| c.id == id).unwrap_or(true);
+        let mut children = proc.children.lock();
+        loop {
+            if !children.iter().any(is_target) {
+                return Err(Error::ECHILD);
+            }
+            if let Some(i) = children
+                .iter()
+                .position(|c| is_target(c) && c.exit_status().is_some())
+            {
+                let zombie = children.remove(i);
+                return Ok((zombie.id, zombie.exit_status().unwrap()));
+            }
+            children = proc.children.wait_interruptible(children)?;
+        }
+    }
+    fn send_signal(&self, proc: ProcId, signal: usize) -> bool {
+        if signal >= NSIG {
+            return false;
+        }
+        match Process::by_id(proc) {
+            // Signal 0 only checks the existence of the process
+            Some(proc) if signal != 0 => {
+                proc.send_signal(signal);
+                true
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+    fn force_signal(&self, signal: usize) {
+        Process::current().unwrap().signals.force(signal)
+    }
+    fn signal_pending(&self) -> bool {
+        Process::current().map_or(false, |proc| proc.signals.interrupted())
+    }
+    fn dequeue_signal(&self) -> Option<SignalHandler> {
+        let proc = Process::current()?;
+        match proc.signals.dequeue()? {
+            Delivery::Handler(handler) => Some(handler),
+            Delivery::Terminate(signal) => {
+                // Same as the shell convention: 128 + signal number
+                proc.exit(128 + signal as isize);
+                drop(proc);
+                SERVICE.scheduler().schedule()
+            }
+        }
+    }
+    fn foreground_proc(&self) -> Option<ProcId> {
+        match FOREGROUND.load(Ordering::SeqCst) {
+            0 => None,
+            pid => Some(ProcId(pid)),
+        }
+    }
+}
+
+fn proc_or_current(pid: usize) -> Option<Arc<Process>> {
+    match pid {
+        0 => Process::current(),
+        pid => Process::by_id(ProcId(pid)),
+    }
+}
+
+/// Processes of other users can only be controlled with `cap`.
+fn may_control(caller: &Credentials, target: &Process, cap: Capabilities) -> bool {
+    caller.uid == target.credentials.lock().uid || caller.can(cap)
 }
 
 impl KernelModule for ProcessManager {
@@ -58,59 +133,92 @@ impl KernelModule for ProcessManager {
         Ok(())
     }
 
-    fn handle_module_call<'a>(&self, privileged: bool, request: Self::ModuleRequest<'a>) -> isize {
+    fn handle_module_call<'a>(&self, ctx: &CallContext, request: Self::ModuleRequest<'a>) -> isize {
+        let caller = &ctx.credentials;
         match request {
-            ProcRequest::MutexCreate => {
-                let mutex = Box::leak(box RawMutex::new()) as *mut RawMutex;
-                if !privileged {
-                    Process::current().unwrap().locks.lock().push(mutex);
+            ProcRequest::SetPriority(pid, nice) => {
+                if !(-20..=19).contains(&nice) {
+                    return Error::EINVAL.into();
+                }
+                let proc = match proc_or_current(pid) {
+                    Some(proc) => proc,
+                    None => return Error::ESRCH.into(),
+                };
+                if (nice < 0 && !caller.can(Capabilities::SYS_NICE))
+                    || !may_control(caller, &proc, Capabilities::SYS_NICE)
+                {
+                    return Error::EPERM.into();
+                }
+                let _guard = interrupt::uninterruptible();
+                for task in proc.threads.lock().iter() {
+                    SERVICE.scheduler().set_priority(*task, nice);
                 }
-                mutex as _
-            }
-            ProcRequest::MutexLock(mutex) => {
-                let mutex = mutex.cast::<RawMutex>();
-                mutex.lock();
                 0
             }
-            ProcRequest::MutexUnlock(mutex) => {
-                let mutex = mutex.cast::<RawMutex>();
-                mutex.unlock();
-                0
+            ProcRequest::Kill(pid, signal) => {
+                let proc = match proc_or_current(pid) {
+                    Some(proc) => proc,
+                    None => return Error::ESRCH.into(),
+                };
+                if signal >= NSIG {
+                    return Error::EINVAL.into();
+                }
+                if !may_control(caller, &proc, Capabilities::KILL) {
+                    return Error::EPERM.into();
+                }
+                if <Self as ::proc::ProcessManager>::send_signal(self, proc.id, signal) {
+                    0
+                } else {
+                    Error::ESRCH.into()
+                }
             }
-            ProcRequest::MutexDestroy(mutex) => {
-                let mutex = mutex.cast_mut_ptr::<RawMutex>();
+            ProcRequest::SigAction(signal, handler, restorer) => {
+                if signal == 0 || signal >= NSIG {
+                    return Error::EINVAL.into();
+                }
                 let proc = Process::current().unwrap();
-                let mut locks = proc.locks.lock();
-                if locks.drain_filter(|x| *x == mutex).count() > 0 {
-                    let _boxed = unsafe { Box::from_raw(mutex) };
+                match proc.signals.set_action(signal, handler, restorer) {
+                    Some(old) => old as _,
+                    None => Error::EINVAL.into(),
                 }
-                0
             }
-            ProcRequest::CondvarCreate => {
-                let cvar = Box::leak(box RawCondvar::new()) as *mut RawCondvar;
-                if !privileged {
-                    Process::current().unwrap().cvars.lock().push(cvar);
+            ProcRequest::SigProcMask(how, set) => {
+                let proc = Process::current().unwrap();
+                match proc.signals.set_mask(how, set) {
+                    Some(old) => old as _,
+                    None => Error::EINVAL.into(),
                 }
-                cvar as _
             }
-            ProcRequest::CondvarWait(cvar, mutex) => {
-                let cvar = cvar.cast::<RawCondvar>();
-                let mutex = mutex.cast::<RawMutex>();
-                cvar.wait(mutex);
+            ProcRequest::SetForeground(pid) => {
+                let proc = match proc_or_current(pid) {
+                    Some(proc) => proc,
+                    None => return Error::ESRCH.into(),
+                };
+                // The foreground process receives the signals of the terminal
+                if !may_control(caller, &proc, Capabilities::KILL) {
+                    return Error::EPERM.into();
+                }
+                FOREGROUND.store(proc.id.0, Ordering::SeqCst);
                 0
             }
-            ProcRequest::CondvarNotifyAll(cvar) => {
-                let cvar = cvar.cast::<RawCondvar>();
-                cvar.notify_all();
+            ProcRequest::GetUid => caller.uid as _,
+            ProcRequest::GetGid => caller.gid as _,
+            ProcRequest::SetUid(uid) => {
+                let uid = match u32::try_from(uid) {
+                    Ok(uid) => uid,
+                    Err(_) => return Error::EINVAL.into(),
+                };
+                let proc = Process::current().unwrap();
+                let mut credentials = proc.credentials.lock();
+                *credentials = Credentials::user(uid, credentials.gid);
                 0
             }
-            ProcRequest::CondvarDestroy(cvar) => {
-                let cvar = cvar.cast_mut_ptr::<RawCondvar>();
-                let proc = Process::current().unwrap();
-                let mut cvars = proc.cvars.lock();
-                if cvars.drain_filter(|x| *x == cvar).count() > 0 {
-                    let _boxed = unsafe { Box::from_raw(cvar) };
-                }
+            ProcRequest::SetGid(gid) => {
+                let gid = match u32::try_from(gid) {
+                    Ok(gid) => gid,
+                    Err(_) => return Error::EINVAL.into(),
+                };
+                Process::current().unwrap().credentials.lock().gid = gid;
                 0
             }
         }
@@ -6,22 +6,26 @@ use kernel_module::SERVICE;
 use proc::{Proc, ProcId, Runnable, TaskId};
 use spin::{Lazy, Mutex};
 use sync::Monitor;
+use syscall::Credentials;
 
-use crate::{
-    locks::{RawCondvar, RawMutex},
-    task::Task,
-};
+use crate::{signal::SignalState, task::Task};
 
 static PROCS: Mutex<BTreeMap<ProcId, Arc<Process>>> = Mutex::new(BTreeMap::new());
 
 pub struct Process {
     pub id: ProcId,
+    pub parent: ProcId,
     pub threads: Mutex<Vec<TaskId>>,
     pub live: Lazy<Monitor<bool>>,
     pub fs: Box<dyn Any>,
     pub mm: Box<dyn Any>,
-    pub locks: Mutex<Vec<*mut RawMutex>>,
-    pub cvars: Mutex<Vec<*mut RawCondvar>>,
+    /// Child processes that are not yet waited by this process.
+    /// Exited children are kept here as zombies until the parent collects their exit status.
+    pub children: Lazy<Monitor<Vec<Arc<Process>>>>,
+    pub exit_status: Mutex<Option<isize>>,
+    pub signals: SignalState,
+    /// Inherited from the parent. Processes created by the kernel run as root.
+    pub credentials: Mutex<Credentials>,
 }
 
 unsafe impl Send for Process {}
@@ -35,15 +39,27 @@ impl Process {
         let proc_id = ProcId(COUNTER.fetch_add(1, Ordering::SeqCst));
         // Allocate proc struct
         let vfs_state = SERVICE.vfs().register_process(proc_id, "".to_owned());
+        let parent = Self::current();
         let proc = Arc::new(Self {
             id: proc_id,
+            parent: parent.as_ref().map(|p| p.id).unwrap_or(ProcId::NULL),
             threads: Mutex::new(vec![]),
             mm,
             live: Lazy::new(|| Monitor::new(true)),
             fs: vfs_state,
-            locks: Default::default(),
-            cvars: Default::default(),
+            children: Lazy::new(|| Monitor::new(vec![])),
+            exit_status: Mutex::new(None),
+            signals: SignalState::new(),
+            credentials: Mutex::new(
+                parent
+                    .as_ref()
+                    .map(|p| *p.credentials.lock())
+                    .unwrap_or(Credentials::ROOT),
+            ),
         });
+        if let Some(parent) = parent {
+            parent.children.lock().push(proc.clone());
+        }
         // Create main thread
         let task = Task::create(proc.clone(), t, SERVICE.create_task_context());
         proc.threads.lock().push(task.id);
@@ -65,6 +81,16 @@ impl Process {
         PROCS.lock().get(&id).cloned()
     }
 
+    /// Send a signal, and wake up the threads to interrupt their blocking calls if the signal is deliverable.
+    pub fn send_signal(&self, signal: usize) {
+        let _guard = interrupt::uninterruptible();
+        if self.signals.send(signal) {
+            for t in &*self.threads.lock() {
+                SERVICE.scheduler().wake_up(*t);
+            }
+        }
+    }
+
     #[inline(always)]
     pub fn current() -> Option<Arc<Self>> {
         let _guard = interrupt::uninterruptible();
@@ -84,6 +110,9 @@ impl Proc for Process {
     fn mm(&self) -> &dyn Any {
         self.mm.as_ref()
     }
+    fn credentials(&self) -> Credentials {
+        *self.credentials.lock()
+    }
     fn tasks(&self) -> &Mutex<Vec<TaskId>> {
         &self.threads
     }
@@ -95,7 +124,7 @@ impl Proc for Process {
         debug_assert_eq!(Arc::strong_count(&task), 2);
         task
     }
-    fn exit(&self) {
+    fn exit(&self, status: isize) {
         let _guard = interrupt::uninterruptible();
         // Release file handles
         SERVICE.vfs().deregister_process(self.id);
@@ -108,13 +137,29 @@ impl Proc for Process {
             self.live.notify_all();
         }
         // Remove from scheduler
-        let threads = self.threads.lock();
-        for t in &*threads {
-            crate::task::TASKS.lock().remove(t).unwrap();
-            SERVICE.scheduler().remove_task(*t)
+        let mut threads = self.threads.lock();
+        for t in threads.drain(..) {
+            crate::task::TASKS.lock().remove(&t).unwrap();
+            SERVICE.scheduler().remove_task(t)
         }
+        // Orphaned children are never waited
+        self.children.lock().clear();
         // Remove from procs
         PROCS.lock().remove(&self.id);
+        // Publish the exit status last: The parent may release this process as soon as it sees the status.
+        // Wake up the parent if it is waiting. This process stays as a zombie in the parent's children list.
+        match Self::by_id(self.parent) {
+            Some(parent) => {
+                let _children = parent.children.lock();
+                *self.exit_status.lock() = Some(status);
+                parent.children.notify_all();
+                parent.send_signal(syscall::signal::SIGCHLD);
+            }
+            None => *self.exit_status.lock() = Some(status),
+        }
+    }
+    fn exit_status(&self) -> Option<isize> {
+        *self.exit_status.lock()
     }
     fn wait_for_completion(&self) {
         let mut live = self.live.lock();
@@ -0,0 +1,230 @@
+use alloc::vec::Vec;
+use atomic::Ordering;
+use core::sync::atomic::{AtomicBool, AtomicUsize};
+use kernel_module::SERVICE;
+use proc::{SignalHandler, TaskId};
+use spin::Mutex;
+use syscall::signal::*;
+
+pub enum Delivery {
+    Handler(SignalHandler),
+    Terminate(usize),
+}
+
+/// Per-process signal state
+pub struct SignalState {
+    pending: AtomicUsize,
+    blocked: AtomicUsize,
+    /// `(handler, restorer)` of each signal
+    actions: Mutex<[(usize, usize); NSIG]>,
+    stopped: AtomicBool,
+    stopped_tasks: Mutex<Vec<TaskId>>,
+}
+
+impl SignalState {
+    pub const fn new() -> Self {
+        Self {
+            pending: AtomicUsize::new(0),
+            blocked: AtomicUsize::new(0),
+            actions: Mutex::new([(SIG_DFL, 0); NSIG]),
+            stopped: AtomicBool::new(false),
+            stopped_tasks: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns whether the signal interrupts blocking calls, i.e. whether it is neither blocked nor ignored.
+    pub fn send(&self, sig: usize) -> bool {
+        let _guard = interrupt::uninterruptible();
+        match sig {
+            // Continue, and discard pending stop signals
+            SIGCONT => {
+                self.pending
+                    .fetch_and(!(sigmask(SIGSTOP) | sigmask(SIGTSTP)), Ordering::SeqCst);
+                self.resume();
+            }
+            // Stop signals discard pending SIGCONT
+            SIGSTOP | SIGTSTP => {
+                self.pending.fetch_and(!sigmask(SIGCONT), Ordering::SeqCst);
+            }
+            // Wake up the stopped tasks so they can die
+            SIGKILL => self.resume(),
+            _ => {}
+        }
+        self.pending.fetch_or(sigmask(sig), Ordering::SeqCst);
+        self.blocked.load(Ordering::SeqCst) & sigmask(sig) == 0 && !self.ignored(sig)
+    }
+
+    /// Send a synchronous fault signal that must not be blocked or ignored.
+    ///
+    /// Same as Linux, the default action is restored if the signal is blocked or ignored. Signals are blocked
+    /// while their handler runs, so a handler that faults again terminates the process instead of looping.
+    pub fn force(&self, sig: usize) {
+        let _guard = interrupt::uninterruptible();
+        let blocked = self.blocked.fetch_and(!sigmask(sig), Ordering::SeqCst) & sigmask(sig) != 0;
+        let mut actions = self.actions.lock();
+        if blocked || actions[sig].0 == SIG_IGN {
+            actions[sig] = (SIG_DFL, 0);
+        }
+        self.pending.fetch_or(sigmask(sig), Ordering::SeqCst);
+    }
+
+    /// Whether a pending signal interrupts blocking calls.
+    pub fn interrupted(&self) -> bool {
+        let deliverable =
+            self.pending.load(Ordering::SeqCst) & !self.blocked.load(Ordering::SeqCst);
+        (1..NSIG).any(|sig| deliverable & sigmask(sig) != 0 && !self.ignored(sig))
+    }
+
+    /// Whether the signal is discarded on delivery.
+    fn ignored(&self, sig: usize) -> bool {
+        let handler = {
+            let _guard = interrupt::uninterruptible();
+            self.actions.lock()[sig].0
+        };
+        match handler {
+            SIG_IGN => true,
+            SIG_DFL => matches!(
+                default_action(sig),
+                DefaultAction::Ignore | DefaultAction::Continue
+            ),
+            _ => false,
+        }
+    }
+
+    /// Returns the previous handler, or `None` if the signal cannot be caught.
+    pub fn set_action(&self, sig: usize, handler: usize, restorer: usize) -> Option<usize> {
+        if sigmask(sig) & UNCATCHABLE != 0 {
+            return None;
+        }
+        let _guard = interrupt::uninterruptible();
+        let mut actions = self.actions.lock();
+        let old = actions[sig].0;
+        actions[sig] = (handler, restorer);
+        Some(old)
+    }
+
+    /// Returns the previous mask, or `None` if `how` is invalid.
+    pub fn set_mask(&self, how: usize, set: usize) -> Option<usize> {
+        let set = set & !UNCATCHABLE;
+        let update = |mask: usize| match how {
+            SIG_BLOCK => Some(mask | set),
+            SIG_UNBLOCK => Some(mask & !set),
+            SIG_SETMASK => Some(set),
+            _ => None,
+        };
+        self.blocked
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, update)
+            .ok()
+    }
+
+    /// Take the next deliverable signal that either has a user handler or terminates the process.
+    /// Ignored signals are dropped. A stop signal blocks the current task until the process is continued.
+    ///
+    /// A signal with a user handler is blocked until the handler returns, and the previous mask is returned
+    /// with the handler.
+    pub fn dequeue(&self) -> Option<Delivery> {
+        loop {
+            let blocked = self.blocked.load(Ordering::SeqCst);
+            let deliverable = self.pending.load(Ordering::SeqCst) & !blocked;
+            if deliverable == 0 {
+                return None;
+            }
+            let sig = deliverable.trailing_zeros() as usize;
+            if self.pending.fetch_and(!sigmask(sig), Ordering::SeqCst) & sigmask(sig) == 0 {
+                // Taken by another thread
+                continue;
+            }
+            let (handler, restorer) = {
+                let _guard = interrupt::uninterruptible();
+                self.actions.lock()[sig]
+            };
+            match handler {
+                SIG_IGN => continue,
+                SIG_DFL => match default_action(sig) {
+                    DefaultAction::Ignore | DefaultAction::Continue => continue,
+                    DefaultAction::Stop => self.stop(),
+                    DefaultAction::Terminate => return Some(Delivery::Terminate(sig)),
+                },
+                _ => {
+                    let mask = self.blocked.fetch_or(sigmask(sig), Ordering::SeqCst);
+                    return Some(Delivery::Handler(SignalHandler {
+                        signal: sig,
+                        handler,
+                        restorer,
+                        mask,
+                    }));
+                }
+            }
+        }
+    }
+
+    /// Block the current task until the process gets `SIGCONT` or `SIGKILL`.
+    fn stop(&self) {
+        let _guard = interrupt::uninterruptible();
+        let task = SERVICE.scheduler().get_current_task_id().unwrap();
+        self.stopped.store(true, Ordering::SeqCst);
+        loop {
+            {
+                let mut stopped_tasks = self.stopped_tasks.lock();
+                if !self.stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                stopped_tasks.push(task);
+                // `resume` may run on another core as soon as the lock is released
+                SERVICE.scheduler().prepare_to_sleep();
+            }
+            syscall::wait();
+        }
+    }
+
+    fn resume(&self) {
+        let mut stopped_tasks = self.stopped_tasks.lock();
+        self.stopped.store(false, Ordering::SeqCst);
+        for t in &*stopped_tasks {
+            SERVICE.scheduler().wake_up(*t)
+        }
+        stopped_tasks.clear()
+    }
+}
+
+#[test]
+fn dequeue_signals() {
+    let signals = SignalState::new();
+    assert_eq!(signals.set_action(SIGUSR1, 0x1000, 0x2000), Some(SIG_DFL));
+    assert_eq!(signals.set_action(SIGUSR2, SIG_IGN, 0), Some(SIG_DFL));
+    assert_eq!(signals.set_action(SIGKILL, 0x1000, 0x2000), None);
+    signals.set_mask(SIG_BLOCK, sigmask(SIGUSR1) | sigmask(SIGKILL));
+    signals.send(SIGUSR1);
+    signals.send(SIGUSR2);
+    signals.send(SIGCHLD);
+    // SIGUSR1 is blocked, others are ignored
+    assert!(signals.dequeue().is_none());
+    signals.set_mask(SIG_UNBLOCK, sigmask(SIGUSR1));
+    match signals.dequeue() {
+        Some(Delivery::Handler(h)) => {
+            assert_eq!((h.signal, h.handler, h.restorer), (SIGUSR1, 0x1000, 0x2000));
+            assert_eq!(h.mask, 0);
+        }
+        _ => panic!(),
+    }
+    assert!(signals.dequeue().is_none());
+    // SIGUSR1 is blocked while its handler runs
+    assert!(!signals.send(SIGUSR1));
+    assert!(!signals.interrupted());
+    assert!(signals.dequeue().is_none());
+    signals.set_mask(SIG_SETMASK, 0);
+    assert!(signals.interrupted());
+    assert!(matches!(signals.dequeue(), Some(Delivery::Handler(_))));
+    // A fault in the handler restores the default action
+    signals.force(SIGUSR1);
+    assert!(matches!(
+        signals.dequeue(),
+        Some(Delivery::Terminate(SIGUSR1))
+    ));
+    // SIGKILL cannot be blocked
+    signals.send(SIGKILL);
+    assert!(matches!(
+        signals.dequeue(),
+        Some(Delivery::Terminate(SIGKILL))
+    ));
+}
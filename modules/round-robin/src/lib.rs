@@ -5,17 +5,18 @@
 #![feature(downcast_unchecked)]
 #![no_std]
 
-#[allow(unused)]
 #[macro_use]
-extern crate log;
+extern crate kernel_module;
 extern crate alloc;
 
+mod queues;
+
 use alloc::{boxed::Box, vec::Vec};
 use atomic::{Atomic, Ordering};
 use core::{any::Any, sync::atomic::AtomicUsize};
-use crossbeam::queue::SegQueue;
-use kernel_module::{kernel_module, KernelModule, ProcessorLocalStorage, SERVICE};
+use kernel_module::{kernel_module, KernelModule, SERVICE};
 use proc::TaskId;
+use queues::RunQueues;
 use sched::{RunState, Scheduler};
 use spin::Lazy;
 
@@ -25,6 +26,8 @@ const UNIT_TIME_SLICE: usize = 1;
 pub struct State {
     run_state: Atomic<RunState>,
     time_slice_units: AtomicUsize,
+    /// The core this task last ran on, or was assigned to.
+    last_core: AtomicUsize,
 }
 
 impl State {
@@ -32,23 +35,24 @@ impl State {
         Self {
             run_state: Atomic::new(RunState::Ready),
             time_slice_units: AtomicUsize::new(0),
+            last_core: AtomicUsize::new(0),
         }
     }
 }
 
-#[kernel_module]
+#[kernel_module(provides("scheduler"))]
 pub static mut SCHEDULER: RoundRobinScheduler = RoundRobinScheduler::new();
 
 pub struct RoundRobinScheduler {
     current_task: Vec<Atomic<Option<TaskId>>>,
-    per_core_task_queue: Lazy<ProcessorLocalStorage<SegQueue<TaskId>>>,
+    run_queues: Lazy<RunQueues>,
 }
 
 impl RoundRobinScheduler {
     const fn new() -> Self {
         Self {
             current_task: Vec::new(),
-            per_core_task_queue: Lazy::new(|| ProcessorLocalStorage::new()),
+            run_queues: Lazy::new(|| RunQueues::new(SERVICE.num_cores())),
         }
     }
 
@@ -60,7 +64,11 @@ impl RoundRobinScheduler {
     #[inline]
     fn get_next_schedulable_task(&self) -> TaskId {
         debug_assert!(!interrupt::is_enabled());
-        if let Some(next_runnable_task) = self.per_core_task_queue.pop() {
+        let core = SERVICE.current_core();
+        if let Some((next_runnable_task, _)) = self.run_queues.pop(core) {
+            self.get_state(next_runnable_task)
+                .last_core
+                .store(core, Ordering::SeqCst);
             return next_runnable_task;
         } else {
             // We should at least have an `idle` task that is runnable
@@ -83,7 +91,7 @@ impl RoundRobinScheduler {
         let state = self.get_state(task);
         debug_assert_ne!(state.run_state.load(Ordering::SeqCst), RunState::Ready);
         state.run_state.store(RunState::Ready, Ordering::SeqCst);
-        self.per_core_task_queue.get(0).push(task);
+        self.run_queues.push(SERVICE.current_core(), task);
     }
 }
 
@@ -101,7 +109,9 @@ impl Scheduler for RoundRobinScheduler {
         let state = self.get_state(task);
         if state.run_state.load(Ordering::SeqCst) == RunState::Ready {
             debug_assert!(!interrupt::is_enabled());
-            self.per_core_task_queue.get(0).push(task);
+            let core = self.run_queues.least_loaded();
+            state.last_core.store(core, Ordering::SeqCst);
+            self.run_queues.push(core, task);
         }
     }
 
@@ -121,12 +131,25 @@ impl Scheduler for RoundRobinScheduler {
     }
 
     fn sleep(&self) {
+        let _guard = interrupt::uninterruptible();
+        let task = self.get_current_task_id().unwrap();
+        let state = self.get_state(task);
+        // Already `Sleeping` after `prepare_to_sleep`, or `Ready` if woken up since then
+        let _ = state.run_state.compare_exchange(
+            RunState::Running,
+            RunState::Sleeping,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+        self.schedule();
+    }
+
+    fn prepare_to_sleep(&self) {
         let _guard = interrupt::uninterruptible();
         let task = self.get_current_task_id().unwrap();
         let state = self.get_state(task);
         assert_eq!(state.run_state.load(Ordering::SeqCst), RunState::Running);
         state.run_state.store(RunState::Sleeping, Ordering::SeqCst);
-        self.schedule();
     }
 
     fn wake_up(&self, task: TaskId) {
@@ -142,7 +165,9 @@ impl Scheduler for RoundRobinScheduler {
                 }
             });
         if old == Ok(RunState::Sleeping) {
-            self.per_core_task_queue.get(0).push(task);
+            // Keep the task on its last core for cache affinity
+            self.run_queues
+                .push(state.last_core.load(Ordering::SeqCst), task);
         }
     }
 
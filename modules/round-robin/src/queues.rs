@@ -0,0 +1,78 @@
+use alloc::vec::Vec;
+use crossbeam::queue::SegQueue;
+use proc::TaskId;
+
+/// Per-core run queues with work stealing.
+///
+/// Queue `i` belongs to the core `i`. A core with an empty queue steals a task from the busiest queue.
+pub struct RunQueues {
+    queues: Vec<SegQueue<TaskId>>,
+}
+
+impl RunQueues {
+    pub fn new(num_queues: usize) -> Self {
+        Self {
+            queues: (0..num_queues).map(|_| SegQueue::new()).collect(),
+        }
+    }
+
+    /// Number of queues.
+    pub fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+
+    pub fn push(&self, queue: usize, task: TaskId) {
+        self.queues[queue].push(task)
+    }
+
+    /// Pop a task from `queue`, or steal one from the busiest queue if it is empty.
+    ///
+    /// Returns the task and the queue it was taken from.
+    pub fn pop(&self, queue: usize) -> Option<(TaskId, usize)> {
+        if let Some(task) = self.queues[queue].pop() {
+            return Some((task, queue));
+        }
+        loop {
+            let victim = (0..self.num_queues())
+                .filter(|i| *i != queue)
+                .max_by_key(|i| self.queues[*i].len())?;
+            if self.queues[victim].is_empty() {
+                return None;
+            }
+            // The victim may get drained by other cores in the meantime. Retry if so.
+            if let Some(task) = self.queues[victim].pop() {
+                return Some((task, victim));
+            }
+        }
+    }
+
+    /// The queue with the fewest tasks.
+    pub fn least_loaded(&self) -> usize {
+        (0..self.num_queues())
+            .min_by_key(|i| self.queues[*i].len())
+            .unwrap_or(0)
+    }
+}
+
+#[test]
+fn run_queues_work_stealing() {
+    let queues = RunQueues::new(4);
+    for i in 0..6 {
+        queues.push(0, TaskId(i));
+    }
+    queues.push(1, TaskId(6));
+    // Local tasks are taken in FIFO order
+    assert_eq!(queues.pop(0), Some((TaskId(0), 0)));
+    assert_eq!(queues.pop(1), Some((TaskId(6), 1)));
+    // Idle queues steal from the busiest one
+    assert_eq!(queues.pop(2), Some((TaskId(1), 0)));
+    assert_eq!(queues.pop(3), Some((TaskId(2), 0)));
+    assert_eq!(queues.least_loaded(), 1);
+    queues.push(1, TaskId(7));
+    assert_eq!(queues.pop(3), Some((TaskId(3), 0)));
+    assert_eq!(queues.pop(3), Some((TaskId(4), 0)));
+    // Now queue #0 and #1 are equally loaded
+    assert_eq!(queues.pop(1), Some((TaskId(7), 1)));
+    assert_eq!(queues.pop(1), Some((TaskId(5), 0)));
+    assert_eq!(queues.pop(2), None);
+}
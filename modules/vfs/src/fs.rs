@@ -1,15 +1,33 @@
 use alloc::{
     borrow::{Cow, ToOwned},
     format,
+    sync::Arc,
 };
+use core::sync::atomic::AtomicUsize;
 use vfs::Node;
 
 use crate::rootfs::ROOT_FS;
 
-/// Per-process file descriptor
+/// An open file. Shared by the file descriptors duplicated from it, e.g. by a spawned or forked child.
+/// The node is closed when the last file descriptor referencing it is closed.
 pub struct FileDescriptor {
     pub node: Node,
-    pub offset: usize,
+    pub offset: AtomicUsize,
+}
+
+impl FileDescriptor {
+    pub fn new(node: Node) -> Arc<Self> {
+        Arc::new(Self {
+            node,
+            offset: AtomicUsize::new(0),
+        })
+    }
+}
+
+impl Drop for FileDescriptor {
+    fn drop(&mut self) {
+        self.node.fs.close(&self.node);
+    }
 }
 
 pub fn vfs_locate_node<'a>(parent: &Node, path: &'a str) -> Option<(Node, &'a str)> {
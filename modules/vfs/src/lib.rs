@@ -14,18 +14,21 @@ mod mount;
 mod rootfs;
 
 use core::any::Any;
+use core::sync::atomic::Ordering;
 
 use crate::fs::FileDescriptor;
 use alloc::{
-    borrow::ToOwned, boxed::Box, collections::BTreeMap, format, string::String, vec, vec::Vec,
+    borrow::ToOwned, boxed::Box, collections::BTreeMap, format, string::String, sync::Arc, vec,
+    vec::Vec,
 };
 use kernel_module::{kernel_module, KernelModule, SERVICE};
 use proc::{Proc, ProcId};
 use rootfs::ROOT_FS;
 use spin::{Mutex, RwLock};
-use vfs::{ramfs::RamFS, FileSystem, VFSManager, VFSRequest};
+use syscall::{mman::MAP_SHARED, CallContext, Capabilities, Error};
+use vfs::{ramfs::RamFS, Fd, FileSystem, Node, VFSManager, VFSRequest};
 
-#[kernel_module]
+#[kernel_module(name = "vfs", provides("vfs"))]
 pub static VFS: VFS = VFS {};
 
 pub struct VFS {}
@@ -54,18 +57,69 @@ impl VFSManager for VFS {
 
     fn deregister_process(&self, _proc: ProcId) {}
 
+    fn capture_fds(&self, fds: Option<&[(Fd, Fd)]>) -> Option<Box<dyn Any>> {
+        let proc_data = self.get_current_state()?.lock();
+        let nodes = match fds {
+            None => proc_data.nodes.clone(),
+            Some(fds) => {
+                let mut nodes = ProcData::EMPTY_NODES;
+                for (child, parent) in fds {
+                    let fdesc = proc_data.nodes.get(parent.0 as usize)?.as_ref()?;
+                    *nodes.get_mut(child.0 as usize)? = Some(fdesc.clone());
+                }
+                nodes
+            }
+        };
+        Some(box nodes)
+    }
+
+    fn install_fds(&self, fds: Box<dyn Any>) {
+        let nodes = fds
+            .downcast::<[Option<Arc<FileDescriptor>>; MAX_FILES]>()
+            .unwrap();
+        let replaced =
+            core::mem::replace(&mut self.get_current_state().unwrap().lock().nodes, *nodes);
+        // Close the replaced descriptors, e.g. the default stdio, outside the lock
+        drop(replaced);
+    }
+
     fn register_fs(&self, fs: &'static dyn FileSystem) {
         crate::FILE_SYSTEMS.write().insert(fs.name().to_owned(), fs);
     }
+
+    fn unregister_fs(&self, name: &str) -> bool {
+        let mounted = crate::mount::MOUNT_POINTS
+            .read()
+            .iter()
+            .flatten()
+            .any(|m| m.fs.name() == name);
+        if mounted {
+            return false;
+        }
+        crate::FILE_SYSTEMS.write().remove(name);
+        true
+    }
+
+    fn file_node(&self, fd: Fd) -> Option<Node> {
+        let proc_data = self.get_current_state()?.lock();
+        let fdesc = proc_data.nodes.get(fd.0 as usize)?.as_ref()?;
+        Some(fdesc.node.clone())
+    }
 }
 
+const MAX_FILES: usize = 16;
+
 struct ProcData {
-    nodes: [Option<FileDescriptor>; 16],
+    nodes: [Option<Arc<FileDescriptor>>; MAX_FILES],
     cwd: String,
-    files: usize,
 }
 
 impl ProcData {
+    const EMPTY_NODES: [Option<Arc<FileDescriptor>>; MAX_FILES] = {
+        const NONE: Option<Arc<FileDescriptor>> = None;
+        [NONE; MAX_FILES]
+    };
+
     fn new(cwd: String) -> Self {
         let cwd = if cwd == "" {
             VFS.get_current_state()
@@ -75,29 +129,20 @@ impl ProcData {
             cwd
         };
         let mut data = Self {
-            nodes: [
-                None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-                None, None,
-            ],
+            nodes: Self::EMPTY_NODES,
             cwd,
-            files: 3,
         };
-        let stdio = fs::vfs_open("/dev/tty.serial").unwrap();
-        data.nodes[0] = Some(FileDescriptor {
-            node: stdio.clone(),
-            offset: 0,
-        });
-        data.nodes[1] = Some(FileDescriptor {
-            node: stdio.clone(),
-            offset: 0,
-        });
-        data.nodes[2] = Some(FileDescriptor {
-            node: stdio.clone(),
-            offset: 0,
-        });
+        let stdio = FileDescriptor::new(fs::vfs_open("/dev/tty.serial").unwrap());
+        data.nodes[0] = Some(stdio.clone());
+        data.nodes[1] = Some(stdio.clone());
+        data.nodes[2] = Some(stdio);
         data
     }
 
+    fn fd(&self, fd: Fd) -> Option<Arc<FileDescriptor>> {
+        self.nodes.get(fd.0 as usize)?.clone()
+    }
+
     fn set_cwd(&mut self, cwd: &str) -> Result<(), ()> {
         let cwd = self.canonicalize(cwd.to_owned())?;
         if !fs::dir_or_mnt_exists(&cwd) {
@@ -147,18 +192,18 @@ impl KernelModule for VFS {
         Ok(())
     }
 
-    fn handle_module_call<'a>(&self, privileged: bool, request: Self::ModuleRequest<'a>) -> isize {
+    fn handle_module_call<'a>(&self, ctx: &CallContext, request: Self::ModuleRequest<'a>) -> isize {
         debug_assert!(!interrupt::is_enabled());
         match request {
             VFSRequest::Open(path) => {
                 let mut proc_data = self.get_current_state().unwrap().lock();
                 let path = match proc_data.canonicalize(path.to_owned()) {
                     Ok(path) => path,
-                    Err(_) => return -1,
+                    Err(_) => return Error::ENOENT.into(),
                 };
                 let node = match fs::vfs_open(&path) {
                     Some(node) => node,
-                    None => return -1,
+                    None => return Error::ENOENT.into(),
                 };
                 let node = if let Some(mnt) = node.mount {
                     let mnt_table = mount::MOUNT_POINTS.read();
@@ -167,77 +212,64 @@ impl KernelModule for VFS {
                 } else {
                     node
                 };
-                let fd = proc_data.files;
-                proc_data.nodes[fd] = Some(FileDescriptor { node, offset: 0 });
-                proc_data.files += 1;
+                let fd = match proc_data.nodes.iter().position(|n| n.is_none()) {
+                    Some(fd) => fd,
+                    None => return Error::EMFILE.into(),
+                };
+                proc_data.nodes[fd] = Some(FileDescriptor::new(node));
                 fd as _
             }
             VFSRequest::Close(fd) => {
                 if fd.0 < 3 {
-                    return -1;
+                    return Error::EBADF.into();
                 }
                 let mut proc_data = self.get_current_state().unwrap().lock();
-                let node = match proc_data.nodes[fd.0 as usize].take() {
-                    Some(fd) => {
-                        proc_data.files -= 1;
-                        fd.node
-                    }
-                    None => return -1,
+                let fdesc = match proc_data
+                    .nodes
+                    .get_mut(fd.0 as usize)
+                    .and_then(|n| n.take())
+                {
+                    Some(fdesc) => fdesc,
+                    None => return Error::EBADF.into(),
                 };
-                node.fs.close(&node);
+                drop(proc_data);
+                // The node is closed if no other process shares the descriptor
+                drop(fdesc);
                 0
             }
             VFSRequest::Read(fd, buf) => {
-                let mut proc_data = self.get_current_state().unwrap().lock();
-                let fdesc = match proc_data.nodes[fd.0 as usize].as_mut() {
+                let fdesc = match self.get_current_state().unwrap().lock().fd(fd) {
                     Some(fd) => fd,
-                    None => return -1,
+                    None => return Error::EBADF.into(),
                 };
-                let fs = fdesc.node.fs;
-                let node = fdesc.node.clone();
-                let offset = fdesc.offset;
-                drop(proc_data);
-                match fs.read(&node, offset, buf) {
-                    None => -1,
+                let offset = fdesc.offset.load(Ordering::SeqCst);
+                // The buffer of a user process is a kernel copy, so blocking reads are safe
+                match fdesc.node.fs.read(&fdesc.node, offset, buf) {
+                    None => read_error(),
                     Some(v) => {
-                        let mut proc_data = self.get_current_state().unwrap().lock();
-                        let fdesc = match proc_data.nodes[fd.0 as usize].as_mut() {
-                            Some(fd) => fd,
-                            None => return -1,
-                        };
-                        fdesc.offset += v;
+                        fdesc.offset.fetch_add(v, Ordering::SeqCst);
                         v as _
                     }
                 }
             }
             VFSRequest::Write(fd, buf) => {
-                let mut proc_data = self.get_current_state().unwrap().lock();
-                let fdesc = match proc_data.nodes[fd.0 as usize].as_mut() {
+                let fdesc = match self.get_current_state().unwrap().lock().fd(fd) {
                     Some(fd) => fd,
-                    None => return -1,
+                    None => return Error::EBADF.into(),
                 };
-                let fs = fdesc.node.fs;
-                let node = fdesc.node.clone();
-                let offset = fdesc.offset;
-                drop(proc_data);
-                match fs.write(&node, offset, buf) {
-                    None => -1,
+                let offset = fdesc.offset.load(Ordering::SeqCst);
+                match fdesc.node.fs.write(&fdesc.node, offset, buf) {
+                    None => Error::EIO.into(),
                     Some(v) => {
-                        let mut proc_data = self.get_current_state().unwrap().lock();
-                        let fdesc = match proc_data.nodes[fd.0 as usize].as_mut() {
-                            Some(fd) => fd,
-                            None => return -1,
-                        };
-                        fdesc.offset += v;
+                        fdesc.offset.fetch_add(v, Ordering::SeqCst);
                         v as _
                     }
                 }
             }
             VFSRequest::ReadDir(fd, i, buf) => {
-                let mut proc_data = self.get_current_state().unwrap().lock();
-                let fdesc = match proc_data.nodes[fd.0 as usize].as_mut() {
+                let fdesc = match self.get_current_state().unwrap().lock().fd(fd) {
                     Some(fd) => fd,
-                    None => return -1,
+                    None => return Error::EBADF.into(),
                 };
                 if let Some(entries) = fdesc.node.fs.read_dir(&fdesc.node) {
                     if i >= entries.len() {
@@ -249,20 +281,24 @@ impl KernelModule for VFS {
                         1
                     }
                 } else {
-                    -1
+                    Error::ENOTDIR.into()
                 }
             }
             VFSRequest::Mount { path, dev, fs } => {
-                assert!(privileged);
-                let fs = FILE_SYSTEMS.read()[fs];
-                mount::vfs_mount(&path, dev, unsafe { &*(fs as *const dyn FileSystem) }).unwrap();
-                0
+                let fs = match FILE_SYSTEMS.read().get(fs) {
+                    Some(fs) => *fs,
+                    None => return Error::ENODEV.into(),
+                };
+                match mount::vfs_mount(&path, dev, fs) {
+                    Ok(_) => 0,
+                    Err(e) => e.into(),
+                }
             }
             VFSRequest::GetCwd(buf) => {
                 let proc_data = self.get_current_state().unwrap().lock();
                 let cwd = proc_data.cwd.as_str();
                 if cwd.len() > buf.len() {
-                    return -1;
+                    return Error::ERANGE.into();
                 }
                 unsafe { core::ptr::copy_nonoverlapping(cwd.as_ptr(), buf.as_mut_ptr(), cwd.len()) }
                 cwd.len() as _
@@ -271,7 +307,26 @@ impl KernelModule for VFS {
                 let mut proc_data = self.get_current_state().unwrap().lock();
                 match proc_data.set_cwd(path) {
                     Ok(_) => 0,
-                    Err(_) => -1,
+                    Err(_) => Error::ENOENT.into(),
+                }
+            }
+            VFSRequest::Mmap {
+                fd,
+                offset,
+                size,
+                prot,
+                flags,
+            } => {
+                if flags == MAP_SHARED && !ctx.credentials.can(Capabilities::SYS_ADMIN) {
+                    return Error::EPERM.into();
+                }
+                let node = match self.file_node(fd) {
+                    Some(node) => node,
+                    None => return Error::EBADF.into(),
+                };
+                match SERVICE.map_file(node, offset, size, prot, flags) {
+                    Ok(address) => address as _,
+                    Err(e) => e.into(),
                 }
             }
         }
@@ -281,6 +336,15 @@ impl KernelModule for VFS {
 static FILE_SYSTEMS: RwLock<BTreeMap<String, &'static dyn FileSystem>> =
     RwLock::new(BTreeMap::new());
 
+/// Error of a failed read. Blocking device reads fail when they are interrupted by a signal.
+fn read_error() -> isize {
+    if SERVICE.process_manager().signal_pending() {
+        Error::EINTR.into()
+    } else {
+        Error::EIO.into()
+    }
+}
+
 #[test]
 fn read_text_file() {
     let file = vfs::open("/etc/hello.txt").unwrap();
@@ -288,5 +352,30 @@ fn read_text_file() {
     let len = vfs::read(file, &mut buf).unwrap();
     let s = core::str::from_utf8(&buf[0..len]);
     assert_eq!(s, Ok("Hello world from file!"));
-    vfs::close(file);
+    vfs::close(file).unwrap();
+}
+
+#[test]
+fn inherited_fds_share_offsets() {
+    let file = vfs::open("/etc/hello.txt").unwrap();
+    let fds = VFS.capture_fds(None).unwrap();
+    let mut buf = [0u8; 32];
+    assert_eq!(vfs::read(file, &mut buf[..6]), Ok(6));
+    // The installed table shares the open file, and its offset
+    VFS.install_fds(fds);
+    let len = vfs::read(file, &mut buf).unwrap();
+    assert_eq!(core::str::from_utf8(&buf[0..len]), Ok("world from file!"));
+    vfs::close(file).unwrap();
+}
+
+#[test]
+fn private_device_mapping() {
+    use syscall::mman::{MAP_PRIVATE, PROT_READ};
+    // Device reads may block, so devices are not copied to private pages
+    let file = vfs::open("/dev/tty.serial").unwrap();
+    assert_eq!(
+        vfs::mmap(file, 0, 4096, PROT_READ, MAP_PRIVATE),
+        Err(Error::ENODEV)
+    );
+    vfs::close(file).unwrap();
 }
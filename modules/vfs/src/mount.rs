@@ -4,6 +4,7 @@ use alloc::{
     format,
 };
 use spin::RwLock;
+use syscall::Error;
 use vfs::{FileSystem, Node};
 
 // static MOUNT_POINTS: BTreeMap<>
@@ -19,33 +20,33 @@ pub static MOUNT_POINTS: RwLock<[Option<MountPoint>; 32]> = RwLock::new([
     None, None, None, None, None, None, None, None, None, None, None, None, None, None, None, None,
 ]);
 
-pub fn vfs_mount(path: &str, dev: usize, fs: &'static dyn FileSystem) -> Option<Node> {
-    assert!(path.starts_with("/"));
+/// Mount `fs` at the absolute `path`. The parent directories must exist.
+pub fn vfs_mount(path: &str, dev: usize, fs: &'static dyn FileSystem) -> Result<Node, Error> {
+    if !path.starts_with("/") {
+        return Err(Error::EINVAL);
+    }
     if path == "/" {
-        return None;
+        return Err(Error::EBUSY);
     }
     let mut mount_points = MOUNT_POINTS.write();
-    let mut i = 0;
-    while i < mount_points.len() {
-        if mount_points[i].is_none() {
-            let (parent, root) = vfs_mount_impl(
-                &ROOT_FS.root_node(),
-                path.split_once("/").unwrap().1,
-                dev,
-                fs,
-                i,
-            )?;
-            mount_points[i] = Some(MountPoint {
-                parent,
-                root: root.clone(),
-                dev,
-                fs,
-            });
-            return Some(root);
-        }
-        i += 1;
-    }
-    None
+    let i = match mount_points.iter().position(|m| m.is_none()) {
+        Some(i) => i,
+        None => return Err(Error::ENOMEM),
+    };
+    let (parent, root) = vfs_mount_impl(
+        &ROOT_FS.root_node(),
+        path.split_once("/").unwrap().1,
+        dev,
+        fs,
+        i,
+    )?;
+    mount_points[i] = Some(MountPoint {
+        parent,
+        root: root.clone(),
+        dev,
+        fs,
+    });
+    Ok(root)
 }
 
 fn vfs_mount_impl(
@@ -54,44 +55,34 @@ fn vfs_mount_impl(
     dev: usize,
     fs: &'static dyn FileSystem,
     key: usize,
-) -> Option<(Node, Node)> {
+) -> Result<(Node, Node), Error> {
     assert!(!path.starts_with("/"));
     let (entry, remaining_path) = path.split_once("/").unwrap_or_else(|| (path, ""));
-    if let Some(stat) = parent.fs.stat(parent, entry) {
-        if stat.is_dir {
-            if remaining_path == "" {
-                println!("{} is a directory", path);
-                None
-            } else {
-                vfs_mount_impl(
-                    &Node {
-                        name: Cow::Owned(entry.to_owned()),
-                        path: Cow::Owned(format!("{}/{}", parent.path, entry)),
-                        fs: parent.fs,
-                        mount: parent.mount,
-                        block: parent.block,
-                        offset: parent.offset,
-                    },
-                    remaining_path,
-                    dev,
-                    fs,
-                    key,
-                )
-            }
-        } else {
-            let parent = parent.fs.mount(parent, entry, key)?;
+    match parent.fs.stat(parent, entry) {
+        Some(stat) if stat.is_dir && remaining_path != "" => vfs_mount_impl(
+            &Node {
+                name: Cow::Owned(entry.to_owned()),
+                path: Cow::Owned(format!("{}/{}", parent.path, entry)),
+                fs: parent.fs,
+                mount: parent.mount,
+                block: parent.block,
+                offset: parent.offset,
+            },
+            remaining_path,
+            dev,
+            fs,
+            key,
+        ),
+        // Directories and other mount points are not replaced
+        Some(stat) if stat.is_dir || stat.mount.is_some() => Err(Error::EEXIST),
+        Some(_) if remaining_path != "" => Err(Error::ENOTDIR),
+        None if remaining_path != "" => Err(Error::ENOENT),
+        _ => {
+            let parent = parent.fs.mount(parent, entry, key).ok_or(Error::EEXIST)?;
             let mut root = parent.clone();
             root.mount = None;
             root.fs = fs;
-            Some((parent, root))
+            Ok((parent, root))
         }
-    } else if remaining_path == "" {
-        let parent = parent.fs.mount(parent, entry, key)?;
-        let mut root = parent.clone();
-        root.mount = None;
-        root.fs = fs;
-        Some((parent, root))
-    } else {
-        None
     }
 }
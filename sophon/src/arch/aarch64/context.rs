@@ -3,18 +3,25 @@ use crate::arch::*;
 use crate::memory::kernel::KERNEL_HEAP;
 use crate::memory::kernel::{KERNEL_STACK_PAGES, KERNEL_STACK_SIZE};
 use crate::task::proc::MMState;
+use alloc::boxed::Box;
 use alloc::vec;
 use alloc::vec::Vec;
 use atomic::{Atomic, Ordering};
+use core::any::Any;
 use core::arch::asm;
+use core::hint::spin_loop;
+use core::mem::size_of;
 use core::ops::Range;
 use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize};
 use cortex_a::registers::*;
 use memory::address::{Address, V};
 use memory::page::PageResource;
 use memory::page::*;
+use proc::SignalHandler;
 use spin::Mutex;
-use tock_registers::interfaces::Readable;
+use syscall::signal::NSIG;
+use tock_registers::interfaces::{Readable, Writeable};
 
 #[repr(C, align(4096))]
 pub struct KernelStack {
@@ -66,6 +73,12 @@ impl Drop for KernelStack {
     }
 }
 
+/// The context whose kernel stack each core is currently running on.
+static ACTIVE_CONTEXT: [AtomicPtr<AArch64Context>; super::smp::MAX_CORES] = {
+    const NONE: AtomicPtr<AArch64Context> = AtomicPtr::new(ptr::null_mut());
+    [NONE; super::smp::MAX_CORES]
+};
+
 /// Represents the archtectural context (i.e. registers)
 #[allow(improper_ctypes)]
 #[repr(C)]
@@ -90,17 +103,48 @@ pub struct AArch64Context {
     kernel_stack: Option<*mut KernelStack>,
     kernel_stack_top: *mut u8,
     response_status: Atomic<Option<isize>>,
+    /// Set while a core is still running on this context's kernel stack.
+    on_cpu: AtomicBool,
+    /// User frames interrupted by signal handlers.
+    /// Kept in the kernel so that the handler cannot forge privileged registers.
+    signal_frames: Mutex<Vec<SignalFrame>>,
+    /// User thread pointer (`TPIDR_EL0`)
+    thread_pointer: AtomicUsize,
 }
 
 impl AArch64Context {
     pub fn push_exception_frame(&self, exception_frame: *mut ExceptionFrame) {
         let _guard = interrupt::uninterruptible();
+        // The user program may have changed its thread pointer. Save it in case we switch to another task.
+        self.thread_pointer
+            .store(TPIDR_EL0.get() as _, Ordering::Relaxed);
         self.exception_frames.lock().push(exception_frame)
     }
     fn pop_exception_frame(&self) -> Option<*mut ExceptionFrame> {
         let _guard = interrupt::uninterruptible();
         self.exception_frames.lock().pop()
     }
+    /// Redirect the user frame to a signal handler, with the signal number as the argument.
+    /// Returns `false` if there are already `MAX_SIGNAL_FRAMES` handlers running.
+    pub fn enter_signal_handler(
+        &self,
+        frame: &mut ExceptionFrame,
+        handler: &SignalHandler,
+    ) -> bool {
+        let mut signal_frames = self.signal_frames.lock();
+        if signal_frames.len() >= MAX_SIGNAL_FRAMES {
+            return false;
+        }
+        signal_frames.push(SignalFrame {
+            frame: frame.clone(),
+            mask: handler.mask,
+        });
+        frame.x0 = handler.signal;
+        frame.x30 = handler.restorer;
+        frame.x31 &= !0xf;
+        frame.elr_el1 = handler.handler as _;
+        true
+    }
 }
 
 impl ArchContext for AArch64Context {
@@ -111,6 +155,9 @@ impl ArchContext for AArch64Context {
             kernel_stack: None,
             kernel_stack_top: ptr::null_mut(),
             response_status: Atomic::new(None),
+            on_cpu: AtomicBool::new(false),
+            signal_frames: Mutex::new(vec![]),
+            thread_pointer: AtomicUsize::new(0),
         }
     }
 
@@ -134,6 +181,24 @@ impl ArchContext for AArch64Context {
 
     unsafe extern "C" fn return_to_user(&self) -> ! {
         assert!(!interrupt::is_enabled());
+        crate::modules::irq_handler_finished();
+        // A task woken up by another core may still have its previous core running on
+        // its kernel stack. Wait until that core switches away.
+        let this = self as *const Self as *mut Self;
+        let prev = ACTIVE_CONTEXT[TargetArch::current_core()].swap(this, Ordering::SeqCst);
+        let prev_on_cpu = if prev != this {
+            while self
+                .on_cpu
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                spin_loop();
+            }
+            prev.as_ref()
+                .map_or(ptr::null(), |p| &p.on_cpu as *const AtomicBool)
+        } else {
+            ptr::null()
+        };
         // Switch page table
         let p4 =
             MMState::of(&*crate::modules::PROCESS_MANAGER.current_proc().unwrap()).get_page_table();
@@ -143,6 +208,7 @@ impl ArchContext for AArch64Context {
             // Force flush TLB.
             asm!("tlbi vmalle1is");
         }
+        TPIDR_EL0.set(self.thread_pointer.load(Ordering::Relaxed) as _);
         // Load user frame
         let exception_frame = self.pop_exception_frame().unwrap_or_else(|| {
             let mut frame: *mut ExceptionFrame =
@@ -157,18 +223,65 @@ impl ArchContext for AArch64Context {
             slot.store(status);
             (*exception_frame).x0 = ::core::mem::transmute(status);
         }
-        // Set stack pointer
-        asm!("mov sp, {}", in(reg) exception_frame);
-        // Return from exception
-        super::exception::exit_exception();
+        // Set stack pointer, and then release the previous kernel stack.
+        // Deliver pending signals on the new stack, and return from exception.
+        asm!(
+            "mov sp, {frame}",
+            "cbz {prev}, 1f",
+            "stlrb wzr, [{prev}]",
+            "1:",
+            "mov x0, sp",
+            "bl handle_pending_signals",
+            "b exit_exception",
+            frame = in(reg) exception_frame,
+            prev = in(reg) prev_on_cpu,
+            options(noreturn),
+        );
+    }
+
+    unsafe fn return_from_signal_handler(&self) -> Option<(isize, usize)> {
+        let saved = self.signal_frames.lock().pop()?;
+        let x0 = saved.frame.x0;
+        let frame = *self.exception_frames.lock().last()?;
+        *frame = saved.frame;
+        Some((x0 as _, saved.mask))
+    }
+
+    fn capture_user_frame(&self) -> Box<dyn Any> {
+        let frame = *self.exception_frames.lock().first().unwrap();
+        box ForkedFrame {
+            frame: unsafe { (*frame).clone() },
+            thread_pointer: self.thread_pointer.load(Ordering::Relaxed),
+            signal_frames: self.signal_frames.lock().clone(),
+        }
+    }
+
+    unsafe fn return_to_forked_user(&self, frame: Box<dyn Any>) -> ! {
+        interrupt::disable();
+        let frame = Box::into_raw(frame.downcast::<ForkedFrame>().unwrap());
+        // The user frame goes to the top of the kernel stack, which the current call stack overlaps.
+        // Copy it from below.
+        let slot = self.kernel_stack_top.sub(size_of::<ExceptionFrame>());
+        asm!(
+            "mov sp, {slot}",
+            "br {resume}",
+            slot = in(reg) slot,
+            resume = in(reg) resume_forked_user as usize,
+            in("x0") self,
+            in("x1") frame,
+            in("x2") slot,
+            options(noreturn),
+        );
     }
 
     unsafe fn enter_usermode(
-        entry: extern "C" fn(_argc: isize, _argv: *const *const u8),
+        entry: UserEntry,
         sp: Address,
+        tp: Address,
         page_table: &mut PageTable,
         argc: isize,
         argv: *const *const u8,
+        envp: *const *const u8,
     ) -> ! {
         // log!(
         //     "TTBR0_EL1={:x} elr_el1={:?} sp_el0={:?}",
@@ -182,9 +295,8 @@ impl ArchContext for AArch64Context {
                 msr spsr_el1, {0}
                 msr elr_el1, {1}
                 msr sp_el0, {2}
+                msr tpidr_el0, {4}
                 msr	ttbr0_el1, {3}
-                mov x0, {4}
-                mov x1, {5}
                 tlbi vmalle1is
                 dsb sy
                 isb sy
@@ -194,15 +306,56 @@ impl ArchContext for AArch64Context {
             in(reg) entry,
             in(reg) sp.as_usize(),
             in(reg) page_table as *const _,
-            in(reg) argc,
-            in(reg) argv,
+            in(reg) tp.as_usize(),
+            in("x0") argc,
+            in("x1") argv,
+            in("x2") envp,
         }
         unreachable!()
     }
 }
 
+/// Each signal is blocked while its handler runs, so deeper nesting means that handlers are not returning.
+const MAX_SIGNAL_FRAMES: usize = NSIG;
+
+/// A user frame interrupted by a signal handler, and the signal mask to restore with it.
+#[derive(Clone)]
+struct SignalFrame {
+    frame: ExceptionFrame,
+    mask: usize,
+}
+
+/// User registers of a forking task, captured by `capture_user_frame`.
+struct ForkedFrame {
+    frame: ExceptionFrame,
+    thread_pointer: usize,
+    signal_frames: Vec<SignalFrame>,
+}
+
+/// Called by `return_to_forked_user`, on the kernel stack right below `slot`.
+unsafe extern "C" fn resume_forked_user(
+    context: &AArch64Context,
+    forked: *mut ForkedFrame,
+    slot: *mut ExceptionFrame,
+) -> ! {
+    let forked = Box::from_raw(forked);
+    slot.write(forked.frame);
+    (*slot).x0 = 0;
+    context
+        .thread_pointer
+        .store(forked.thread_pointer, Ordering::Relaxed);
+    *context.signal_frames.lock() = forked.signal_frames;
+    context.exception_frames.lock().push(slot);
+    context.return_to_user()
+}
+
 impl Drop for AArch64Context {
     fn drop(&mut self) {
         // println!("Context drop");
+        let this = self as *mut Self;
+        for active in &ACTIVE_CONTEXT {
+            let _ =
+                active.compare_exchange(this, ptr::null_mut(), Ordering::SeqCst, Ordering::SeqCst);
+        }
     }
 }
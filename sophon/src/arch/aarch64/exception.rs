@@ -1,8 +1,12 @@
 use crate::arch::{aarch64::context::*, *};
+use crate::memory::vma::{handle_copy_on_write, handle_page_fault, Access};
+use crate::memory::USER_SPACE_MEMORY_RANGE;
 use crate::modules::INTERRUPT;
-use crate::modules::PROCESS_MANAGER;
+use crate::modules::{PROCESS_MANAGER, SCHEDULER};
+use crate::task::MMState;
 use core::arch::{asm, global_asm};
 use cortex_a::{asm::barrier, registers::*};
+use memory::address::Address;
 use tock_registers::interfaces::{Readable, Writeable};
 
 #[repr(usize)]
@@ -25,16 +29,66 @@ pub enum ExceptionKind {
 }
 
 #[repr(u32)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(unused)]
 pub enum ExceptionClass {
+    Unknown = 0b000000,
     SVCAArch64 = 0b010101,
+    InstructionAbortLowerEL = 0b100000,
+    InstructionAbortHigherEL = 0b100001,
+    PCAlignmentFault = 0b100010,
     DataAbortLowerEL = 0b100100,
     DataAbortHigherEL = 0b100101,
+    SPAlignmentFault = 0b100110,
+    FPExceptionAArch64 = 0b101100,
+    SError = 0b101111,
+    BreakpointLowerEL = 0b110000,
+    SoftwareStepLowerEL = 0b110010,
+    WatchpointLowerEL = 0b110100,
+    BRKAArch64 = 0b111100,
+}
+
+impl ExceptionClass {
+    fn from_esr(esr: u64) -> Option<Self> {
+        use ExceptionClass::*;
+        let class = match esr >> 26 {
+            0b000000 => Unknown,
+            0b010101 => SVCAArch64,
+            0b100000 => InstructionAbortLowerEL,
+            0b100001 => InstructionAbortHigherEL,
+            0b100010 => PCAlignmentFault,
+            0b100100 => DataAbortLowerEL,
+            0b100101 => DataAbortHigherEL,
+            0b100110 => SPAlignmentFault,
+            0b101100 => FPExceptionAArch64,
+            0b101111 => SError,
+            0b110000 => BreakpointLowerEL,
+            0b110010 => SoftwareStepLowerEL,
+            0b110100 => WatchpointLowerEL,
+            0b111100 => BRKAArch64,
+            _ => return None,
+        };
+        Some(class)
+    }
+
+    /// Signal for a fault of this class raised by a user program.
+    fn user_fault_signal(class: Option<Self>) -> usize {
+        use syscall::signal::*;
+        use ExceptionClass::*;
+        match class {
+            Some(InstructionAbortLowerEL | DataAbortLowerEL) => SIGSEGV,
+            Some(PCAlignmentFault | SPAlignmentFault) => SIGBUS,
+            Some(FPExceptionAArch64) => SIGFPE,
+            Some(BreakpointLowerEL | SoftwareStepLowerEL | WatchpointLowerEL | BRKAArch64) => {
+                SIGTRAP
+            }
+            _ => SIGILL,
+        }
+    }
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ExceptionFrame {
     pub q: [u128; 32],
     pub elr_el1: *mut u8,
@@ -52,10 +106,46 @@ pub struct ExceptionFrame {
     pub x1: usize,
 }
 
-unsafe fn get_exception_class() -> ExceptionClass {
-    let esr_el1: u32;
-    asm!("mrs {:x}, esr_el1", out(reg) esr_el1);
-    ::core::mem::transmute(esr_el1 >> 26)
+impl ExceptionFrame {
+    /// General purpose register `x{i}`. `x31` is the user stack pointer `SP_EL0`.
+    pub fn reg(&self, i: usize) -> usize {
+        match i {
+            0 => self.x0,
+            1 => self.x1,
+            2 => self.x2,
+            3 => self.x3,
+            4 => self.x4,
+            5 => self.x5,
+            6 => self.x6,
+            7 => self.x7,
+            // Pushed in pairs, from (x28, x29) down to (x8, x9)
+            8..=29 => self.x8_to_x29[(29 - i) / 2 * 2 + i % 2] as _,
+            30 => self.x30,
+            31 => self.x31,
+            _ => unreachable!(),
+        }
+    }
+
+    fn dump(&self) {
+        for i in (0..32).step_by(4) {
+            log!(
+                "    x{:<2}={:#018x} x{:<2}={:#018x} x{:<2}={:#018x} x{:<2}={:#018x}",
+                i,
+                self.reg(i),
+                i + 1,
+                self.reg(i + 1),
+                i + 2,
+                self.reg(i + 2),
+                i + 3,
+                self.reg(i + 3),
+            );
+        }
+        log!("    pc={:?} spsr={:#x}", self.elr_el1, self.spsr_el1);
+    }
+}
+
+fn get_exception_class() -> Option<ExceptionClass> {
+    ExceptionClass::from_esr(ESR_EL1.get())
 }
 
 unsafe fn is_el0(frame: &ExceptionFrame) -> bool {
@@ -70,7 +160,7 @@ pub unsafe extern "C" fn handle_exception(exception_frame: &mut ExceptionFrame)
         .push_exception_frame(exception_frame);
     let exception = get_exception_class();
     match exception {
-        ExceptionClass::SVCAArch64 => {
+        Some(ExceptionClass::SVCAArch64) => {
             // log!("SVCAArch64 Start {:?}", Task::current().unwrap().id());
             let f = if privileged {
                 crate::task::syscall::handle_syscall::<true>
@@ -88,7 +178,11 @@ pub unsafe extern "C" fn handle_exception(exception_frame: &mut ExceptionFrame)
             exception_frame.x0 = ::core::mem::transmute(r);
             // log!("SVCAArch64 End {:?}", Task::current().unwrap().id());
         }
-        ExceptionClass::DataAbortLowerEL | ExceptionClass::DataAbortHigherEL => {
+        Some(class) if handle_translation_fault(class) => {}
+        Some(class) if handle_permission_fault(class) => {}
+        _ if !privileged => handle_user_fault(exception_frame, exception),
+        Some(ExceptionClass::DataAbortHigherEL) if fixup_user_copy(exception_frame) => {}
+        Some(ExceptionClass::DataAbortHigherEL) => {
             let mut far: usize;
             asm!("mrs {:x}, far_el1", out(reg) far);
             let mut elr: usize;
@@ -96,7 +190,6 @@ pub unsafe extern "C" fn handle_exception(exception_frame: &mut ExceptionFrame)
             log!("Data Abort {:?} {:?}", far as *mut (), elr as *mut ());
             unreachable!()
         }
-        #[allow(unreachable_patterns)]
         _ => panic_for_unhandled_exception(exception_frame),
     }
     // Note: `Task::current()` must be dropped before calling `return_to_user`.
@@ -105,6 +198,110 @@ pub unsafe extern "C" fn handle_exception(exception_frame: &mut ExceptionFrame)
     (*context).return_to_user();
 }
 
+/// Populate a page of the current process, on its first touch by the user program or the kernel.
+/// Returns `false` if the fault is not a translation fault of a mapped area.
+unsafe fn handle_translation_fault(class: ExceptionClass) -> bool {
+    use ExceptionClass::*;
+    let esr = ESR_EL1.get();
+    // DFSC/IFSC 0b0001xx: Translation fault at level xx
+    if esr & 0b111100 != 0b000100 {
+        return false;
+    }
+    let access = match class {
+        InstructionAbortLowerEL => Access::Execute,
+        // WnR
+        DataAbortLowerEL | DataAbortHigherEL if esr & (1 << 6) != 0 => Access::Write,
+        DataAbortLowerEL | DataAbortHigherEL => Access::Read,
+        _ => return false,
+    };
+    let far = Address::from(FAR_EL1.get() as usize);
+    if !USER_SPACE_MEMORY_RANGE.contains(&far) {
+        return false;
+    }
+    let proc = PROCESS_MANAGER.current_proc().unwrap();
+    handle_page_fault(MMState::of(&*proc), far, access)
+}
+
+/// Copy a copy-on-write page of the current process, on a write by the user program or the kernel.
+/// Returns `false` if the fault is not a permission fault of such a write.
+unsafe fn handle_permission_fault(class: ExceptionClass) -> bool {
+    use ExceptionClass::*;
+    let esr = ESR_EL1.get();
+    // DFSC 0b0011xx: Permission fault at level xx. WnR: Caused by a write.
+    if esr & 0b111100 != 0b001100 || esr & (1 << 6) == 0 {
+        return false;
+    }
+    if !matches!(class, DataAbortLowerEL | DataAbortHigherEL) {
+        return false;
+    }
+    let far = Address::from(FAR_EL1.get() as usize);
+    let proc = PROCESS_MANAGER.current_proc().unwrap();
+    handle_copy_on_write(MMState::of(&*proc), far)
+}
+
+extern "C" {
+    fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn __copy_user_fault();
+    fn __copy_user_end();
+}
+
+/// Copy `len` bytes between the kernel and the user memory of the current process.
+/// Returns `false` if the user memory faults in the middle of the copy.
+pub unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> bool {
+    __copy_user(dst, src, len) != 0
+}
+
+/// Resume a kernel fault raised by `copy_user` at its failure path.
+/// Returns `false` if the fault is raised elsewhere.
+fn fixup_user_copy(exception_frame: &mut ExceptionFrame) -> bool {
+    let pc = exception_frame.elr_el1 as usize;
+    if !(__copy_user as usize..__copy_user_end as usize).contains(&pc) {
+        return false;
+    }
+    exception_frame.elr_el1 = __copy_user_fault as *mut u8;
+    true
+}
+
+/// Report a fault raised by the current user program, and send the corresponding signal to the process.
+/// Unless the signal is caught, the process is terminated before returning to the user mode.
+unsafe fn handle_user_fault(exception_frame: &ExceptionFrame, exception: Option<ExceptionClass>) {
+    let signal = ExceptionClass::user_fault_signal(exception);
+    log!(
+        "[kernel] process #{} fault: {:?} ESR={:#x} FAR={:#x} (signal {})",
+        PROCESS_MANAGER.current_proc_id().unwrap().0,
+        exception,
+        ESR_EL1.get(),
+        FAR_EL1.get(),
+        signal
+    );
+    exception_frame.dump();
+    PROCESS_MANAGER.force_signal(signal);
+}
+
+/// Called by `return_to_user`, after switching to the kernel stack of the current task.
+/// Deliver a pending signal if we are returning to the user mode.
+#[no_mangle]
+pub unsafe extern "C" fn handle_pending_signals(exception_frame: &mut ExceptionFrame) {
+    if !is_el0(exception_frame) {
+        return;
+    }
+    if let Some(handler) = PROCESS_MANAGER.dequeue_signal() {
+        let context =
+            AArch64Context::of(&*PROCESS_MANAGER.current_task().unwrap()) as *const AArch64Context;
+        if !(*context).enter_signal_handler(exception_frame, &handler) {
+            // Same exit status as a termination by the signal
+            let proc = PROCESS_MANAGER.current_proc().unwrap();
+            log!(
+                "[kernel] process #{} has too many nested signal handlers",
+                proc.id().0
+            );
+            proc.exit(128 + syscall::signal::SIGSEGV as isize);
+            drop(proc);
+            SCHEDULER.schedule()
+        }
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn handle_exception_serror(exception_frame: *mut ExceptionFrame) {
     log!("SError received");
@@ -132,8 +329,9 @@ unsafe fn panic_for_unhandled_exception(exception_frame: *mut ExceptionFrame) ->
         sp_el0,
     );
     panic!(
-        "Unknown exception 0b{:b}",
-        ::core::mem::transmute::<_, u32>(exception)
+        "Unhandled exception {:?} (EC=0b{:b})",
+        exception,
+        ESR_EL1.get() >> 26
     );
 }
 
@@ -154,7 +352,6 @@ pub unsafe extern "C" fn handle_interrupt(exception_frame: &mut ExceptionFrame)
 
 extern "C" {
     pub fn exception_handlers() -> !;
-    pub fn exit_exception() -> !;
 }
 
 pub unsafe extern "C" fn setup_vbar() {
@@ -167,6 +364,27 @@ pub unsafe extern "C" fn setup_vbar() {
     barrier::isb(barrier::SY);
 }
 
+// Byte-wise copy between the kernel and user memory. Faults on the user memory are redirected to
+// `__copy_user_fault` by `fixup_user_copy`.
+global_asm! {"
+.global __copy_user
+.global __copy_user_fault
+.global __copy_user_end
+
+__copy_user:
+    cbz x2, 1f
+0:  ldrb w3, [x1], #1
+    strb w3, [x0], #1
+    subs x2, x2, #1
+    b.ne 0b
+1:  mov x0, #1
+    ret
+__copy_user_fault:
+    mov x0, #0
+    ret
+__copy_user_end:
+"}
+
 // FIXME: We may need to switch stack after enter an exception,
 //        to avoid stack overflow.
 // Exception handlers table
@@ -1,11 +1,15 @@
 mod context;
 mod exception;
+mod smp;
 
 use super::{Arch, TargetArch};
 use crate::memory::kernel::KERNEL_MEMORY_MAPPER;
 use boot::BootInfo;
 use context::AArch64Context;
 use core::arch::asm;
+use core::time::Duration;
+use cortex_a::registers::*;
+use tock_registers::interfaces::Readable;
 
 static mut SHUTDOWN: Option<extern "C" fn() -> !> = None;
 
@@ -17,6 +21,7 @@ impl Arch for AArch64 {
     fn init(boot_info: &'static BootInfo) {
         unsafe { SHUTDOWN = boot_info.shutdown };
         interrupt::disable();
+        smp::init();
     }
 
     fn setup_interrupt_table() {
@@ -25,6 +30,25 @@ impl Arch for AArch64 {
         }
     }
 
+    fn num_cores() -> usize {
+        smp::num_cores()
+    }
+
+    #[inline(always)]
+    fn current_core() -> usize {
+        smp::current_core()
+    }
+
+    fn start_secondary_cores() {
+        smp::start_secondary_cores()
+    }
+
+    fn uptime() -> Duration {
+        let ticks = CNTPCT_EL0.get() as u128;
+        let freq = CNTFRQ_EL0.get() as u128;
+        Duration::from_nanos((ticks * 1_000_000_000 / freq) as u64)
+    }
+
     fn halt(code: i32) -> ! {
         // Try QEMU exit service
         if cfg!(feature = "qemu") {
@@ -55,6 +79,10 @@ impl Arch for AArch64 {
             unsafe { asm!("wfe") };
         }
     }
+
+    unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> bool {
+        exception::copy_user(dst, src, len)
+    }
 }
 
 #[allow(unused)]
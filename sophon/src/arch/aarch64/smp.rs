@@ -0,0 +1,253 @@
+use super::context::KernelStack;
+use crate::memory::kernel::KERNEL_MEMORY_MAPPER;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use cortex_a::registers::*;
+use device_tree::DeviceTree;
+use memory::address::Address;
+use spin::Once;
+use tock_registers::interfaces::{Readable, Writeable};
+
+/// Upper bound of the number of cores the kernel is able to manage.
+pub const MAX_CORES: usize = 16;
+
+/// PSCI `CPU_ON` function id (SMC64 calling convention).
+const PSCI_CPU_ON: u32 = 0xC400_0003;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PsciConduit {
+    Hvc,
+    Smc,
+}
+
+struct Cores {
+    /// PSCI conduit and the function id of `CPU_ON`
+    psci: Option<(PsciConduit, u32)>,
+    /// MPIDR of each core, indexed by the logical core id. The boot core is always `0`.
+    mpidr: Vec<u64>,
+}
+
+static CORES: Once<Cores> = Once::new();
+static ONLINE_CORES: AtomicUsize = AtomicUsize::new(1);
+
+/// Boot parameters for a secondary core.
+///
+/// The core starts with MMU and caches disabled, so `secondary_core_entry` reads
+/// this record by its physical address. Aligned to make sure it never crosses a page.
+#[repr(C, align(128))]
+struct SecondaryBootInfo {
+    mair: u64,
+    tcr: u64,
+    ttbr0: u64,
+    cpacr: u64,
+    sctlr: u64,
+    stack_top: u64,
+    this: u64,
+    entry: u64,
+    core: usize,
+}
+
+fn current_mpidr() -> u64 {
+    // Aff3 | Aff2 | Aff1 | Aff0
+    MPIDR_EL1.get() & 0xff_00ff_ffff
+}
+
+fn discover_cores(devtree: Option<&DeviceTree>) -> Cores {
+    let boot_core = current_mpidr();
+    let mut mpidr = vec![boot_core];
+    let psci = devtree.and_then(|devtree| {
+        let node = devtree
+            .compatible("arm,psci-1.0")
+            .or_else(|| devtree.compatible("arm,psci-0.2"))
+            .or_else(|| devtree.compatible("arm,psci"))?;
+        let conduit = match node.prop_str("method")? {
+            "hvc" => PsciConduit::Hvc,
+            "smc" => PsciConduit::Smc,
+            _ => return None,
+        };
+        let cpu_on = node.prop_u32("cpu_on").unwrap_or(PSCI_CPU_ON);
+        Some((conduit, cpu_on))
+    });
+    if let (Some(devtree), Some(_)) = (devtree, psci) {
+        for cpu in devtree.device_type("cpu") {
+            // For cpu nodes, `reg` holds the MPIDR affinity bits.
+            let id = match cpu.regs().and_then(|mut regs| regs.next()) {
+                Some(reg) => reg.start.as_usize() as u64,
+                None => continue,
+            };
+            if id == boot_core {
+                continue;
+            }
+            match cpu.prop_str("enable-method") {
+                None | Some("psci") if mpidr.len() < MAX_CORES => mpidr.push(id),
+                None | Some("psci") => {
+                    log!("[kernel] skip cpu {:#x}: too many cores", id);
+                }
+                Some(method) => {
+                    log!(
+                        "[kernel] skip cpu {:#x}: unsupported enable-method {:?}",
+                        id,
+                        method
+                    );
+                }
+            }
+        }
+    }
+    Cores { psci, mpidr }
+}
+
+pub fn init() {
+    TPIDR_EL1.set(0);
+    let cores = CORES.call_once(|| discover_cores(unsafe { crate::DEV_TREE.as_ref() }));
+    log!("[kernel] {} core(s) available", cores.mpidr.len());
+}
+
+pub fn num_cores() -> usize {
+    CORES.get().map(|c| c.mpidr.len()).unwrap_or(1)
+}
+
+#[inline(always)]
+pub fn current_core() -> usize {
+    TPIDR_EL1.get() as usize
+}
+
+unsafe fn psci_call(conduit: PsciConduit, func: u32, a1: usize, a2: usize, a3: usize) -> isize {
+    let ret: isize;
+    macro_rules! call {
+        ($insn: literal) => {
+            asm!(
+                $insn,
+                inout("x0") func as usize => ret,
+                inout("x1") a1 => _,
+                inout("x2") a2 => _,
+                inout("x3") a3 => _,
+                out("x4") _, out("x5") _, out("x6") _, out("x7") _,
+                out("x8") _, out("x9") _, out("x10") _, out("x11") _,
+                out("x12") _, out("x13") _, out("x14") _, out("x15") _,
+                out("x16") _, out("x17") _,
+                options(nostack)
+            )
+        };
+    }
+    match conduit {
+        PsciConduit::Hvc => call!("hvc #0"),
+        // `smc #0`. LLVM rejects the mnemonic unless the target has EL3.
+        PsciConduit::Smc => call!(".inst 0xd4000003"),
+    }
+    ret
+}
+
+/// Boot all the secondary cores discovered by `init`, one by one.
+pub fn start_secondary_cores() {
+    let cores = CORES.get().unwrap();
+    let (conduit, cpu_on) = match cores.psci {
+        Some(psci) => psci,
+        None => return,
+    };
+    extern "C" {
+        fn secondary_core_entry();
+    }
+    let entry = KERNEL_MEMORY_MAPPER
+        .translate(Address::from(secondary_core_entry as *const u8))
+        .unwrap();
+    for (core, mpidr) in cores.mpidr.iter().cloned().enumerate().skip(1) {
+        let stack = KernelStack::new();
+        let info = alloc::boxed::Box::leak(box SecondaryBootInfo {
+            mair: MAIR_EL1.get(),
+            tcr: TCR_EL1.get(),
+            ttbr0: TTBR0_EL1.get(),
+            cpacr: CPACR_EL1.get(),
+            sctlr: SCTLR_EL1.get(),
+            stack_top: stack.range().end.as_usize() as u64,
+            this: 0,
+            entry: secondary_core_main as usize as u64,
+            core,
+        });
+        info.this = info as *const SecondaryBootInfo as u64;
+        let info_paddr = KERNEL_MEMORY_MAPPER
+            .translate(Address::from(info as *const SecondaryBootInfo))
+            .unwrap();
+        let expected_online_cores = ONLINE_CORES.load(Ordering::SeqCst) + 1;
+        let status = unsafe {
+            // Make the record visible to a core with caches disabled
+            asm!("dc civac, {}", "dsb sy", in(reg) info.this);
+            psci_call(
+                conduit,
+                cpu_on,
+                mpidr as usize,
+                entry.as_usize(),
+                info_paddr.as_usize(),
+            )
+        };
+        if status != 0 {
+            log!(
+                "[kernel] failed to start core #{} (mpidr={:#x}): PSCI error {}",
+                core,
+                mpidr,
+                status
+            );
+            continue;
+        }
+        while ONLINE_CORES.load(Ordering::SeqCst) < expected_online_cores {
+            spin_loop();
+        }
+    }
+}
+
+/// Rust entry for secondary cores. Running with the kernel address space and a fresh kernel stack.
+extern "C" fn secondary_core_main(info: &'static SecondaryBootInfo) -> ! {
+    TPIDR_EL1.set(info.core as u64);
+    unsafe {
+        super::exception::setup_vbar();
+    }
+    ONLINE_CORES.fetch_add(1, Ordering::SeqCst);
+    crate::start_secondary_core(info.core)
+}
+
+// Secondary core trampoline.
+// Entered by the PSCI firmware at EL2 or EL1, with MMU off and x0 = physical address of `SecondaryBootInfo`.
+// Must not cross a page, since the kernel image is not physically contiguous.
+global_asm! {"
+.global secondary_core_entry
+.balign 128
+secondary_core_entry:
+    mrs     x1, CurrentEL
+    lsr     x1, x1, #2
+    cmp     x1, #2
+    b.ne    1f
+    // Drop to EL1, same as what the bootloader does for the boot core
+    mov     x1, #3
+    msr     cnthctl_el2, x1
+    msr     cntvoff_el2, xzr
+    mov     x1, #(1 << 31)
+    msr     hcr_el2, x1
+    mov     x1, #0x3c5
+    msr     spsr_el2, x1
+    adr     x1, 1f
+    msr     elr_el2, x1
+    eret
+1:
+    ldr     x1, [x0, #0]
+    msr     mair_el1, x1
+    ldr     x1, [x0, #8]
+    msr     tcr_el1, x1
+    ldr     x1, [x0, #16]
+    msr     ttbr0_el1, x1
+    ldr     x1, [x0, #24]
+    msr     cpacr_el1, x1
+    isb
+    tlbi    vmalle1
+    dsb     sy
+    isb
+    ldr     x1, [x0, #32]
+    msr     sctlr_el1, x1
+    isb
+    ldr     x1, [x0, #40]
+    mov     sp, x1
+    ldr     x1, [x0, #56]
+    ldr     x0, [x0, #48]
+    br      x1
+"}
@@ -1,4 +1,7 @@
+use alloc::boxed::Box;
 use boot::BootInfo;
+use core::any::Any;
+use core::time::Duration;
 use memory::address::*;
 use memory::page_table::PageTable;
 use proc::Task;
@@ -6,28 +9,45 @@ use proc::Task;
 #[allow(unused)]
 #[inline]
 pub(self) fn handle_irq(irq: usize) -> isize {
-    if let Some(handler) = crate::modules::INTERRUPT.get_irq_handler(irq) {
-        handler()
-    } else {
+    // The timer handler does not return, so check expired futex waits first.
+    crate::task::futex::FUTEXES.expire_timeouts();
+    crate::modules::handle_irq(irq).unwrap_or_else(|| {
         log!("IRQ #{:?} has no handler!", irq);
         0
-    }
+    })
 }
 
+/// Entry point of a user program: `_start(argc, argv, envp)`.
+pub type UserEntry = extern "C" fn(argc: isize, argv: *const *const u8, envp: *const *const u8);
+
 pub trait ArchContext: Sized + 'static {
     fn empty() -> Self;
     fn new(entry: *const extern "C" fn(ctx: *mut ()) -> !, ctx: *mut ()) -> Self;
     fn set_response_status(&self, s: isize);
 
     unsafe extern "C" fn return_to_user(&self) -> !;
+    /// Enter the user mode with the given stack pointer and thread pointer.
     unsafe fn enter_usermode(
-        entry: extern "C" fn(_argc: isize, _argv: *const *const u8),
+        entry: UserEntry,
         sp: Address,
+        tp: Address,
         page_table: &mut PageTable,
         argc: isize,
         argv: *const *const u8,
+        envp: *const *const u8,
     ) -> !;
 
+    /// Restore the user frame interrupted by the last signal handler, as the result of the current syscall.
+    /// Returns the restored return-value register, and the signal mask saved with the frame.
+    unsafe fn return_from_signal_handler(&self) -> Option<(isize, usize)>;
+
+    /// Capture the user registers of the current syscall, for a forked task to resume with
+    /// `return_to_forked_user`.
+    fn capture_user_frame(&self) -> Box<dyn Any>;
+
+    /// Resume the user mode from a frame captured by `capture_user_frame`, with the syscall returning `0`.
+    unsafe fn return_to_forked_user(&self, frame: Box<dyn Any>) -> !;
+
     fn of(task: &dyn Task) -> &Self {
         unsafe { task.context().downcast_ref_unchecked() }
     }
@@ -40,7 +60,23 @@ pub trait Arch {
 
     fn setup_interrupt_table();
 
+    /// Number of cores available to the kernel. Valid after `init`.
+    fn num_cores() -> usize;
+
+    /// Logical index of the current core. The boot core is always `0`.
+    fn current_core() -> usize;
+
+    /// Boot all the secondary cores. Each of them enters `crate::start_secondary_core`.
+    fn start_secondary_cores();
+
+    /// Time elapsed since the system counter started.
+    fn uptime() -> Duration;
+
     fn halt(code: i32) -> !;
+
+    /// Copy `len` bytes between the kernel and the user memory of the current process.
+    /// Returns `false` instead of panicking if the user memory faults.
+    unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> bool;
 }
 
 pub type TargetArch = impl Arch;
@@ -1,5 +1,8 @@
-use super::{Arch, ArchContext, TargetArch};
+use super::{Arch, ArchContext, TargetArch, UserEntry};
+use alloc::boxed::Box;
 use boot::BootInfo;
+use core::any::Any;
+use core::time::Duration;
 use memory::{address::Address, page_table::PageTable};
 
 #[repr(C)]
@@ -23,14 +26,28 @@ impl ArchContext for X64Context {
     }
 
     unsafe fn enter_usermode(
-        _entry: extern "C" fn(_argc: isize, _argv: *const *const u8),
+        _entry: UserEntry,
         _sp: Address,
+        _tp: Address,
         _page_table: &mut PageTable,
         _argc: isize,
         _argv: *const *const u8,
+        _envp: *const *const u8,
     ) -> ! {
         unimplemented!()
     }
+
+    unsafe fn return_from_signal_handler(&self) -> Option<(isize, usize)> {
+        unimplemented!()
+    }
+
+    fn capture_user_frame(&self) -> Box<dyn Any> {
+        unimplemented!()
+    }
+
+    unsafe fn return_to_forked_user(&self, _frame: Box<dyn Any>) -> ! {
+        unimplemented!()
+    }
 }
 pub struct X64;
 
@@ -45,9 +62,29 @@ impl Arch for X64 {
         unimplemented!()
     }
 
+    fn num_cores() -> usize {
+        unimplemented!()
+    }
+
+    fn current_core() -> usize {
+        unimplemented!()
+    }
+
+    fn start_secondary_cores() {
+        unimplemented!()
+    }
+
+    fn uptime() -> Duration {
+        unimplemented!()
+    }
+
     fn halt(_code: i32) -> ! {
         unimplemented!()
     }
+
+    unsafe fn copy_user(_dst: *mut u8, _src: *const u8, _len: usize) -> bool {
+        unimplemented!()
+    }
 }
 
 #[allow(unused)]
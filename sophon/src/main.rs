@@ -13,6 +13,7 @@
 #![feature(drain_filter)]
 #![feature(downcast_unchecked)]
 #![feature(const_option_ext)]
+#![feature(used_with_arg)]
 #![no_std]
 #![no_main]
 
@@ -35,10 +36,12 @@ use core::panic::PanicInfo;
 use crate::arch::{Arch, TargetArch};
 use crate::memory::kernel::{KernelHeapAllocator, KERNEL_HEAP};
 use crate::memory::physical::PHYSICAL_MEMORY;
-use crate::modules::{PROCESS_MANAGER, SCHEDULER};
+use crate::modules::{INTERRUPT, PROCESS_MANAGER, SCHEDULER, TIMER};
 use crate::task::runnables::{Idle, UserTask};
 use ::vfs::ramfs::RamFS;
+use alloc::borrow::Cow;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use boot::BootInfo;
 use device_tree::DeviceTree;
 use spin::Mutex;
@@ -60,18 +63,6 @@ fn display_banner() {
     println!(r"");
 }
 
-const ALL_MODULES: &'static [(&'static str, &'static str)] = &[
-    ("hello", "/etc/modules/libhello.so"),
-    ("bcm2711-gpio", "/etc/modules/libbcm2711_gpio.so"),
-    ("gic", "/etc/modules/libgic.so"),
-    ("gic-timer", "/etc/modules/libgic_timer.so"),
-    ("vfs", "/etc/modules/libvfs.so"),
-    ("pm", "/etc/modules/libpm.so"),
-    ("dev", "/etc/modules/libdev.so"),
-    ("pl011", "/etc/modules/libpl011.so"),
-    ("round-robin", "/etc/modules/libround_robin.so"),
-];
-
 #[no_mangle]
 pub extern "C" fn _start(boot_info: &'static BootInfo) -> isize {
     if let Some(uart) = boot_info.uart {
@@ -93,6 +84,7 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> isize {
     PHYSICAL_MEMORY.init(boot_info.available_physical_memory);
     log!("[kernel] initialize kernel heap");
     KERNEL_HEAP.init();
+    PHYSICAL_MEMORY.init_frame_table(boot_info.available_physical_memory);
 
     // Initialize arch and boot drivers
     log!("[kernel] load device tree");
@@ -107,19 +99,25 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> isize {
     unsafe { INIT_FS = Some(initfs) };
 
     log!("[kernel] load kernel modules...");
-    for (name, path) in ALL_MODULES {
-        log!("[kernel]  - load module '{}'", name);
-        let file = initfs.get(path).unwrap().as_file().unwrap();
-        crate::modules::register(name, file.to_vec());
+    crate::modules::register_module_manager();
+    if let Err(e) = crate::modules::load_boot_modules(initfs) {
+        panic!("failed to load kernel modules: {:?}", e);
     }
     log!("[kernel] kernel modules loaded");
 
     log!("[kernel] start idle process");
-    let _proc = PROCESS_MANAGER.spawn(box Idle);
+    let idle = PROCESS_MANAGER.spawn(box Idle);
+    for _ in 1..TargetArch::num_cores() {
+        let _task = idle.clone().spawn_task(box Idle);
+    }
+    for task in idle.tasks().lock().iter() {
+        SCHEDULER.set_priority(*task, 19);
+    }
 
     log!("[kernel] start init process");
-    let init = initfs.get("/bin/init").unwrap().as_file().unwrap().to_vec();
-    let _proc = UserTask::spawn_user_process(init.to_vec(), &[]);
+    let init = unsafe { &*INIT_FS.unwrap() }.get("/bin/init").unwrap();
+    let init: &'static [u8] = init.as_file().unwrap();
+    let _proc = UserTask::spawn_user_process(Cow::Borrowed(init), Vec::new(), Vec::new(), None);
 
     if cfg!(sophon_test) {
         log!("[kernel] run boot tests");
@@ -128,10 +126,21 @@ pub extern "C" fn _start(boot_info: &'static BootInfo) -> isize {
         crate::utils::testing::start_kernel_test_runner();
     }
 
+    log!("[kernel] start secondary cores");
+    TargetArch::start_secondary_cores();
+
     log!("[kernel] start scheduler");
     SCHEDULER.schedule();
 }
 
+/// Entry point of the secondary cores, after the arch-specific initialization.
+pub fn start_secondary_core(core: usize) -> ! {
+    log!("[kernel] core #{} online", core);
+    INTERRUPT.init(false);
+    TIMER.init(false);
+    SCHEDULER.schedule();
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo<'_>) -> ! {
     log!("{}", info);
@@ -2,12 +2,16 @@ use crate::memory::kernel::KERNEL_HEAP_RANGE;
 use crate::memory::physical::PHYSICAL_MEMORY;
 use atomic::{Atomic, Ordering};
 use core::ops::{Deref, DerefMut};
+use interrupt::UninterruptibleMutex;
 use memory::address::{Address, P, V};
 use memory::page::*;
 use memory::page_table::{PageFlags, PageTable};
+use spin::Mutex;
 
 pub struct KernelMemoryMapper {
     page_table: Atomic<*mut PageTable>,
+    /// Serializes updates to the kernel page table across cores
+    lock: Mutex<()>,
 }
 
 unsafe impl Sync for KernelMemoryMapper {}
@@ -16,6 +20,7 @@ impl KernelMemoryMapper {
     pub const fn new() -> Self {
         Self {
             page_table: Atomic::new(core::ptr::null_mut()),
+            lock: Mutex::new(()),
         }
     }
 
@@ -36,6 +41,7 @@ impl KernelMemoryMapper {
         debug_assert!(
             page.start() >= KERNEL_HEAP_RANGE.start && page.start() < KERNEL_HEAP_RANGE.end
         );
+        let _guard = self.lock.lock_uninterruptible();
         let mut page_table = self.with_kernel_address_space();
         page_table.map(page, frame, flags, &PHYSICAL_MEMORY);
     }
@@ -45,6 +51,7 @@ impl KernelMemoryMapper {
         debug_assert!(
             page.start() >= KERNEL_HEAP_RANGE.start && page.start() < KERNEL_HEAP_RANGE.end
         );
+        let _guard = self.lock.lock_uninterruptible();
         let mut page_table = self.with_kernel_address_space();
         page_table.unmap(page, &PHYSICAL_MEMORY);
     }
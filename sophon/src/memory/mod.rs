@@ -4,7 +4,9 @@ use memory::address::Address;
 
 pub mod kernel;
 pub mod physical;
+pub mod user;
 pub mod utils;
+pub mod vma;
 
 pub const USER_SPACE_MEMORY_RANGE: Range<Address> =
     Address::new(0x1000_00000000)..Address::new(0xf000_00000000);
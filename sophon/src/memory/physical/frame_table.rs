@@ -0,0 +1,138 @@
+use alloc::boxed::Box;
+use bitflags::bitflags;
+use core::iter::Step;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use memory::page::Frame;
+use proc::ProcId;
+
+#[allow(unused, non_camel_case_types)]
+#[bitflags(u32)]
+pub enum FrameFlags {
+    /// Must stay at its physical address, e.g. while a device accesses it by DMA
+    PINNED = 1 << 0,
+    /// Written since it was populated
+    DIRTY = 1 << 1,
+    /// Used by the kernel, e.g. by the kernel heap or page tables
+    KERNEL = 1 << 2,
+    /// Mapped to the user space
+    USER = 1 << 3,
+    /// Caches the contents of a file
+    PAGE_CACHE = 1 << 4,
+    /// Released by its last reference
+    FREE = 1 << 5,
+}
+
+/// Metadata of an allocated 4K frame, or of the first 4K frame of a larger one.
+///
+/// Frames allocated before the table is created have no references, and are freed by their first `release`.
+pub struct FrameDescriptor {
+    refs: AtomicU32,
+    flags: AtomicU32,
+    owner: AtomicUsize,
+}
+
+impl FrameDescriptor {
+    const fn new() -> Self {
+        Self {
+            refs: AtomicU32::new(0),
+            flags: AtomicU32::new(0),
+            owner: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of page tables (or other users) referencing the frame.
+    pub fn refs(&self) -> u32 {
+        self.refs.load(Ordering::SeqCst)
+    }
+
+    pub fn flags(&self) -> FrameFlags {
+        self.flags.load(Ordering::SeqCst).into()
+    }
+
+    pub fn insert_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_or(flags.into(), Ordering::SeqCst);
+    }
+
+    pub fn remove_flags(&self, flags: FrameFlags) {
+        self.flags.fetch_and(!u32::from(flags), Ordering::SeqCst);
+    }
+
+    /// The process the frame is allocated for. `None` for kernel frames.
+    pub fn owner(&self) -> Option<ProcId> {
+        match self.owner.load(Ordering::SeqCst) {
+            0 => None,
+            id => Some(ProcId(id)),
+        }
+    }
+
+    pub fn set_owner(&self, owner: Option<ProcId>) {
+        let id = owner.map_or(ProcId::NULL.0, |p| p.0);
+        self.owner.store(id, Ordering::SeqCst);
+    }
+
+    /// Reset the metadata of a newly allocated frame, with a single reference.
+    pub(super) fn init(&self, flags: FrameFlags) {
+        self.flags.store(flags.into(), Ordering::SeqCst);
+        self.owner.store(ProcId::NULL.0, Ordering::SeqCst);
+        self.refs.store(1, Ordering::SeqCst);
+    }
+
+    pub(super) fn get(&self) {
+        self.refs.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Drop a reference. Returns `true` if the frame is no longer referenced, and should be freed.
+    pub(super) fn put(&self) -> bool {
+        let old = self
+            .refs
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |refs| {
+                Some(refs.saturating_sub(1))
+            })
+            .unwrap();
+        // Only frames allocated before the table is created are released without references, and only once.
+        debug_assert!(
+            old != 0 || !self.flags().contains(FrameFlags::FREE),
+            "frame released twice"
+        );
+        if old > 1 {
+            return false;
+        }
+        self.flags.store(FrameFlags::FREE.into(), Ordering::SeqCst);
+        self.owner.store(ProcId::NULL.0, Ordering::SeqCst);
+        true
+    }
+}
+
+/// Descriptors of all the frames available to the physical allocator.
+/// Allocated from the kernel heap, so updates must not take locks.
+pub(super) struct FrameTable {
+    ranges: &'static [Range<Frame>],
+    /// The lowest available frame
+    base: Frame,
+    descriptors: Box<[FrameDescriptor]>,
+}
+
+impl FrameTable {
+    pub fn new(ranges: &'static [Range<Frame>]) -> Self {
+        let base = ranges.iter().map(|r| r.start).min().unwrap();
+        let end = ranges.iter().map(|r| r.end).max().unwrap();
+        let descriptors = (0..Frame::steps_between(&base, &end).unwrap())
+            .map(|_| FrameDescriptor::new())
+            .collect();
+        Self {
+            ranges,
+            base,
+            descriptors,
+        }
+    }
+
+    /// The descriptor of a frame of available memory. `None` for other frames, e.g. device registers.
+    pub fn get(&self, frame: Frame) -> Option<&FrameDescriptor> {
+        if !self.ranges.iter().any(|r| r.contains(&frame)) {
+            return None;
+        }
+        let index = Frame::steps_between(&self.base, &frame)?;
+        self.descriptors.get(index)
+    }
+}
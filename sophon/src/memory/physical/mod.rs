@@ -1,18 +1,25 @@
+mod frame_table;
 mod physical_page_resource;
 
+use self::frame_table::FrameTable;
+pub use self::frame_table::{FrameDescriptor, FrameFlags};
 use self::physical_page_resource::PHYSICAL_PAGE_RESOURCE;
 use super::kernel::KERNEL_MEMORY_MAPPER;
 use core::ops::Range;
 use interrupt::UninterruptibleMutex;
 use memory::{address::P, page::*};
+use proc::ProcId;
+use spin::Once;
 
 pub struct PhysicalMemory {
-    _private: (),
+    frames: Once<FrameTable>,
 }
 
 impl PhysicalMemory {
     pub const fn new() -> Self {
-        Self { _private: () }
+        Self {
+            frames: Once::new(),
+        }
     }
 
     pub fn init(&self, frames: &'static [Range<Frame>]) {
@@ -20,12 +27,80 @@ impl PhysicalMemory {
         KERNEL_MEMORY_MAPPER.init();
     }
 
+    /// Allocate the descriptors of `frames`. Called after the kernel heap is initialized.
+    pub fn init_frame_table(&self, frames: &'static [Range<Frame>]) {
+        self.frames.call_once(|| FrameTable::new(frames));
+    }
+
+    /// Metadata of a frame, or of the first 4K frame of a larger one.
+    /// `None` if the frame is not available memory, e.g. device registers.
+    pub fn descriptor<S: PageSize>(&self, frame: Frame<S>) -> Option<&FrameDescriptor> {
+        self.frames.get()?.get(Frame::new(frame.start()))
+    }
+
+    fn acquire_with_flags<S: PageSize>(&self, flags: FrameFlags) -> Option<Frame<S>> {
+        let frame = {
+            let _guard = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
+            PHYSICAL_PAGE_RESOURCE
+                .lock_uninterruptible()
+                .acquire::<S>()?
+        };
+        if let Some(descriptor) = self.descriptor(frame) {
+            descriptor.init(flags);
+        }
+        Some(frame)
+    }
+
+    /// Allocate a frame for the kernel.
     pub fn acquire<S: PageSize>(&self) -> Option<Frame<S>> {
-        let _guard = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
-        PHYSICAL_PAGE_RESOURCE.lock_uninterruptible().acquire()
+        self.acquire_with_flags(FrameFlags::KERNEL)
     }
 
+    /// Allocate a frame to be mapped in the user space of `owner`.
+    pub fn acquire_user(&self, owner: ProcId) -> Option<Frame> {
+        let frame = self.acquire_with_flags(FrameFlags::USER)?;
+        self.descriptor(frame).unwrap().set_owner(Some(owner));
+        Some(frame)
+    }
+
+    /// Add a reference to a frame, e.g. when it is mapped by one more address space.
+    /// Frames outside the available memory are not counted.
+    pub fn share<S: PageSize>(&self, frame: Frame<S>) {
+        if let Some(descriptor) = self.descriptor(frame) {
+            descriptor.get();
+        }
+    }
+
+    /// Whether the frame is referenced more than once.
+    pub fn is_shared<S: PageSize>(&self, frame: Frame<S>) -> bool {
+        self.descriptor(frame).map_or(false, |d| d.refs() > 1)
+    }
+
+    /// Keep the frame allocated and in place until `unpin`, e.g. during DMA. Pinning holds a reference.
+    pub fn pin<S: PageSize>(&self, frame: Frame<S>) {
+        if let Some(descriptor) = self.descriptor(frame) {
+            descriptor.get();
+            descriptor.insert_flags(FrameFlags::PINNED);
+        }
+    }
+
+    /// Drop the reference held by `pin`.
+    pub fn unpin<S: PageSize>(&self, frame: Frame<S>) {
+        if let Some(descriptor) = self.descriptor(frame) {
+            descriptor.remove_flags(FrameFlags::PINNED);
+            self.release(frame);
+        }
+    }
+
+    /// Drop a reference to a frame. The frame is freed once it is no longer referenced.
+    /// Frames outside the available memory, e.g. mapped device registers, are never freed.
     pub fn release<S: PageSize>(&self, frame: Frame<S>) {
+        if let Some(frames) = self.frames.get() {
+            match frames.get(Frame::new(frame.start())) {
+                Some(descriptor) if descriptor.put() => {}
+                _ => return,
+            }
+        }
         let _guard = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
         PHYSICAL_PAGE_RESOURCE.lock_uninterruptible().release(frame)
     }
@@ -60,3 +135,28 @@ impl<S: PageSize> Drop for SharedPhysicalPage<S> {
         PHYSICAL_MEMORY.release(self.frame);
     }
 }
+
+#[test]
+fn frame_refcount() {
+    let owner = ProcId(usize::MAX);
+    let frame = PHYSICAL_MEMORY.acquire_user(owner).unwrap();
+    let descriptor = PHYSICAL_MEMORY.descriptor(frame).unwrap();
+    assert_eq!(descriptor.refs(), 1);
+    assert_eq!(descriptor.flags(), FrameFlags::USER);
+    assert_eq!(descriptor.owner(), Some(owner));
+    PHYSICAL_MEMORY.share(frame);
+    assert!(PHYSICAL_MEMORY.is_shared(frame));
+    // The first release drops the extra reference only
+    PHYSICAL_MEMORY.release(frame);
+    assert!(!PHYSICAL_MEMORY.is_shared(frame));
+    assert_eq!(descriptor.owner(), Some(owner));
+    // Pinned frames stay allocated after their mappings are released
+    PHYSICAL_MEMORY.pin(frame);
+    assert!(descriptor.flags().contains(FrameFlags::PINNED));
+    PHYSICAL_MEMORY.release(frame);
+    assert_eq!(descriptor.owner(), Some(owner));
+    PHYSICAL_MEMORY.unpin(frame);
+    assert_eq!(descriptor.refs(), 0);
+    assert_eq!(descriptor.owner(), None);
+    assert_eq!(descriptor.flags(), FrameFlags::FREE);
+}
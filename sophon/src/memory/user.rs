@@ -0,0 +1,141 @@
+use super::kernel::KERNEL_MEMORY_MAPPER;
+use super::vma::{handle_copy_on_write, handle_page_fault, Access};
+use super::USER_SPACE_MEMORY_RANGE;
+use crate::arch::{Arch, TargetArch};
+use crate::modules::PROCESS_MANAGER;
+use crate::task::MMState;
+use alloc::borrow::Cow;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::{size_of, zeroed};
+use core::slice;
+use memory::address::Address;
+use memory::page::{PageSize, Size4K};
+use memory::page_table::PageFlags;
+use syscall::user_memory::{copy_from_user, UserData, UserMemory};
+
+/// User memory of the current process
+pub struct CurrentUserMemory;
+
+pub static USER_MEMORY: CurrentUserMemory = CurrentUserMemory;
+
+impl UserMemory for CurrentUserMemory {
+    fn check(&self, start: usize, size: usize, write: bool) -> bool {
+        let end = match start.checked_add(size) {
+            Some(end) => end,
+            None => return false,
+        };
+        if start < USER_SPACE_MEMORY_RANGE.start.as_usize()
+            || end > USER_SPACE_MEMORY_RANGE.end.as_usize()
+        {
+            return false;
+        }
+        let proc = match PROCESS_MANAGER.current_proc() {
+            Some(proc) => proc,
+            None => return false,
+        };
+        let mm = MMState::of(&*proc);
+        let access = if write { Access::Write } else { Access::Read };
+        let mut page = Address::from(start).align_down(Size4K::BYTES);
+        while page.as_usize() < end {
+            let flags = {
+                let _guard = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
+                mm.get_page_table().get_flags(page)
+            };
+            match flags {
+                Some(flags) if flags.contains(PageFlags::USER) => {
+                    if write && flags.contains(PageFlags::NO_WRITE) {
+                        // Copy the page before the kernel writes to it
+                        if !flags.contains(PageFlags::COPY_ON_WRITE)
+                            || !handle_copy_on_write(mm, page)
+                        {
+                            return false;
+                        }
+                    }
+                }
+                Some(_) => return false,
+                // Not touched yet. Populate the page before the kernel accesses it.
+                None => {
+                    if !handle_page_fault(mm, page, access) {
+                        return false;
+                    }
+                }
+            }
+            page = page + Size4K::BYTES;
+        }
+        true
+    }
+
+    fn read(&self, addr: usize, buf: &mut [u8]) -> bool {
+        // Populate the pages first. The copy fails instead of faulting if they are unmapped meanwhile.
+        self.check(addr, buf.len(), false)
+            && unsafe { TargetArch::copy_user(buf.as_mut_ptr(), addr as *const u8, buf.len()) }
+    }
+
+    fn write(&self, addr: usize, data: &[u8]) -> bool {
+        self.check(addr, data.len(), true)
+            && unsafe { TargetArch::copy_user(addr as *mut u8, data.as_ptr(), data.len()) }
+    }
+}
+
+/// Copy `len` elements of user memory at `ptr`.
+pub fn copy_slice<T: UserData>(ptr: usize, len: usize) -> Option<Vec<T>> {
+    let size = len.checked_mul(size_of::<T>())?;
+    let mut data = Vec::new();
+    data.try_reserve_exact(len).ok()?;
+    data.resize(len, unsafe { zeroed::<T>() });
+    let buf = unsafe { slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u8, size) };
+    USER_MEMORY.read(ptr, buf).then_some(data)
+}
+
+/// Copy a user string given the address of its fat pointer.
+fn copy_str(data: usize) -> Option<String> {
+    let [ptr, len] = copy_from_user::<[usize; 2]>(&USER_MEMORY, data)?;
+    String::from_utf8(copy_slice(ptr, len)?).ok()
+}
+
+/// Decode a `&str` syscall argument. Strings of unprivileged callers are copied, as other threads may unmap them.
+pub fn str_arg<const PRIVILEGED: bool>(data: usize) -> Option<Cow<'static, str>> {
+    if PRIVILEGED {
+        Some(Cow::Borrowed(unsafe { *(data as *const &str) }))
+    } else {
+        copy_str(data).map(Cow::Owned)
+    }
+}
+
+/// Decode a `&[&str]` syscall argument. Strings of unprivileged callers are copied.
+pub fn str_array_arg<const PRIVILEGED: bool>(data: usize) -> Option<Vec<Cow<'static, str>>> {
+    if PRIVILEGED {
+        let strs: &[&'static str] = unsafe { *(data as *const &[&str]) };
+        return Some(strs.iter().map(|s| Cow::Borrowed(*s)).collect());
+    }
+    let [ptr, len] = copy_from_user::<[usize; 2]>(&USER_MEMORY, data)?;
+    copy_slice::<[usize; 2]>(ptr, len)?
+        .iter()
+        .map(|&[ptr, len]| {
+            String::from_utf8(copy_slice(ptr, len)?)
+                .ok()
+                .map(Cow::Owned)
+        })
+        .collect()
+}
+
+#[test]
+fn reject_kernel_memory() {
+    let s = "kernel";
+    let s_ptr = &s as *const &str as usize;
+    assert!(!USER_MEMORY.check(s.as_ptr() as usize, s.len(), false));
+    assert!(!USER_MEMORY.check(usize::MAX, 2, false));
+    assert_eq!(str_arg::<true>(s_ptr).as_deref(), Some("kernel"));
+    assert_eq!(str_arg::<false>(s_ptr), None);
+    let mut buf = [0u8; 6];
+    assert!(!USER_MEMORY.read(s.as_ptr() as usize, &mut buf));
+}
+
+#[test]
+fn copy_unmapped_user_memory() {
+    // The fault is caught by the copy routine, instead of panicking the kernel
+    let mut buf = [0u8; 8];
+    let addr = USER_SPACE_MEMORY_RANGE.start.as_usize() as *const u8;
+    assert!(!unsafe { TargetArch::copy_user(buf.as_mut_ptr(), addr, buf.len()) });
+}
@@ -1,17 +1,22 @@
 use core::{iter::Step, ops::Range};
 
-use crate::{memory::kernel::KERNEL_MEMORY_MAPPER, task::MMState};
+use crate::task::MMState;
 
 use super::kernel::KERNEL_MEMORY_RANGE;
 use super::physical::PHYSICAL_MEMORY;
+use super::vma::{VMAKind, VMA};
+use super::USER_SPACE_MEMORY_RANGE;
 use alloc::sync::Arc;
 use atomic::Ordering;
+use interrupt::UninterruptibleMutex;
 use memory::{
     page::{Frame, Page, PageSize, Size1G, Size2M, Size4K},
     page_table::*,
 };
 use proc::Proc;
 
+/// Release a user page table and its lower-level tables, and drop the references of the mapped frames.
+/// Frames still mapped by other address spaces stay allocated.
 pub fn release_user_page_table<L: TableLevel>(page_table: &mut PageTable<L>) {
     for i in 0..512 {
         if L::ID == L4::ID && i >= PageTable::<L4>::get_index(KERNEL_MEMORY_RANGE.start) {
@@ -36,38 +41,30 @@ pub fn release_user_page_table<L: TableLevel>(page_table: &mut PageTable<L>) {
     PHYSICAL_MEMORY.release::<Size4K>(Frame::new(page_table.into()));
 }
 
+/// Grow the heap of a process. The new pages are backed by physical memory on first touch.
+/// Returns `None` if the heap would grow past the user address space.
 pub fn sbrk(proc: Arc<dyn Proc>, num_pages: usize) -> Option<Range<Page<Size4K>>> {
-    let result = MMState::of(&*proc).virtual_memory_highwater.fetch_update(
-        Ordering::SeqCst,
-        Ordering::SeqCst,
-        |old| {
-            let old_aligned = old.align_up(Size4K::BYTES);
-            Some(old_aligned + (num_pages << Size4K::LOG_BYTES))
-        },
+    let mm = MMState::of(&*proc);
+    let mut vmas = mm.vmas.lock_uninterruptible();
+    let start = Page::new(
+        mm.virtual_memory_highwater
+            .load(Ordering::SeqCst)
+            .align_up(Size4K::BYTES),
     );
-    // log!("sbrk: {:?} {:?}", self.id, result);
-    match result {
-        Ok(a) => {
-            let old_top = a;
-            let start = Page::new(a.align_up(Size4K::BYTES));
-            let end = Page::forward(start, num_pages);
-            debug_assert_eq!(old_top, start.start());
-            // Map old_top .. end
-            {
-                let page_table = MMState::of(&*proc).get_page_table();
-                let _guard = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
-                for page in start..end {
-                    let frame = PHYSICAL_MEMORY.acquire().unwrap();
-                    page_table.map(
-                        page,
-                        frame,
-                        PageFlags::user_data_flags_4k(),
-                        &PHYSICAL_MEMORY,
-                    );
-                }
-            }
-            Some(start..end)
-        }
-        Err(_e) => return None,
+    let end = Page::forward_checked(start, num_pages)?;
+    if end.start() > USER_SPACE_MEMORY_RANGE.end {
+        return None;
+    }
+    if start != end {
+        vmas.insert(VMA {
+            range: start..end,
+            flags: PageFlags::user_data_flags_4k(),
+            kind: VMAKind::Heap,
+        })
+        .ok()?;
     }
+    mm.virtual_memory_highwater
+        .store(end.start(), Ordering::SeqCst);
+    // log!("sbrk: {:?} {:?}", proc.id(), start..end);
+    Some(start..end)
 }
@@ -0,0 +1,758 @@
+//! Virtual memory areas of user processes.
+//!
+//! Pages of an area are populated on first touch by `handle_page_fault`, with zeroed frames or the contents of
+//! the mapped file. Files are only mapped privately if they stay in memory, so that no file system is called while
+//! the areas are locked. Shared file mappings are populated when they are mapped.
+//!
+//! After `fork`, the parent and the child share their private frames. The shared pages are mapped read-only with
+//! `COPY_ON_WRITE`, and copied by `handle_copy_on_write` on the first write.
+//!
+//! Each mapping of a frame holds a reference to it, counted by `PHYSICAL_MEMORY`.
+
+use super::kernel::{KERNEL_MEMORY_MAPPER, KERNEL_MEMORY_RANGE};
+use super::physical::{FrameFlags, PHYSICAL_MEMORY};
+use super::USER_SPACE_MEMORY_RANGE;
+use crate::modules::PROCESS_MANAGER;
+use crate::task::MMState;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::iter::Step;
+use core::ops::{Deref, Range};
+use interrupt::UninterruptibleMutex;
+use memory::address::Address;
+use memory::page::{Frame, Page, PageSize, Size4K};
+use memory::page_table::{PageFlags, PageTable, L4};
+use proc::{ProcId, TaskId};
+use syscall::mman::{PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE};
+use syscall::Error;
+use vfs::Node;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VMAKind {
+    /// A segment of the loaded program
+    Image(ImageMapping),
+    /// Grown by `sbrk`
+    Heap,
+    /// Mapped by `mmap`
+    Anonymous,
+    /// Stack of a thread, released when the thread exits. `None` for the main thread.
+    Stack(Option<TaskId>),
+    /// Mapped from a file by `VFSRequest::Mmap`
+    File(FileMapping),
+}
+
+#[derive(Clone)]
+pub struct FileMapping {
+    pub node: Node,
+    /// Offset in the file of the first page of the area
+    pub offset: usize,
+    /// Contents of the file given by `FileSystem::resident`, which private pages are copied from.
+    /// `None` if the pages are the memory of the file itself, given by `FileSystem::shared_frame`.
+    pub contents: Option<&'static [u8]>,
+}
+
+impl FileMapping {
+    pub fn is_shared(&self) -> bool {
+        self.contents.is_none()
+    }
+}
+
+impl PartialEq for FileMapping {
+    fn eq(&self, other: &Self) -> bool {
+        self.node.fs.name() == other.node.fs.name()
+            && self.node.path == other.node.path
+            && self.offset == other.offset
+            && self.is_shared() == other.is_shared()
+    }
+}
+
+impl Eq for FileMapping {}
+
+impl fmt::Debug for FileMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileMapping")
+            .field("path", &self.node.path)
+            .field("offset", &self.offset)
+            .field("shared", &self.is_shared())
+            .finish()
+    }
+}
+
+/// Contents of a loaded executable.
+#[derive(Clone)]
+pub enum ImageData {
+    /// A file that stays in memory, e.g. in the init-fs
+    Resident(&'static [u8]),
+    /// A copy of a file read from a file system
+    Loaded(Arc<[u8]>),
+}
+
+impl Deref for ImageData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Resident(data) => data,
+            Self::Loaded(data) => data,
+        }
+    }
+}
+
+/// A segment of an executable, copied to private pages on first touch.
+#[derive(Clone)]
+pub struct ImageMapping {
+    pub data: ImageData,
+    /// Offset in the file of the first page of the area
+    pub offset: usize,
+    /// Offset in the file of the end of the segment contents. The rest of the area is zeroed.
+    pub file_end: usize,
+}
+
+impl PartialEq for ImageMapping {
+    fn eq(&self, other: &Self) -> bool {
+        self.data.as_ptr() == other.data.as_ptr()
+            && self.offset == other.offset
+            && self.file_end == other.file_end
+    }
+}
+
+impl Eq for ImageMapping {}
+
+impl fmt::Debug for ImageMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImageMapping")
+            .field("offset", &self.offset)
+            .field("file_end", &self.file_end)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VMA {
+    pub range: Range<Page>,
+    /// Flags of the pages mapped in this area
+    pub flags: PageFlags,
+    pub kind: VMAKind,
+}
+
+impl VMA {
+    pub fn contains(&self, a: Address) -> bool {
+        self.range.start.start() <= a && a < self.range.end.start()
+    }
+
+    /// Start of the pages reserved by this area. Stacks keep a guard page below them unmapped.
+    fn reserved_start(&self) -> Page {
+        match self.kind {
+            VMAKind::Stack(_) => Page::backward(self.range.start, 1),
+            _ => self.range.start,
+        }
+    }
+
+    /// Whether the frames mapped in this area are private to it, and are copied on write after `fork`.
+    pub fn is_private(&self) -> bool {
+        !matches!(&self.kind, VMAKind::File(file) if file.is_shared())
+    }
+}
+
+/// Kind of access that raised a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// The areas of a process, indexed by their start page. Areas never overlap.
+#[derive(Clone)]
+pub struct VMAs {
+    areas: BTreeMap<Page, VMA>,
+}
+
+impl VMAs {
+    pub const fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    /// The area containing `a`.
+    pub fn find(&self, a: Address) -> Option<&VMA> {
+        let (_, vma) = self.areas.range(..=Page::containing(a)).next_back()?;
+        Some(vma).filter(|vma| vma.contains(a))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &VMA> {
+        self.areas.values()
+    }
+
+    /// Add an area. It is merged with the area right below it, if they have the same flags and kind.
+    /// File and image areas are never merged.
+    ///
+    /// Fails with `EINVAL` if it is empty, `ENOMEM` if it is outside the user address space,
+    /// or `EEXIST` if it overlaps an existing area or a stack guard page.
+    pub fn insert(&mut self, vma: VMA) -> Result<(), Error> {
+        if vma.range.is_empty() {
+            return Err(Error::EINVAL);
+        }
+        if vma.range.start.start() < USER_SPACE_MEMORY_RANGE.start
+            || vma.range.end.start() > USER_SPACE_MEMORY_RANGE.end
+        {
+            return Err(Error::ENOMEM);
+        }
+        let below = self.areas.range(..vma.range.end).next_back();
+        let above = self.areas.range(vma.range.end..).next();
+        if below.map_or(false, |(_, x)| x.range.end > vma.reserved_start())
+            || above.map_or(false, |(_, x)| x.reserved_start() < vma.range.end)
+        {
+            return Err(Error::EEXIST);
+        }
+        if let Some((_, prev)) = self.areas.range_mut(..vma.range.start).next_back() {
+            if prev.range.end == vma.range.start
+                && prev.flags == vma.flags
+                && prev.kind == vma.kind
+                && !matches!(vma.kind, VMAKind::File(_) | VMAKind::Image(_))
+            {
+                prev.range.end = vma.range.end;
+                return Ok(());
+            }
+        }
+        self.areas.insert(vma.range.start, vma);
+        Ok(())
+    }
+
+    /// Remove the area starting at `start`.
+    pub fn remove(&mut self, start: Page) -> Option<VMA> {
+        self.areas.remove(&start)
+    }
+
+    /// Split the area containing `at`, so that an area starts at `at`.
+    fn split(&mut self, at: Page) {
+        let vma = match self.areas.range_mut(..at).next_back() {
+            Some((_, vma)) if vma.range.end > at => vma,
+            _ => return,
+        };
+        let mut upper = vma.clone();
+        upper.range.start = at;
+        let delta = at.start() - vma.range.start.start();
+        match &mut upper.kind {
+            VMAKind::File(file) => file.offset += delta,
+            VMAKind::Image(image) => image.offset += delta,
+            _ => {}
+        }
+        vma.range.end = at;
+        self.areas.insert(at, upper);
+    }
+
+    /// Remove the parts of the areas inside `range`, and return them.
+    pub fn remove_range(&mut self, range: Range<Page>) -> Vec<VMA> {
+        self.split(range.start);
+        self.split(range.end);
+        let starts: Vec<_> = self.areas.range(range).map(|(start, _)| *start).collect();
+        starts
+            .iter()
+            .map(|start| self.areas.remove(start).unwrap())
+            .collect()
+    }
+
+    /// Change the flags of the areas inside `range`. Fails with `ENOMEM` if a page in `range` is not mapped.
+    /// Areas of device memory stay device memory.
+    pub fn protect(&mut self, range: Range<Page>, flags: PageFlags) -> Result<(), Error> {
+        let mut cursor = range.start;
+        while cursor < range.end {
+            cursor = self.find(cursor.start()).ok_or(Error::ENOMEM)?.range.end;
+        }
+        self.split(range.start);
+        self.split(range.end);
+        for (_, vma) in self.areas.range_mut(range) {
+            vma.flags = if vma.flags.contains(PageFlags::NORMAL_MEMORY) {
+                flags
+            } else {
+                flags & !PageFlags::NORMAL_MEMORY
+            };
+        }
+        Ok(())
+    }
+
+    /// Find the highest free range of `pages` pages below `top`, with a free guard page below it.
+    pub fn find_free_range(&self, top: Page, pages: usize) -> Option<Range<Page>> {
+        let bottom = Page::new(USER_SPACE_MEMORY_RANGE.start);
+        let mut end = match self.areas.range(top..).next() {
+            Some((_, above)) => Page::min(top, above.reserved_start()),
+            None => top,
+        };
+        for vma in self.areas.range(..top).rev().map(|(_, vma)| vma) {
+            if vma.range.end <= end && Page::steps_between(&vma.range.end, &end).unwrap() > pages {
+                break;
+            }
+            end = Page::min(end, vma.reserved_start());
+        }
+        if Page::steps_between(&bottom, &end)? <= pages {
+            return None;
+        }
+        Some(Page::backward(end, pages)..end)
+    }
+}
+
+/// Page flags of a `mman::PROT_*` protection. `None` for unknown protection bits.
+pub fn prot_flags(prot: usize) -> Option<PageFlags> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return None;
+    }
+    let mut flags = PageFlags::user_data_flags_4k();
+    if prot == PROT_NONE {
+        flags = flags & !PageFlags::USER;
+    }
+    if prot & PROT_WRITE == 0 {
+        flags |= PageFlags::NO_WRITE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageFlags::NO_EXEC;
+    }
+    Some(flags)
+}
+
+/// Populate the page containing `a`, if the area holding it permits the access.
+/// Returns `false` if `a` is outside any area (e.g. in a stack guard page), the access is not allowed,
+/// or the page cannot be populated.
+pub fn handle_page_fault(mm: &MMState, a: Address, access: Access) -> bool {
+    let owner = current_owner();
+    let vmas = mm.vmas.lock_uninterruptible();
+    let vma = match vmas.find(a) {
+        Some(vma) => vma,
+        None => return false,
+    };
+    let allowed = match access {
+        _ if !vma.flags.contains(PageFlags::USER) => false,
+        Access::Read => true,
+        Access::Write => !vma.flags.contains(PageFlags::NO_WRITE),
+        Access::Execute => !vma.flags.contains(PageFlags::NO_EXEC),
+    };
+    if !allowed {
+        return false;
+    }
+    let page = Page::<Size4K>::containing(a);
+    let page_table = mm.get_page_table();
+    let _guard = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
+    if page_table.translate(page.start()).is_some() {
+        // Populated by another thread
+        return true;
+    }
+    let offset = page.start() - vma.range.start.start();
+    let contents = match &vma.kind {
+        VMAKind::File(FileMapping {
+            contents: Some(contents),
+            offset: start,
+            ..
+        }) => Some((*contents, start + offset..usize::MAX)),
+        // Shared file mappings are populated when they are mapped
+        VMAKind::File(_) => return false,
+        VMAKind::Image(image) => Some((&*image.data, image.offset + offset..image.file_end)),
+        _ => None,
+    };
+    let frame = match PHYSICAL_MEMORY.acquire_user(owner) {
+        Some(frame) => frame,
+        None => {
+            log!("[kernel] out of memory when populating {:?}", page);
+            return false;
+        }
+    };
+    unsafe { frame.zero() };
+    if let Some((data, range)) = contents {
+        copy_contents(data, range, frame);
+    }
+    if !vma.flags.contains(PageFlags::NO_EXEC) {
+        memory::cache::flush_cache(frame.start()..frame.end());
+    }
+    if access == Access::Write {
+        let descriptor = PHYSICAL_MEMORY.descriptor(frame).unwrap();
+        descriptor.insert_flags(FrameFlags::DIRTY);
+    }
+    page_table.map(page, frame, vma.flags, &PHYSICAL_MEMORY);
+    true
+}
+
+/// The process to attribute newly populated frames to.
+fn current_owner() -> ProcId {
+    PROCESS_MANAGER
+        .current_proc()
+        .map_or(ProcId::NULL, |proc| proc.id())
+}
+
+/// Copy the page of `data` starting at `range.start` to a zeroed `frame`. Bytes past `range.end`, or past the end
+/// of `data`, stay zeroed.
+fn copy_contents(data: &[u8], range: Range<usize>, frame: Frame) {
+    let end = range.end.min(data.len()).min(range.start + Size4K::BYTES);
+    if range.start >= end {
+        return;
+    }
+    // Frames are identity-mapped in the kernel address space
+    unsafe {
+        core::ptr::copy_nonoverlapping::<u8>(
+            &data[range.start],
+            frame.start().as_mut_ptr(),
+            end - range.start,
+        )
+    };
+}
+
+/// Map zeroed pages at `start`, or at an address chosen by the kernel if `start` is `None`.
+pub fn map_anonymous(
+    mm: &MMState,
+    start: Option<Page>,
+    pages: usize,
+    flags: PageFlags,
+) -> Result<Range<Page>, Error> {
+    let mut vmas = mm.vmas.lock_uninterruptible();
+    let range = match start {
+        Some(start) => start..Page::forward(start, pages),
+        None => vmas
+            .find_free_range(Page::new(USER_SPACE_MEMORY_RANGE.end), pages)
+            .ok_or(Error::ENOMEM)?,
+    };
+    vmas.insert(VMA {
+        range: range.clone(),
+        flags,
+        kind: VMAKind::Anonymous,
+    })?;
+    Ok(range)
+}
+
+/// Map `pages` pages of a file, at an address chosen by the kernel.
+///
+/// Fails with `ENODEV` if a shared mapping is larger than the memory of the file.
+pub fn map_file(
+    mm: &MMState,
+    file: FileMapping,
+    pages: usize,
+    flags: PageFlags,
+) -> Result<Range<Page>, Error> {
+    // The file system is not called while the areas are locked. Find the shared frames first.
+    let frames = if file.is_shared() {
+        (0..pages)
+            .map(|i| {
+                let offset = file.offset + (i << Size4K::LOG_BYTES);
+                file.node.fs.shared_frame(&file.node, offset)
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or(Error::ENODEV)?
+    } else {
+        vec![]
+    };
+    let mut vmas = mm.vmas.lock_uninterruptible();
+    let range = vmas
+        .find_free_range(Page::new(USER_SPACE_MEMORY_RANGE.end), pages)
+        .ok_or(Error::ENOMEM)?;
+    vmas.insert(VMA {
+        range: range.clone(),
+        flags,
+        kind: VMAKind::File(file),
+    })?;
+    let page_table = mm.get_page_table();
+    let _guard = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
+    for (page, frame) in range.clone().zip(frames) {
+        PHYSICAL_MEMORY.share(frame);
+        page_table.map(page, frame, flags, &PHYSICAL_MEMORY);
+    }
+    Ok(range)
+}
+
+/// Map a segment of an executable, to be populated on first touch.
+pub fn map_image(
+    mm: &MMState,
+    image: ImageMapping,
+    pages: Range<Page>,
+    flags: PageFlags,
+) -> Result<(), Error> {
+    mm.vmas.lock_uninterruptible().insert(VMA {
+        range: pages,
+        flags,
+        kind: VMAKind::Image(image),
+    })
+}
+
+/// Unmap the pages in `range`, and release the frames populated in them. Returns the removed areas.
+pub fn unmap(mm: &MMState, range: Range<Page>) -> Vec<VMA> {
+    let mut vmas = mm.vmas.lock_uninterruptible();
+    let removed = vmas.remove_range(range);
+    let page_table = mm.get_page_table();
+    let _guard = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
+    let mut frames = vec![];
+    for vma in &removed {
+        for page in vma.range.clone() {
+            if let Some(frame) = page_table.translate(page.start()) {
+                page_table.unmap(page, &PHYSICAL_MEMORY);
+                PageTable::invalidate_tlb(page);
+                frames.push(Frame::<Size4K>::new(frame));
+            }
+        }
+    }
+    // Released only after the stale translations are invalidated on all cores
+    for frame in frames {
+        PHYSICAL_MEMORY.release(frame);
+    }
+    removed
+}
+
+/// Change the flags of the pages in `range`, including the populated ones.
+/// Copy-on-write pages stay read-only until they are copied.
+pub fn protect(mm: &MMState, range: Range<Page>, flags: PageFlags) -> Result<(), Error> {
+    let mut vmas = mm.vmas.lock_uninterruptible();
+    vmas.protect(range.clone(), flags)?;
+    let page_table = mm.get_page_table();
+    let _guard = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
+    for page in range {
+        let flags = vmas.find(page.start()).unwrap().flags;
+        match page_table.get_flags(page.start()) {
+            Some(old) if old.contains(PageFlags::COPY_ON_WRITE) => {
+                page_table.update_flags(page.start(), copy_on_write(flags))
+            }
+            _ => page_table.update_flags(page.start(), flags),
+        };
+    }
+    Ok(())
+}
+
+/// Flags of a page shared copy-on-write, in an area with `flags`.
+fn copy_on_write(flags: PageFlags) -> PageFlags {
+    flags | PageFlags::NO_WRITE | PageFlags::COPY_ON_WRITE
+}
+
+/// Resolve a write to a copy-on-write page containing `a`: copy the page if its frame is still shared,
+/// or make it writable again otherwise.
+/// Returns `false` if the page is not copy-on-write, or its area does not permit writes.
+pub fn handle_copy_on_write(mm: &MMState, a: Address) -> bool {
+    let owner = current_owner();
+    let vmas = mm.vmas.lock_uninterruptible();
+    let vma = match vmas.find(a) {
+        Some(vma) if vma.flags.contains(PageFlags::USER) => vma,
+        _ => return false,
+    };
+    if vma.flags.contains(PageFlags::NO_WRITE) {
+        return false;
+    }
+    let page = Page::<Size4K>::containing(a);
+    let page_table = mm.get_page_table();
+    let _guard = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
+    match page_table.get_flags(page.start()) {
+        Some(flags) if flags.contains(PageFlags::COPY_ON_WRITE) => {}
+        // Resolved by another thread
+        Some(flags) => return !flags.contains(PageFlags::NO_WRITE),
+        None => return false,
+    }
+    let frame = Frame::<Size4K>::new(page_table.translate(page.start()).unwrap());
+    if !PHYSICAL_MEMORY.is_shared(frame) {
+        // The other address spaces have dropped the frame
+        page_table.update_flags(page.start(), vma.flags);
+        return true;
+    }
+    let copy = match PHYSICAL_MEMORY.acquire_user(owner) {
+        Some(frame) => frame,
+        None => {
+            log!("[kernel] out of memory when copying {:?}", page);
+            return false;
+        }
+    };
+    unsafe {
+        core::ptr::copy_nonoverlapping::<u8>(
+            frame.start().as_ptr(),
+            copy.start().as_mut_ptr(),
+            Size4K::BYTES,
+        )
+    };
+    PHYSICAL_MEMORY
+        .descriptor(copy)
+        .unwrap()
+        .insert_flags(FrameFlags::DIRTY);
+    page_table.map(page, copy, vma.flags, &PHYSICAL_MEMORY);
+    PageTable::invalidate_tlb(page);
+    PHYSICAL_MEMORY.release(frame);
+    true
+}
+
+/// Duplicate the user address space of `mm` for a forked process. Returns the page table and areas of the copy.
+///
+/// Both address spaces reference the mapped frames. Private frames are mapped copy-on-write in both of them,
+/// and shared file mappings (e.g. device registers) map the same memory in the copy.
+pub fn fork(mm: &MMState) -> (&'static mut PageTable, VMAs) {
+    let vmas = mm.vmas.lock_uninterruptible();
+    let page_table = mm.get_page_table();
+    let _guard = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
+    let child = PageTable::alloc(&PHYSICAL_MEMORY);
+    // Map kernel pages
+    let index = PageTable::<L4>::get_index(KERNEL_MEMORY_RANGE.start);
+    child[index] = page_table[index].clone();
+    for vma in vmas.iter() {
+        for page in vma.range.clone() {
+            let (frame, flags) = match page_table.translate(page.start()) {
+                Some(frame) => (
+                    Frame::new(frame),
+                    page_table.get_flags(page.start()).unwrap(),
+                ),
+                None => continue,
+            };
+            PHYSICAL_MEMORY.share(frame);
+            let flags = if vma.is_private() {
+                page_table.update_flags(page.start(), copy_on_write(flags));
+                copy_on_write(flags)
+            } else {
+                flags
+            };
+            child.map(page, frame, flags, &PHYSICAL_MEMORY);
+        }
+    }
+    // The parent's TLB entries of the pages made read-only are flushed when `_guard` is dropped
+    (child, vmas.clone())
+}
+
+#[test]
+fn vma_layout() {
+    let base = Page::new(USER_SPACE_MEMORY_RANGE.start);
+    let page = |i| Page::forward(base, i);
+    let vma = |start, end, kind| VMA {
+        range: page(start)..page(end),
+        flags: PageFlags::user_data_flags_4k(),
+        kind,
+    };
+    let mut vmas = VMAs::new();
+    vmas.insert(vma(0, 4, VMAKind::Heap)).unwrap();
+    // Adjacent heap areas are merged
+    vmas.insert(vma(4, 8, VMAKind::Heap)).unwrap();
+    assert_eq!(vmas.iter().count(), 1);
+    assert_eq!(vmas.insert(vma(7, 9, VMAKind::Heap)), Err(Error::EEXIST));
+    // Areas must not grow past the user address space
+    let top = Page::new(USER_SPACE_MEMORY_RANGE.end);
+    let past_top = VMA {
+        range: Page::backward(top, 1)..Page::forward(top, 1),
+        flags: PageFlags::user_data_flags_4k(),
+        kind: VMAKind::Heap,
+    };
+    assert_eq!(vmas.insert(past_top), Err(Error::ENOMEM));
+    vmas.insert(vma(20, 30, VMAKind::Stack(None))).unwrap();
+    assert!(vmas.find(page(7).start() + 8usize).is_some());
+    assert!(vmas.find(page(8).start()).is_none());
+    // The guard page below a stack stays unmapped
+    assert_eq!(vmas.insert(vma(8, 20, VMAKind::Heap)), Err(Error::EEXIST));
+    // Stacks are allocated downwards, with a guard page below each of them
+    assert_eq!(vmas.find_free_range(page(20), 4), Some(page(15)..page(19)));
+    assert_eq!(vmas.find_free_range(page(20), 10), Some(page(9)..page(19)));
+    assert_eq!(vmas.find_free_range(page(20), 11), None);
+    assert_eq!(vmas.find_free_range(page(40), 9), Some(page(31)..page(40)));
+    assert_eq!(vmas.find_free_range(page(40), 10), Some(page(9)..page(19)));
+    // Partial unmapping and protection split the areas
+    let removed = vmas.remove_range(page(2)..page(4));
+    assert_eq!(removed, [vma(2, 4, VMAKind::Heap)]);
+    assert!(vmas.find(page(2).start()).is_none());
+    let read_only = PageFlags::user_data_flags_4k() | PageFlags::NO_WRITE;
+    assert_eq!(
+        vmas.protect(page(1)..page(3), read_only),
+        Err(Error::ENOMEM)
+    );
+    vmas.protect(page(5)..page(6), read_only).unwrap();
+    let flags: Vec<_> = vmas
+        .iter()
+        .map(|vma| (vma.range.clone(), vma.flags))
+        .collect();
+    assert_eq!(flags.len(), 5);
+    assert_eq!(flags[2], (page(5)..page(6), read_only));
+}
+
+#[test]
+fn file_vma_split() {
+    use crate::modules::{module_call, VFS};
+    use vfs::{Fd, VFSRequest};
+    let fd = Fd(module_call("vfs", true, &VFSRequest::Open("/etc/hello.txt")) as _);
+    let node = VFS.file_node(fd).unwrap();
+    module_call("vfs", true, &VFSRequest::Close(fd));
+    // Init-fs files are used in place
+    let data = node.fs.resident(&node).unwrap();
+    assert_eq!(data, b"Hello world from file!");
+    let base = Page::new(USER_SPACE_MEMORY_RANGE.start);
+    let page = |i| Page::forward(base, i);
+    let file = |offset| {
+        VMAKind::File(FileMapping {
+            node: node.clone(),
+            offset,
+            contents: Some(data),
+        })
+    };
+    let mut vmas = VMAs::new();
+    let flags = PageFlags::user_data_flags_4k();
+    for (start, end) in [(0, 4), (4, 8)] {
+        vmas.insert(VMA {
+            range: page(start)..page(end),
+            flags,
+            kind: file(0),
+        })
+        .unwrap();
+    }
+    // File areas are not merged, as their offsets may not be contiguous
+    assert_eq!(vmas.iter().count(), 2);
+    assert!(vmas.iter().all(|vma| vma.is_private()));
+    // Splitting an area keeps the offsets of its pages
+    vmas.remove_range(page(1)..page(2));
+    let upper = vmas.find(page(2).start()).unwrap();
+    assert_eq!(upper.range, page(2)..page(4));
+    assert_eq!(upper.kind, file(2 * Size4K::BYTES));
+}
+
+#[test]
+fn fork_copy_on_write() {
+    let new_mm = || {
+        let mm = MMState::new().downcast::<MMState>().unwrap();
+        let _guard = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
+        let page_table = PageTable::alloc(&PHYSICAL_MEMORY);
+        let index = PageTable::<L4>::get_index(KERNEL_MEMORY_RANGE.start);
+        page_table[index] = PageTable::get()[index].clone();
+        mm.page_table
+            .store(page_table, core::sync::atomic::Ordering::SeqCst);
+        mm
+    };
+    let mapping = |mm: &MMState, a: Address| {
+        let _guard = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
+        let page_table = mm.get_page_table();
+        let frame = Frame::<Size4K>::new(page_table.translate(a).unwrap());
+        (frame, page_table.get_flags(a).unwrap())
+    };
+    let refs = |frame| PHYSICAL_MEMORY.descriptor(frame).unwrap().refs();
+    let parent = new_mm();
+    let flags = PageFlags::user_data_flags_4k();
+    let a = map_anonymous(&parent, None, 1, flags)
+        .unwrap()
+        .start
+        .start();
+    assert!(handle_page_fault(&parent, a, Access::Write));
+    let (frame, _) = mapping(&parent, a);
+    // Frames are identity-mapped in the kernel address space
+    let read = |frame: Frame| {
+        let _guard = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
+        unsafe { frame.start().as_ptr::<usize>().read() }
+    };
+    {
+        let _guard = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
+        unsafe { frame.start().as_mut_ptr::<usize>().write(42) };
+    }
+    // Both address spaces map the frame read-only
+    let child = parent.fork();
+    for mm in [&parent, &child] {
+        let (f, flags) = mapping(mm, a);
+        assert_eq!(f, frame);
+        assert!(flags.contains(PageFlags::COPY_ON_WRITE | PageFlags::NO_WRITE));
+    }
+    assert_eq!(refs(frame), 2);
+    // A write by the child copies the page
+    assert!(handle_copy_on_write(&child, a));
+    let (copy, flags) = mapping(&child, a);
+    assert_ne!(copy, frame);
+    assert!(!flags.contains(PageFlags::NO_WRITE));
+    assert_eq!(read(copy), 42);
+    assert_eq!(refs(frame), 1);
+    // The parent holds the last reference, and writes to the frame in place
+    assert!(handle_copy_on_write(&parent, a));
+    let (f, flags) = mapping(&parent, a);
+    assert_eq!(f, frame);
+    assert!(!flags.contains(PageFlags::NO_WRITE));
+    assert!(!flags.contains(PageFlags::COPY_ON_WRITE));
+}
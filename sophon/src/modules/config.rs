@@ -0,0 +1,152 @@
+//! Boot-time module loading, driven by `/etc/modules.conf` in the init-fs.
+
+use alloc::vec::Vec;
+use kernel_module::ModuleInfo;
+use syscall::Error;
+use vfs::ramfs::RamFS;
+
+/// Paths of the modules to load, one per line. `#` starts a comment.
+pub const MODULES_CONF: &str = "/etc/modules.conf";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigError<'a> {
+    /// A listed file is missing, or has no module metadata.
+    BadModule(&'a str),
+    /// No listed module has the given name, or provides the given service.
+    MissingDependency {
+        module: &'a str,
+        dependency: &'a str,
+    },
+    /// The modules depend on each other.
+    Cycle(Vec<&'a str>),
+    /// `register` failed, e.g. two modules have the same name.
+    Register { module: &'a str, error: Error },
+}
+
+fn parse_config(conf: &str) -> impl Iterator<Item = &str> {
+    conf.lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+}
+
+/// Order the modules so each one comes after its dependencies.
+/// Otherwise, modules keep the order they are listed in.
+fn sort<'a>(modules: &[ModuleInfo<'a>]) -> Result<Vec<usize>, ConfigError<'a>> {
+    // Providers of each module's dependencies
+    let mut depends = Vec::with_capacity(modules.len());
+    for m in modules {
+        let mut providers = Vec::new();
+        for dep in &m.depends {
+            let len = providers.len();
+            providers.extend((0..modules.len()).filter(|i| modules[*i].satisfies(dep)));
+            if providers.len() == len {
+                return Err(ConfigError::MissingDependency {
+                    module: m.name,
+                    dependency: dep,
+                });
+            }
+        }
+        depends.push(providers);
+    }
+    let mut loaded = alloc::vec![false; modules.len()];
+    let mut order = Vec::with_capacity(modules.len());
+    while order.len() < modules.len() {
+        let next = (0..modules.len())
+            .find(|i| !loaded[*i] && depends[*i].iter().all(|d| loaded[*d]))
+            .ok_or_else(|| {
+                let remaining = (0..modules.len()).filter(|i| !loaded[*i]);
+                ConfigError::Cycle(remaining.map(|i| modules[i].name).collect())
+            })?;
+        loaded[next] = true;
+        order.push(next);
+    }
+    Ok(order)
+}
+
+/// Load the modules listed in `/etc/modules.conf`, in dependency order.
+pub fn load_boot_modules(initfs: &RamFS) -> Result<(), ConfigError> {
+    let conf = initfs
+        .get(MODULES_CONF)
+        .and_then(|e| e.as_file())
+        .and_then(|f| core::str::from_utf8(f).ok())
+        .ok_or(ConfigError::BadModule(MODULES_CONF))?;
+    let mut files = Vec::new();
+    let mut infos = Vec::new();
+    for path in parse_config(conf) {
+        let elf = initfs
+            .get(path)
+            .and_then(|e| e.as_file())
+            .ok_or(ConfigError::BadModule(path))?;
+        let info = elf_loader::find_section(elf, ModuleInfo::NOTE_SECTION)
+            .and_then(ModuleInfo::parse)
+            .ok_or(ConfigError::BadModule(path))?;
+        files.push(elf);
+        infos.push(info);
+    }
+    for i in sort(&infos)? {
+        log!(
+            "[kernel]  - load module '{}' v{}",
+            infos[i].name,
+            infos[i].version
+        );
+        super::register(infos[i].name, files[i].to_vec()).map_err(|error| {
+            ConfigError::Register {
+                module: infos[i].name,
+                error,
+            }
+        })?;
+    }
+    Ok(())
+}
+
+#[test]
+fn module_dependency_order() {
+    use alloc::vec;
+    let info = |name, depends, provides| ModuleInfo {
+        abi_version: kernel_module::SERVICE_ABI_VERSION,
+        service_table_size: 0,
+        name,
+        version: "0.1.0",
+        depends,
+        provides,
+    };
+    let modules = [
+        info("pl011", vec!["dev", "interrupt_controller"], vec![]),
+        info("hello", vec![], vec![]),
+        info("dev", vec!["vfs"], vec![]),
+        info("gic", vec![], vec!["interrupt_controller"]),
+        info("vfs", vec![], vec!["vfs"]),
+    ];
+    let order = sort(&modules).unwrap();
+    let names: Vec<_> = order.iter().map(|i| modules[*i].name).collect();
+    assert_eq!(names, ["hello", "gic", "vfs", "dev", "pl011"]);
+    assert_eq!(
+        sort(&modules[..3]),
+        Err(ConfigError::MissingDependency {
+            module: "pl011",
+            dependency: "interrupt_controller"
+        })
+    );
+    let cycle = [info("a", vec!["b"], vec![]), info("b", vec!["a"], vec![])];
+    assert_eq!(sort(&cycle), Err(ConfigError::Cycle(vec!["a", "b"])));
+}
+
+#[test]
+fn module_info_note() {
+    let initfs = unsafe { &*crate::INIT_FS.unwrap() };
+    let elf = initfs
+        .get("/etc/modules/libdev.so")
+        .unwrap()
+        .as_file()
+        .unwrap();
+    let note = elf_loader::find_section(elf, ModuleInfo::NOTE_SECTION).unwrap();
+    let info = ModuleInfo::parse(note).unwrap();
+    assert_eq!(info.abi_version, kernel_module::SERVICE_ABI_VERSION);
+    assert_eq!(
+        info.service_table_size as usize,
+        core::mem::size_of::<kernel_module::KernelServiceTable>()
+    );
+    assert_eq!(info.name, "dev");
+    assert_eq!(info.depends, ["vfs"]);
+    assert!(info.provides.is_empty());
+}
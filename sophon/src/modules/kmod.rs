@@ -0,0 +1,85 @@
+//! The module manager. Loads, unloads and lists modules at runtime.
+
+use super::MODULES;
+use alloc::{borrow::ToOwned, string::String};
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+use kernel_module::{ModuleCallHandler, ModuleInfo};
+use syscall::module_calls::kmod::KmodRequest;
+use syscall::{CallContext, Error, ModuleRequest, RawModuleRequest};
+
+struct ModuleManager;
+
+impl ModuleCallHandler for ModuleManager {
+    fn handle<'a>(&self, ctx: &CallContext, raw: RawModuleRequest<'a>) -> isize {
+        if !ctx
+            .credentials
+            .can(KmodRequest::required_capabilities(raw.id()))
+        {
+            return Error::EPERM.into();
+        }
+        let result = match KmodRequest::from_raw(raw) {
+            Ok(KmodRequest::Load(path)) => load(path),
+            Ok(KmodRequest::Unload(name)) => super::unload(name).map(|_| 0),
+            Ok(KmodRequest::List(buf)) => list(buf),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(x) => x as _,
+            Err(e) => e.into(),
+        }
+    }
+}
+
+pub fn register_module_manager() {
+    super::register_builtin("kmod", &ModuleManager);
+}
+
+fn load(path: &str) -> Result<usize, Error> {
+    let elf = crate::task::syscall::read_elf(path)?;
+    let name = elf_loader::find_section(&elf, ModuleInfo::NOTE_SECTION)
+        .and_then(ModuleInfo::parse)
+        .ok_or(Error::ENOEXEC)?
+        .name
+        .to_owned();
+    super::register(&name, elf.into_owned())
+}
+
+fn list(buf: &mut [u8]) -> Result<usize, Error> {
+    let mut text = String::new();
+    for (id, m) in MODULES.read().iter().enumerate() {
+        if let Some(m) = m {
+            let calls = m.calls.load(Ordering::Relaxed);
+            writeln!(text, "{} {} {} {}", m.name, id, m.size(), calls).unwrap();
+        }
+    }
+    let out = buf.get_mut(..text.len()).ok_or(Error::ERANGE)?;
+    out.copy_from_slice(text.as_bytes());
+    Ok(text.len())
+}
+
+#[test]
+fn module_manager() {
+    use super::module_call;
+    let mut buf = [0u8; 4096];
+    let len = module_call("kmod", true, &KmodRequest::List(&mut buf));
+    let text = core::str::from_utf8(&buf[..len as usize]).unwrap();
+    assert!(text.lines().any(|l| l.starts_with("vfs ")));
+    assert!(text.lines().any(|l| l.starts_with("kmod ")));
+    assert_eq!(
+        module_call("kmod", true, &KmodRequest::List(&mut [0u8; 4])),
+        Error::ERANGE.code()
+    );
+    let load = |path| module_call("kmod", true, &KmodRequest::Load(path));
+    assert_eq!(load("/etc/no-such-module.so"), Error::ENOENT.code());
+    assert_eq!(load("/etc/modules/libhello.so"), Error::EEXIST.code());
+    assert_eq!(
+        module_call("kmod", true, &KmodRequest::Unload("kmod")),
+        Error::EBUSY.code()
+    );
+    // Only users with `SYS_MODULE` can load and unload modules
+    let user = CallContext::user(syscall::Credentials::user(1000, 1000));
+    let unload = KmodRequest::Unload("hello");
+    let args = unload.as_raw().as_buf();
+    assert_eq!(super::call_module("kmod", &user, args), Error::EPERM.code());
+}
@@ -1,25 +1,48 @@
-use alloc::{borrow::ToOwned, boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+use alloc::{borrow::ToOwned, boxed::Box, collections::BTreeMap, string::String, vec, vec::Vec};
 use core::iter::Step;
-use kernel_module::KernelServiceWrapper;
-use kernel_module::ModuleCallHandler;
-use memory::page::{Page, PageResource, Size4K};
-use spin::RwLock;
-use syscall::RawModuleRequest;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel_module::{KernelServiceTable, KernelServiceWrapper, ModuleInfo, SERVICE_ABI_VERSION};
+use kernel_module::{KernelSymbol, ModuleCallHandler, ModuleDeinitHandler, SYMBOL_SECTION};
+use memory::page::{Page, PageResource, PageSize, Size4K};
+use spin::{Lazy, RwLock};
+use syscall::{user_memory::UserArgs, CallContext, Error, RawModuleRequest};
 
+use crate::arch::{Arch, TargetArch};
 use crate::memory::kernel::KERNEL_HEAP;
+use crate::memory::user::USER_MEMORY;
 
 use self::services::KernelService;
 
+mod config;
+mod kmod;
 mod named_modules;
 mod services;
+mod symbols;
 
+pub use config::load_boot_modules;
+pub use kmod::register_module_manager;
 pub use named_modules::{INTERRUPT, PROCESS_MANAGER, SCHEDULER, TIMER, VFS};
+pub use symbols::lookup as lookup_symbol;
 
 struct KernelModule {
-    _name: String,
+    name: String,
     _service: Box<KernelService>,
-    _deinit: Option<extern "C" fn()>,
+    /// Passed to the module. Refers to `_service`.
+    _service_table: Box<KernelServiceTable>,
+    deinit: Option<&'static dyn ModuleDeinitHandler>,
     call: Option<&'static dyn ModuleCallHandler>,
+    /// Number of module calls currently executing in this module
+    active_calls: AtomicUsize,
+    /// Number of module calls since the module is loaded
+    calls: AtomicUsize,
+    /// Pages holding the loaded ELF segments
+    image: Vec<Range<Page>>,
+    irqs: Vec<usize>,
+    /// Whether this module installed the current system logger
+    sys_logger: bool,
+    /// Ids of the modules whose symbols this module uses
+    uses: Vec<usize>,
     _elf: Vec<u8>,
 }
 
@@ -29,69 +52,330 @@ static MODULES: RwLock<[Option<Box<KernelModule>>; MAX_MODULES]> = {
     RwLock::new([UNINIT; MAX_MODULES])
 };
 static MODULE_NAMES: RwLock<BTreeMap<String, usize>> = RwLock::new(BTreeMap::new());
+/// The IRQ whose handler each core is running, plus one. `0` if none.
+static RUNNING_IRQS: Lazy<Vec<AtomicUsize>> = Lazy::new(|| {
+    (0..TargetArch::num_cores())
+        .map(|_| AtomicUsize::new(0))
+        .collect()
+});
 
-fn load_elf(
-    elf_data: &[u8],
-) -> (
-    extern "C" fn(kernel_module::KernelServiceWrapper) -> usize,
-    &[extern "C" fn()],
-) {
-    let entry = elf_loader::ELFLoader::load(elf_data, &mut |pages| {
-        let range = KERNEL_HEAP
-            .acquire_pages::<Size4K>(Page::steps_between(&pages.start, &pages.end).unwrap())
-            .unwrap();
-        // log!("code: {:?}", range);
-        range
+impl KernelModule {
+    /// Bytes of memory holding the module image
+    fn size(&self) -> usize {
+        self.image
+            .iter()
+            .map(|r| Page::steps_between(&r.start, &r.end).unwrap() << Size4K::LOG_BYTES)
+            .sum()
+    }
+}
+
+/// A loaded module image. Its undefined symbols are resolved.
+struct LoadedImage {
+    start: extern "C" fn(kernel_module::KernelServiceWrapper) -> isize,
+    init_array: &'static [extern "C" fn()],
+    /// Symbols exported by the module
+    exports: &'static [KernelSymbol],
+    image: Vec<Range<Page>>,
+    /// Ids of the modules whose symbols are used
+    uses: Vec<usize>,
+}
+
+fn load_elf(elf_data: &[u8]) -> Result<LoadedImage, Error> {
+    let mut image = vec![];
+    let mut uses = vec![];
+    let entry = elf_loader::ELFLoader::load_with_symbols(
+        elf_data,
+        &mut |pages| {
+            let range = KERNEL_HEAP
+                .acquire_pages::<Size4K>(Page::steps_between(&pages.start, &pages.end).unwrap())
+                .unwrap();
+            // log!("code: {:?}", range);
+            image.push(range.clone());
+            range
+        },
+        &mut |name| {
+            let (address, module) = symbols::lookup(name)?;
+            if let Some(id) = module {
+                if !uses.contains(&id) {
+                    uses.push(id);
+                }
+            }
+            Some(address)
+        },
+    );
+    let entry = match entry {
+        Ok(entry) => entry,
+        Err(_) => {
+            for pages in image {
+                KERNEL_HEAP.release_pages(pages);
+            }
+            return Err(Error::ENOEXEC);
+        }
+    };
+    let exports = match elf_loader::find_section_range(elf_data, SYMBOL_SECTION) {
+        Some(range) => unsafe {
+            core::slice::from_raw_parts(
+                (range.start + entry.vaddr_offset).as_ptr(),
+                (range.end - range.start) / core::mem::size_of::<KernelSymbol>(),
+            )
+        },
+        None => &[],
+    };
+    Ok(LoadedImage {
+        start: unsafe { core::mem::transmute(entry.entry) },
+        init_array: unsafe { core::mem::transmute(entry.init_array) },
+        exports,
+        image,
+        uses,
     })
-    .unwrap();
-    let init_array = unsafe { core::mem::transmute(entry.init_array) };
-    let entry = unsafe { core::mem::transmute(entry.entry) };
-    (entry, init_array)
 }
 
-pub fn register(name: &str, elf: Vec<u8>) {
-    let (start, service_ptr) = {
+/// Refuse modules built against an incompatible service ABI, before running any of their code.
+fn check_abi(name: &str, elf: &[u8]) -> Result<(), Error> {
+    let info =
+        match elf_loader::find_section(elf, ModuleInfo::NOTE_SECTION).and_then(ModuleInfo::parse) {
+            Some(info) => info,
+            None => {
+                log!("[kernel] module '{}' has no module metadata", name);
+                return Err(Error::ENOEXEC);
+            }
+        };
+    let table_size = core::mem::size_of::<KernelServiceTable>();
+    if info.abi_version != SERVICE_ABI_VERSION || info.service_table_size as usize > table_size {
+        log!(
+            "[kernel] module '{}' is built against service ABI v{} ({} bytes), but the kernel provides v{} ({} bytes)",
+            name,
+            info.abi_version,
+            info.service_table_size,
+            SERVICE_ABI_VERSION,
+            table_size
+        );
+        return Err(Error::ENOEXEC);
+    }
+    Ok(())
+}
+
+/// Load and initialize a module, and return its id.
+///
+/// Fails with `EEXIST` if the name or an exported symbol is taken, `ENOEXEC` if the module is built
+/// against an incompatible service ABI or uses undefined symbols, or the error returned by its `init`.
+pub fn register(name: &str, elf: Vec<u8>) -> Result<usize, Error> {
+    let (id, start, table_ptr) = {
         let mut names = MODULE_NAMES.write();
         let mut modules = MODULES.write();
         if names.contains_key(name) {
-            return;
+            return Err(Error::EEXIST);
+        }
+        check_abi(name, &elf)?;
+        let id = modules
+            .iter()
+            .position(|m| m.is_none())
+            .ok_or(Error::ENOMEM)?;
+        let LoadedImage {
+            start,
+            init_array,
+            exports,
+            image,
+            uses,
+        } = load_elf(&elf)?;
+        if let Err(e) = symbols::export(id, exports) {
+            for pages in image {
+                KERNEL_HEAP.release_pages(pages);
+            }
+            return Err(e);
         }
-        let id = names.len();
-        let (start, init_array) = load_elf(&elf);
         let service = box KernelService(id);
-        let service_ptr = service.as_ref() as *const KernelService;
+        let service_table = box KernelServiceTable::new(unsafe { &*(&*service as *const _) });
+        let table_ptr = service_table.as_ref() as *const KernelServiceTable;
         for init in init_array {
             init()
         }
         modules[id] = Some(box KernelModule {
-            _name: name.to_owned(),
+            name: name.to_owned(),
             _service: service,
-            _deinit: None,
+            _service_table: service_table,
+            deinit: None,
             call: None,
+            active_calls: AtomicUsize::new(0),
+            calls: AtomicUsize::new(0),
+            image,
+            irqs: vec![],
+            sys_logger: false,
+            uses,
             _elf: elf,
         });
         names.insert(name.to_owned(), id);
-        (start, service_ptr)
+        (id, start, table_ptr)
+    };
+    let result = start(KernelServiceWrapper::new(unsafe { &*table_ptr }));
+    if let Err(e) = Error::check(result) {
+        // The module found the service table incompatible, or failed to initialize.
+        MODULE_NAMES.write().remove(name);
+        let module = MODULES.write()[id].take().unwrap();
+        free(id, module);
+        return Err(e);
+    }
+    Ok(id)
+}
+
+/// Add a module implemented by the kernel. It has no image, and cannot be unloaded.
+fn register_builtin(name: &str, call: &'static dyn ModuleCallHandler) {
+    let mut names = MODULE_NAMES.write();
+    let mut modules = MODULES.write();
+    assert!(!names.contains_key(name));
+    let id = modules.iter().position(|m| m.is_none()).unwrap();
+    let service = box KernelService(id);
+    let service_table = box KernelServiceTable::new(unsafe { &*(&*service as *const _) });
+    modules[id] = Some(box KernelModule {
+        name: name.to_owned(),
+        _service: service,
+        _service_table: service_table,
+        deinit: None,
+        call: Some(call),
+        active_calls: AtomicUsize::new(0),
+        calls: AtomicUsize::new(0),
+        image: vec![],
+        irqs: vec![],
+        sys_logger: false,
+        uses: vec![],
+        _elf: vec![],
+    });
+    names.insert(name.to_owned(), id);
+}
+
+/// Deinitialize a module and free its memory.
+///
+/// Fails with `EBUSY` if the module is being called, other modules use its symbols, or it refuses to be
+/// unloaded.
+pub fn unload(name: &str) -> Result<(), Error> {
+    // Remove the name first, so that no new calls can reach the module.
+    let (id, deinit) = {
+        let mut names = MODULE_NAMES.write();
+        let modules = MODULES.read();
+        let id = *names.get(name).ok_or(Error::ENOENT)?;
+        let module = modules[id].as_ref().unwrap();
+        if module.active_calls.load(Ordering::SeqCst) != 0 {
+            return Err(Error::EBUSY);
+        }
+        let used = modules.iter().flatten().any(|m| m.uses.contains(&id));
+        if used {
+            return Err(Error::EBUSY);
+        }
+        names.remove(name);
+        (id, module.deinit)
     };
-    start(KernelServiceWrapper::from_service(unsafe { &*service_ptr }));
+    if let Err(e) = deinit.map_or(Err(Error::EBUSY), |d| d.deinit()) {
+        MODULE_NAMES.write().insert(name.to_owned(), id);
+        return Err(e);
+    }
+    let module = MODULES.write()[id].take().unwrap();
+    free(id, module);
+    Ok(())
+}
+
+/// Release the resources the kernel holds for a removed module.
+fn free(id: usize, module: Box<KernelModule>) {
+    symbols::remove(id);
+    // IRQ handlers and the logger live in the module image. Remove them before it is freed.
+    let handlers = module
+        .irqs
+        .iter()
+        .map(|irq| {
+            INTERRUPT.disable_irq(*irq);
+            INTERRUPT.remove_irq_handler(*irq)
+        })
+        .collect::<Vec<_>>();
+    // Other cores may have fetched a handler before it was removed.
+    let current_core = TargetArch::current_core();
+    for (core, running) in RUNNING_IRQS.iter().enumerate() {
+        if core == current_core {
+            continue;
+        }
+        while module
+            .irqs
+            .contains(&running.load(Ordering::SeqCst).wrapping_sub(1))
+        {
+            core::hint::spin_loop();
+        }
+    }
+    drop(handlers);
+    if module.sys_logger {
+        crate::utils::boot_logger::restore();
+    }
+    for pages in &module.image {
+        KERNEL_HEAP.release_pages(pages.clone());
+    }
+}
+
+/// Run the handler of an IRQ on the current core. `None` if the IRQ has no handler.
+pub fn handle_irq(irq: usize) -> Option<isize> {
+    // Publish the IRQ before fetching the handler, so that `free` waits for it.
+    RUNNING_IRQS[TargetArch::current_core()].store(irq + 1, Ordering::SeqCst);
+    let result = INTERRUPT.get_irq_handler(irq).map(|handler| handler());
+    irq_handler_finished();
+    result
+}
+
+/// Called when the current core leaves an IRQ handler.
+/// Handlers that switch to another task, e.g. the timer handler, never return to `handle_irq`.
+pub fn irq_handler_finished() {
+    RUNNING_IRQS[TargetArch::current_core()].store(0, Ordering::SeqCst);
+}
+
+/// Replace a module with a new build of it.
+pub fn reload(name: &str, elf: Vec<u8>) -> Result<usize, Error> {
+    unload(name)?;
+    register(name, elf)
 }
 
 pub fn raw_module_call(module: &str, privileged: bool, args: [usize; 4]) -> isize {
+    let ctx = if privileged {
+        CallContext::KERNEL
+    } else {
+        CallContext::user(PROCESS_MANAGER.current_proc().unwrap().credentials())
+    };
+    call_module(module, &ctx, args)
+}
+
+fn call_module(module: &str, ctx: &CallContext, args: [usize; 4]) -> isize {
     // log!("module call #{} {:x?}", module, args);
     let _guard = ::interrupt::uninterruptible();
-    let id = *MODULE_NAMES.read().get(module).unwrap();
-    let modules_ptr = MODULES.read()[id]
+    let module_ptr = {
+        // Count the call before releasing the names, so `unload` cannot miss it.
+        let names = MODULE_NAMES.read();
+        let id = match names.get(module) {
+            Some(id) => *id,
+            None => return Error::ENOSYS.into(),
+        };
+        match MODULES.read()[id].as_ref() {
+            Some(m) => {
+                m.active_calls.fetch_add(1, Ordering::SeqCst);
+                m.calls.fetch_add(1, Ordering::Relaxed);
+                m.as_ref() as *const KernelModule
+            }
+            None => return Error::ENOSYS.into(),
+        }
+    };
+    let m = unsafe { &*module_ptr };
+    let result = m
+        .call
         .as_ref()
-        .map(|m| m.as_ref() as *const KernelModule);
-    if let Some(modules_ptr) = modules_ptr {
-        let m = unsafe { &*modules_ptr };
-        m.call
-            .as_ref()
-            .map(|call| call.handle(privileged, RawModuleRequest::from_buf(args)))
-            .unwrap_or(-1)
-    } else {
-        -1
-    }
+        .map(|call| {
+            if ctx.privileged {
+                return call.handle(ctx, RawModuleRequest::from_buf(args));
+            }
+            // Slice arguments are copied in, and the mutable ones written back after the call
+            let user_args = UserArgs::new(&USER_MEMORY);
+            let result = call.handle(ctx, RawModuleRequest::from_user_buf(args, &user_args));
+            if user_args.copy_back() {
+                result
+            } else {
+                Error::EFAULT.code()
+            }
+        })
+        .unwrap_or(Error::ENOSYS.code());
+    m.active_calls.fetch_sub(1, Ordering::SeqCst);
+    result
 }
 
 pub fn module_call<'a>(
@@ -101,3 +385,122 @@ pub fn module_call<'a>(
 ) -> isize {
     raw_module_call(module, privileged, request.as_raw().as_buf())
 }
+
+#[test]
+fn unknown_module_calls() {
+    assert_eq!(
+        raw_module_call("no-such-module", true, [0; 4]),
+        Error::ENOSYS.code()
+    );
+    assert_eq!(
+        raw_module_call("vfs", true, [999, 0, 0, 0]),
+        Error::ENOSYS.code()
+    );
+    // Random request ids and arguments from an unprivileged program
+    let mut status = -1;
+    assert!(::syscall::exec("/bin/modfuzz", &[], &mut status) > 0);
+    assert_eq!(status, 0);
+}
+
+#[test]
+fn module_request_encoding() {
+    use syscall::ModuleRequest;
+
+    #[derive(ModuleRequest, Debug, PartialEq)]
+    enum TestRequest<'a> {
+        Small(usize),
+        Large(usize, &'a str, isize, u32, usize),
+    }
+    let large = TestRequest::Large(1, "two", -3, 4, 5);
+    let raw = large.as_raw();
+    let decoded = TestRequest::from_raw(RawModuleRequest::from_buf(raw.as_buf()));
+    assert_eq!(decoded, Ok(TestRequest::Large(1, "two", -3, 4, 5)));
+    let small = TestRequest::Small(42);
+    let decoded = TestRequest::from_raw(RawModuleRequest::from_buf(small.as_raw().as_buf()));
+    assert_eq!(decoded, Ok(TestRequest::Small(42)));
+    // The spilled arguments of unprivileged callers must be in user memory
+    let user_args = UserArgs::new(&USER_MEMORY);
+    let decoded = TestRequest::from_raw(RawModuleRequest::from_user_buf(raw.as_buf(), &user_args));
+    assert_eq!(decoded, Err(Error::EFAULT));
+}
+
+#[test]
+fn module_call_permissions() {
+    use syscall::{module_calls::proc::ProcRequest, Credentials, ModuleRequest};
+    use vfs::VFSRequest;
+    let mount = VFSRequest::Mount {
+        path: "/mnt",
+        dev: 0,
+        fs: "ramfs",
+    };
+    let args = mount.as_raw().as_buf();
+    // Denied before the arguments are decoded
+    let user = CallContext::user(Credentials::user(1000, 1000));
+    assert_eq!(call_module("vfs", &user, args), Error::EPERM.code());
+    // Root passes the check, but still cannot pass kernel pointers
+    let root = CallContext::user(Credentials::ROOT);
+    assert_eq!(call_module("vfs", &root, args), Error::EFAULT.code());
+    // Processes of other users cannot be moved to the foreground
+    let set_foreground = ProcRequest::SetForeground(0);
+    let args = set_foreground.as_raw().as_buf();
+    assert_eq!(call_module("pm", &user, args), Error::EPERM.code());
+}
+
+#[test]
+fn mount_errors() {
+    use vfs::VFSRequest;
+    let mount = |path, fs| module_call("vfs", true, &VFSRequest::Mount { path, dev: 0, fs });
+    assert_eq!(mount("/mnt", "no-such-fs"), Error::ENODEV.code());
+    assert_eq!(mount("mnt", "devfs"), Error::EINVAL.code());
+    assert_eq!(mount("/no-such-dir/mnt", "devfs"), Error::ENOENT.code());
+    // Already mounted
+    assert_eq!(mount("/dev", "devfs"), Error::EEXIST.code());
+}
+
+#[test]
+fn unload_errors() {
+    assert_eq!(unload("no-such-module"), Err(Error::ENOENT));
+    // vfs does not implement `deinit`, and stays loaded
+    assert_eq!(unload("vfs"), Err(Error::EBUSY));
+    assert!(MODULE_NAMES.read().contains_key("vfs"));
+    assert_eq!(register("vfs", vec![]), Err(Error::EEXIST));
+}
+
+#[test]
+fn unload_and_reload() {
+    let initfs = unsafe { &*crate::INIT_FS.unwrap() };
+    let elf = initfs
+        .get("/etc/modules/libhello.so")
+        .unwrap()
+        .as_file()
+        .unwrap();
+    let id = reload("hello", elf.to_vec()).unwrap();
+    assert_eq!(MODULE_NAMES.read().get("hello"), Some(&id));
+    // The symbols of the new image are exported
+    let (address, module) = symbols::lookup("hello_greeting").unwrap();
+    assert_eq!(module, Some(id));
+    let f: fn() -> &'static str = unsafe { core::mem::transmute(address.as_usize()) };
+    assert_eq!(f(), "Hello, Kernel Module!");
+    unload("hello").unwrap();
+    assert!(!MODULE_NAMES.read().contains_key("hello"));
+    assert!(symbols::lookup("hello_greeting").is_none());
+    register("hello", elf.to_vec()).unwrap();
+}
+
+#[test]
+fn incompatible_abi() {
+    let initfs = unsafe { &*crate::INIT_FS.unwrap() };
+    let elf = initfs
+        .get("/etc/modules/libhello.so")
+        .unwrap()
+        .as_file()
+        .unwrap();
+    // The ABI version is the first word of the note descriptor, after the owner name.
+    let note = elf_loader::find_section(elf, ModuleInfo::NOTE_SECTION).unwrap();
+    let owner_size = u32::from_le_bytes(note[0..4].try_into().unwrap()) as usize;
+    let offset = note.as_ptr() as usize - elf.as_ptr() as usize + ((12 + owner_size + 3) & !3);
+    let mut patched = elf.to_vec();
+    patched[offset..offset + 4].copy_from_slice(&(SERVICE_ABI_VERSION + 1).to_le_bytes());
+    assert_eq!(register("hello-v2", patched), Err(Error::ENOEXEC));
+    assert!(!MODULE_NAMES.read().contains_key("hello-v2"));
+}
@@ -5,6 +5,8 @@ use crate::arch::ArchContext;
 use crate::arch::{Arch, TargetArch};
 use crate::memory::kernel::KERNEL_HEAP;
 use crate::memory::kernel::KERNEL_MEMORY_MAPPER;
+use crate::memory::user::USER_MEMORY;
+use crate::memory::vma;
 use crate::modules::SCHEDULER;
 use crate::task::MMState;
 use crate::utils::testing::Tests;
@@ -14,15 +16,19 @@ use core::any::Any;
 use core::iter::Step;
 use core::ops::Range;
 use device_tree::DeviceTree;
-use kernel_module::ModuleCallHandler;
+use interrupt::IRQHandler;
+use kernel_module::{ModuleCallHandler, ModuleDeinitHandler};
 use log::Logger;
 use memory::page::Frame;
 use memory::page_table::PageFlags;
 use memory::{
     address::Address,
-    page::{Page, Size4K},
+    page::{Page, PageSize, Size4K},
 };
 use proc::TaskId;
+use syscall::mman::{MAP_PRIVATE, MAP_SHARED};
+use syscall::user_memory::UserMemory;
+use syscall::Error;
 
 pub struct KernelService(pub usize);
 
@@ -32,6 +38,11 @@ impl kernel_module::KernelService for KernelService {
     }
 
     fn set_sys_logger(&self, logger: &'static dyn Logger) {
+        for (id, m) in MODULES.write().iter_mut().enumerate() {
+            if let Some(m) = m {
+                m.sys_logger = id == self.0;
+            }
+        }
         log::init(logger)
     }
 
@@ -45,6 +56,12 @@ impl kernel_module::KernelService for KernelService {
         });
     }
 
+    fn register_deinit_handler(&self, handler: &'static dyn ModuleDeinitHandler) {
+        MODULES.write()[self.0].as_mut().map(|m| {
+            m.deinit = Some(handler);
+        });
+    }
+
     fn module_call<'a>(&self, module: &str, request: syscall::RawModuleRequest<'a>) -> isize {
         raw_module_call(module, true, request.as_buf())
     }
@@ -74,7 +91,7 @@ impl kernel_module::KernelService for KernelService {
         if cfg!(sophon_test) {
             TargetArch::halt(-1)
         }
-        syscall::exit();
+        syscall::exit(-1);
     }
 
     fn create_mm_state(&self) -> Box<dyn Any> {
@@ -119,6 +136,13 @@ impl kernel_module::KernelService for KernelService {
         crate::modules::INTERRUPT.set_instance(controller);
     }
 
+    fn set_irq_handler(&self, irq: usize, handler: IRQHandler) {
+        MODULES.write()[self.0].as_mut().map(|m| {
+            m.irqs.push(irq);
+        });
+        crate::modules::INTERRUPT.set_irq_handler(irq, handler);
+    }
+
     fn timer_controller(&self) -> &'static dyn interrupt::TimerController {
         &*crate::modules::TIMER
     }
@@ -128,11 +152,11 @@ impl kernel_module::KernelService for KernelService {
     }
 
     fn num_cores(&self) -> usize {
-        1
+        TargetArch::num_cores()
     }
 
     fn current_core(&self) -> usize {
-        0
+        TargetArch::current_core()
     }
 
     unsafe fn return_to_user(&self, task: TaskId) -> ! {
@@ -158,4 +182,41 @@ impl kernel_module::KernelService for KernelService {
     fn create_task_context(&self) -> Box<dyn Any> {
         box <TargetArch as Arch>::Context::new(crate::task::entry as _, 0 as _)
     }
+
+    fn map_file(
+        &self,
+        node: vfs::Node,
+        offset: usize,
+        size: usize,
+        prot: usize,
+        flags: usize,
+    ) -> Result<usize, Error> {
+        let mut page_flags = vma::prot_flags(prot).ok_or(Error::EINVAL)?;
+        let pages = size.saturating_add(Size4K::MASK) >> Size4K::LOG_BYTES;
+        if pages == 0 || offset & Size4K::MASK != 0 {
+            return Err(Error::EINVAL);
+        }
+        // Private pages are copied from the file when first touched, and only files that stay in memory can be
+        // copied without blocking. Shared pages are the memory of the file, e.g. device registers.
+        let contents = match flags {
+            MAP_SHARED => {
+                page_flags = page_flags & !PageFlags::NORMAL_MEMORY;
+                None
+            }
+            MAP_PRIVATE => Some(node.fs.resident(&node).ok_or(Error::ENODEV)?),
+            _ => return Err(Error::EINVAL),
+        };
+        let file = vma::FileMapping {
+            node,
+            offset,
+            contents,
+        };
+        let proc = PROCESS_MANAGER.current_proc().unwrap();
+        let range = vma::map_file(MMState::of(&*proc), file, pages, page_flags)?;
+        Ok(range.start.start().as_usize())
+    }
+
+    fn user_memory(&self) -> &'static dyn UserMemory {
+        &USER_MEMORY
+    }
 }
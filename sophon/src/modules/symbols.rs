@@ -0,0 +1,80 @@
+//! Symbols exported with `#[export_symbol]`, by the kernel and by loaded modules.
+//!
+//! Undefined symbols of a module are resolved against them when it is loaded.
+
+use alloc::{borrow::ToOwned, collections::BTreeMap, string::String};
+use kernel_module::{export_symbol, KernelSymbol};
+use memory::address::Address;
+use spin::RwLock;
+use syscall::Error;
+
+// Defined by the linker. Only their addresses are used.
+#[allow(improper_ctypes)]
+extern "C" {
+    static __start_sophon_ksymtab: KernelSymbol;
+    static __stop_sophon_ksymtab: KernelSymbol;
+}
+
+/// Symbols exported by loaded modules, and the ids of their modules.
+static MODULE_SYMBOLS: RwLock<BTreeMap<String, (Address, usize)>> = RwLock::new(BTreeMap::new());
+
+fn kernel_symbols() -> &'static [KernelSymbol] {
+    unsafe {
+        let start = &__start_sophon_ksymtab as *const KernelSymbol;
+        let end = &__stop_sophon_ksymtab as *const KernelSymbol;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Version of the running kernel.
+#[export_symbol]
+pub fn kernel_version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+/// Find an exported symbol. Returns its address, and the id of the exporting module if it is not the kernel.
+pub fn lookup(name: &str) -> Option<(Address, Option<usize>)> {
+    if let Some(s) = kernel_symbols().iter().find(|s| s.name == name) {
+        return Some((Address::from(s.address), None));
+    }
+    let symbols = MODULE_SYMBOLS.read();
+    symbols.get(name).map(|(address, id)| (*address, Some(*id)))
+}
+
+/// Add the symbols exported by a module. Fails with `EEXIST`, and adds nothing, if a name is already exported.
+pub(super) fn export(module: usize, exports: &[KernelSymbol]) -> Result<(), Error> {
+    let mut symbols = MODULE_SYMBOLS.write();
+    for (i, s) in exports.iter().enumerate() {
+        let taken = symbols.contains_key(s.name)
+            || exports[..i].iter().any(|x| x.name == s.name)
+            || kernel_symbols().iter().any(|x| x.name == s.name);
+        if taken {
+            log!("[kernel] symbol '{}' is already exported", s.name);
+            return Err(Error::EEXIST);
+        }
+    }
+    for s in exports {
+        symbols.insert(s.name.to_owned(), (Address::from(s.address), module));
+    }
+    Ok(())
+}
+
+/// Remove the symbols exported by an unloaded module.
+pub(super) fn remove(module: usize) {
+    MODULE_SYMBOLS.write().retain(|_, (_, id)| *id != module);
+}
+
+#[test]
+fn symbol_lookup() {
+    let (address, module) = lookup("kernel_version").unwrap();
+    assert_eq!(module, None);
+    let f: fn() -> &'static str = unsafe { core::mem::transmute(address.as_usize()) };
+    assert_eq!(f(), kernel_version());
+    // Exported by the `hello` module
+    let (address, module) = lookup("hello_greeting").unwrap();
+    assert_eq!(module, super::MODULE_NAMES.read().get("hello").copied());
+    let f: fn() -> &'static str = unsafe { core::mem::transmute(address.as_usize()) };
+    assert_eq!(f(), "Hello, Kernel Module!");
+    assert!(lookup("no_such_symbol").is_none());
+    assert_eq!(export(usize::MAX, kernel_symbols()), Err(Error::EEXIST));
+}
@@ -0,0 +1,210 @@
+use crate::arch::{Arch, TargetArch};
+use crate::memory::kernel::KERNEL_MEMORY_MAPPER;
+use crate::memory::user::USER_MEMORY;
+use crate::memory::USER_SPACE_MEMORY_RANGE;
+use crate::modules::{PROCESS_MANAGER, SCHEDULER};
+use crate::task::MMState;
+use alloc::collections::{BTreeMap, VecDeque};
+use core::mem::size_of;
+use core::sync::atomic::AtomicU32;
+use core::time::Duration;
+use memory::address::{Address, P};
+use proc::TaskId;
+use spin::Mutex;
+use syscall::user_memory::UserMemory;
+use syscall::Error;
+
+struct Waiter {
+    task: TaskId,
+    deadline: Option<Duration>,
+}
+
+/// Wait queues of futex words, keyed by physical address.
+/// So threads mapping the same word at different addresses still meet each other.
+pub struct FutexTable {
+    queues: Mutex<BTreeMap<Address<P>, VecDeque<Waiter>>>,
+}
+
+pub static FUTEXES: FutexTable = FutexTable::new();
+
+impl FutexTable {
+    pub const fn new() -> Self {
+        Self {
+            queues: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Physical address of a futex word. User programs can only use words in the user space.
+    pub fn key<const PRIVILEGED: bool>(addr: usize) -> Option<Address<P>> {
+        if addr & (core::mem::align_of::<AtomicU32>() - 1) != 0 {
+            return None;
+        }
+        let addr = Address::from(addr);
+        if USER_SPACE_MEMORY_RANGE.contains(&addr) {
+            // Populate the page, and copy it if it is copy-on-write. Otherwise the next write moves the word
+            // to another frame, and wakers would no longer find the waiters.
+            if !USER_MEMORY.check(addr.as_usize(), size_of::<AtomicU32>(), true) {
+                return None;
+            }
+            let proc = PROCESS_MANAGER.current_proc()?;
+            let page_table = MMState::of(&*proc).get_page_table();
+            let _guard = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
+            page_table.translate(addr)
+        } else if PRIVILEGED {
+            KERNEL_MEMORY_MAPPER.translate(addr)
+        } else {
+            None
+        }
+    }
+
+    /// Block the current task until woken up, if the word read by `load` still holds `expected`.
+    /// Returns 0 when woken up, `EAGAIN` on value mismatch, `EFAULT` if `load` fails, `ETIMEDOUT`, or `EINTR` if
+    /// `interruptible` is set and the process has a pending signal. Other spurious wake-ups return 0.
+    pub fn wait(
+        &self,
+        key: Address<P>,
+        load: impl Fn() -> Option<u32>,
+        expected: u32,
+        timeout: Option<Duration>,
+        interruptible: bool,
+    ) -> isize {
+        let _guard = interrupt::uninterruptible();
+        let task = SCHEDULER.get_current_task_id().unwrap();
+        let deadline = timeout.map(|t| TargetArch::uptime() + t);
+        {
+            let mut queues = self.queues.lock();
+            // Checked under the queue lock, so a concurrent `wake` cannot slip in between.
+            match load() {
+                Some(value) if value == expected => {}
+                Some(_) => return Error::EAGAIN.into(),
+                None => return Error::EFAULT.into(),
+            }
+            queues
+                .entry(key)
+                .or_default()
+                .push_back(Waiter { task, deadline });
+            // A `wake` on another core may run as soon as the lock is released,
+            // before this task switches away. Mark it as sleeping first, so the wake-up is not lost.
+            SCHEDULER.prepare_to_sleep();
+        }
+        // Signals wake up the task after they are queued. One queued before `prepare_to_sleep` may have missed it.
+        if interruptible && PROCESS_MANAGER.signal_pending() {
+            SCHEDULER.wake_up(task);
+        }
+        ::syscall::wait();
+        // `wake` dequeues the waiters it wakes up. Still being queued means the wait timed out or was interrupted.
+        let mut queues = self.queues.lock();
+        let queue = match queues.get_mut(&key) {
+            Some(queue) => queue,
+            None => return 0,
+        };
+        let len = queue.len();
+        queue.retain(|w| w.task != task);
+        let woken = queue.len() == len;
+        if queue.is_empty() {
+            queues.remove(&key);
+        }
+        drop(queues);
+        if woken {
+            0
+        } else if interruptible && PROCESS_MANAGER.signal_pending() {
+            Error::EINTR.into()
+        } else if deadline.map_or(false, |d| d <= TargetArch::uptime()) {
+            Error::ETIMEDOUT.into()
+        } else {
+            0
+        }
+    }
+
+    /// Wake up at most `count` tasks waiting on `key`. Returns the number of woken tasks.
+    pub fn wake(&self, key: Address<P>, count: usize) -> usize {
+        let _guard = interrupt::uninterruptible();
+        let mut queues = self.queues.lock();
+        let queue = match queues.get_mut(&key) {
+            Some(queue) => queue,
+            None => return 0,
+        };
+        let n = usize::min(count, queue.len());
+        for waiter in queue.drain(..n) {
+            SCHEDULER.wake_up(waiter.task);
+        }
+        if queue.is_empty() {
+            queues.remove(&key);
+        }
+        n
+    }
+
+    /// Wake up the tasks whose wait has timed out. They dequeue themselves.
+    pub fn expire_timeouts(&self) {
+        let _guard = interrupt::uninterruptible();
+        let now = TargetArch::uptime();
+        let queues = self.queues.lock();
+        for waiter in queues.values().flatten() {
+            if waiter.deadline.map(|d| d <= now).unwrap_or(false) {
+                SCHEDULER.wake_up(waiter.task);
+            }
+        }
+    }
+}
+
+#[test]
+fn futex_value_mismatch() {
+    use alloc::boxed::Box;
+    use atomic::Ordering;
+    let word = Box::new(AtomicU32::new(1));
+    let key = FutexTable::key::<true>(&*word as *const AtomicU32 as usize).unwrap();
+    // Returns immediately without blocking
+    let load = || Some(word.load(Ordering::SeqCst));
+    assert_eq!(FUTEXES.wait(key, load, 0, None, true), Error::EAGAIN.code());
+    assert_eq!(FUTEXES.wake(key, 1), 0);
+    // Unaligned, and kernel words are not accessible to user programs
+    assert!(FutexTable::key::<true>(&*word as *const AtomicU32 as usize + 1).is_none());
+    assert!(FutexTable::key::<false>(&*word as *const AtomicU32 as usize).is_none());
+}
+
+#[test]
+fn futex_ping_pong() {
+    use ::proc::Runnable;
+    use atomic::Ordering;
+    // Even values are written by the main thread, odd values by the other one.
+    // A lost wake-up blocks one of them forever.
+    const ROUNDS: u32 = 1000;
+    static WORD: AtomicU32 = AtomicU32::new(0);
+
+    fn wait_for(key: Address<P>, value: u32) {
+        loop {
+            let current = WORD.load(Ordering::SeqCst);
+            if current == value {
+                return;
+            }
+            FUTEXES.wait(
+                key,
+                || Some(WORD.load(Ordering::SeqCst)),
+                current,
+                None,
+                false,
+            );
+        }
+    }
+
+    struct Ponger(Address<P>);
+    impl Runnable for Ponger {
+        fn run(&mut self) -> ! {
+            for i in 0..ROUNDS {
+                wait_for(self.0, 2 * i + 1);
+                WORD.store(2 * i + 2, Ordering::SeqCst);
+                FUTEXES.wake(self.0, 1);
+            }
+            ::syscall::thread_exit()
+        }
+    }
+    let key = FutexTable::key::<true>(&WORD as *const AtomicU32 as usize).unwrap();
+    let proc = PROCESS_MANAGER.current_proc().unwrap();
+    let ponger = proc.spawn_task(box Ponger(key));
+    for i in 0..ROUNDS {
+        WORD.store(2 * i + 1, Ordering::SeqCst);
+        FUTEXES.wake(key, 1);
+        wait_for(key, 2 * i + 2);
+    }
+    ponger.wait_for_completion();
+}
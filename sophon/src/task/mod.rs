@@ -1,3 +1,4 @@
+pub mod futex;
 pub mod proc;
 pub mod runnables;
 pub mod syscall;
@@ -1,15 +1,23 @@
 use crate::memory::kernel::KERNEL_MEMORY_MAPPER;
+use crate::memory::vma::{self, VMAs};
 use alloc::boxed::Box;
 use atomic::{Atomic, Ordering};
 use core::any::Any;
 use core::ops::Deref;
+use elf_loader::TLSTemplate;
+use interrupt::UninterruptibleMutex;
 use memory::address::{Address, V};
 use memory::page_table::PageTable;
 use proc::Proc;
+use spin::Mutex;
 
 pub struct MMState {
     pub page_table: Atomic<*mut PageTable>,
     pub virtual_memory_highwater: Atomic<Address<V>>,
+    /// TLS initialization image of the loaded program
+    pub tls_template: Mutex<Option<TLSTemplate>>,
+    /// Mapped areas of the user space
+    pub vmas: Mutex<VMAs>,
 }
 
 impl MMState {
@@ -21,6 +29,8 @@ impl MMState {
                 Atomic::new(PageTable::get())
             },
             virtual_memory_highwater: Atomic::new(crate::memory::USER_SPACE_MEMORY_RANGE.start),
+            tls_template: Mutex::new(None),
+            vmas: Mutex::new(VMAs::new()),
         };
         box x
     }
@@ -32,6 +42,35 @@ impl MMState {
     pub fn of(proc: &dyn Proc) -> &Self {
         proc.mm().downcast_ref().unwrap()
     }
+
+    /// Duplicate the user address space for a forked process. Private pages are shared copy-on-write.
+    pub fn fork(&self) -> Self {
+        let (page_table, vmas) = vma::fork(self);
+        Self {
+            page_table: Atomic::new(page_table),
+            virtual_memory_highwater: Atomic::new(
+                self.virtual_memory_highwater.load(Ordering::SeqCst),
+            ),
+            tls_template: Mutex::new(*self.tls_template.lock_uninterruptible()),
+            vmas: Mutex::new(vmas),
+        }
+    }
+
+    /// Take over the user address space of `mm`, created by `fork`. Called by the forked process.
+    pub fn adopt(&self, mm: Self) {
+        let page_table = mm
+            .page_table
+            .swap(self.page_table.load(Ordering::SeqCst), Ordering::SeqCst);
+        self.page_table.store(page_table, Ordering::SeqCst);
+        self.virtual_memory_highwater.store(
+            mm.virtual_memory_highwater.load(Ordering::SeqCst),
+            Ordering::SeqCst,
+        );
+        *self.tls_template.lock_uninterruptible() = *mm.tls_template.lock_uninterruptible();
+        *self.vmas.lock_uninterruptible() =
+            core::mem::replace(&mut *mm.vmas.lock_uninterruptible(), VMAs::new());
+        // `mm` now holds the initial page table, which is not released
+    }
 }
 
 impl Drop for MMState {
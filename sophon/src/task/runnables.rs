@@ -1,25 +1,33 @@
 use super::MMState;
 use crate::arch::*;
-use crate::memory::kernel::KERNEL_MEMORY_MAPPER;
 use crate::memory::kernel::KERNEL_MEMORY_RANGE;
 use crate::memory::physical::PHYSICAL_MEMORY;
-use crate::modules::PROCESS_MANAGER;
+use crate::memory::vma::{self, handle_page_fault, unmap, Access, VMAKind, VMA};
+use crate::memory::vma::{ImageData, ImageMapping};
+use crate::memory::USER_SPACE_MEMORY_RANGE;
+use crate::modules::{PROCESS_MANAGER, SCHEDULER, VFS};
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
 use alloc::ffi::CString;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use atomic::Ordering;
+use core::any::Any;
 use core::arch::asm;
 use core::iter::Step;
 use core::mem::size_of;
 use core::mem::transmute;
-use core::ptr::copy_nonoverlapping;
+use core::ptr::{copy_nonoverlapping, write_bytes};
+use elf_loader::TLSTemplate;
 use interrupt::UninterruptibleMutex;
 use memory::address::*;
 use memory::page::*;
 use memory::page_table::L4;
 use memory::page_table::{PageFlags, PageTable};
-use proc::Proc;
 use proc::Runnable;
+use proc::{Proc, TaskId};
+use syscall::mman::{PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE};
+use syscall::Error;
 
 /// The idle task.
 ///
@@ -42,102 +50,240 @@ impl Runnable for Idle {
 /// `UserTask` will prepare the stacks and arguments, and switch to usermode.
 pub struct UserTask {
     entry: Option<*const extern "C" fn()>,
+    /// Argument and stack of a secondary thread
+    arg: usize,
+    stack_top: Address,
     args: Option<Vec<CString>>,
-    elf: Option<Vec<u8>>,
+    env: Option<Vec<CString>>,
+    fds: Option<Box<dyn Any>>,
+    elf: Option<Cow<'static, [u8]>>,
+    /// Address space and user registers of a forked process
+    fork: Option<(MMState, Box<dyn Any>)>,
 }
 
 impl UserTask {
-    const USER_STACK_START: Address<V> = Address::new(0x111900000);
-    const USER_STACK_PAGES: usize = 4; // Too many???
-    const USER_STACK_SIZE: usize = Self::USER_STACK_PAGES * Size4K::BYTES;
+    /// Stacks are allocated downwards from the top of the user space, each with a guard page below.
+    const USER_STACK_TOP: Address<V> = USER_SPACE_MEMORY_RANGE.end;
+    /// Stack pages are backed by physical memory on first touch.
+    const MAIN_STACK_PAGES: usize = 256;
+    const THREAD_STACK_PAGES: usize = 16;
+    /// TLS blocks are carved from the top of the stacks, and must leave most of a thread stack free.
+    const MAX_TLS_SIZE: usize = Self::THREAD_STACK_PAGES * Size4K::BYTES / 4;
 
     /// Create a main thread.
-    pub fn new_main(elf: Option<Vec<u8>>, args: Option<Vec<CString>>) -> Self {
+    ///
+    /// `fds` are the file descriptors captured from the parent by `VFSManager::capture_fds`.
+    pub fn new_main(
+        elf: Option<Cow<'static, [u8]>>,
+        args: Option<Vec<CString>>,
+        env: Option<Vec<CString>>,
+        fds: Option<Box<dyn Any>>,
+    ) -> Self {
         Self {
             entry: None,
+            arg: 0,
+            stack_top: Address::ZERO,
             args,
+            env,
+            fds,
             elf,
+            fork: None,
         }
     }
 
-    /// Create a secondary thread
-    pub fn new_companion(entry: *const extern "C" fn(), args: Option<Vec<CString>>) -> Self {
+    /// Create a secondary thread, running `entry(arg)` on the given user stack.
+    /// If `stack_top` is zero, the thread runs on a stack allocated by the kernel.
+    pub fn new_companion(entry: *const extern "C" fn(), arg: usize, stack_top: Address) -> Self {
         Self {
             entry: Some(entry),
-            args,
+            arg,
+            stack_top,
+            args: None,
+            env: None,
+            fds: None,
+            elf: None,
+            fork: None,
+        }
+    }
+
+    /// Create the main thread of a forked process, resuming from the parent's `fork` syscall.
+    ///
+    /// `mm` and `regs` are created by `MMState::fork` and `ArchContext::capture_user_frame`.
+    pub fn new_fork(mm: MMState, regs: Box<dyn Any>, fds: Box<dyn Any>) -> Self {
+        Self {
+            entry: None,
+            arg: 0,
+            stack_top: Address::ZERO,
+            args: None,
+            env: None,
+            fds: Some(fds),
             elf: None,
+            fork: Some((mm, regs)),
         }
     }
 
     /// Spawn a new user process.
-    pub fn spawn_user_process(elf: Vec<u8>, args: &[&str]) -> Arc<dyn Proc> {
+    pub fn spawn_user_process(
+        elf: Cow<'static, [u8]>,
+        args: Vec<CString>,
+        env: Vec<CString>,
+        fds: Option<Box<dyn Any>>,
+    ) -> Arc<dyn Proc> {
         PROCESS_MANAGER.spawn(box UserTask::new_main(
             Some(elf),
-            Some(args.iter().map(|s| CString::new(*s).unwrap()).collect()),
+            Some(args),
+            Some(env),
+            fds,
         ))
     }
 
-    fn setup_user_stack(page_table: &mut PageTable) -> Address {
-        let tid = PROCESS_MANAGER.current_task().unwrap().id();
-        let i = PROCESS_MANAGER
-            .current_proc()
-            .unwrap()
-            .tasks()
+    /// Copy `strs` to the user stack, followed by a null-terminated array of pointers to them.
+    fn push_strings(mut stack_top: Address, strs: &[CString]) -> (Address, *const *const u8) {
+        let mut ptrs: Vec<*const u8> = Vec::with_capacity(strs.len() + 1);
+        for s in strs {
+            let buf = s.to_bytes_with_nul();
+            let ptr = stack_top - buf.len();
+            unsafe { copy_nonoverlapping(buf.as_ptr(), ptr.as_mut_ptr(), buf.len()) };
+            ptrs.push(ptr.as_ptr());
+            stack_top = ptr;
+        }
+        ptrs.push(core::ptr::null());
+        stack_top = stack_top.align_down(size_of::<*const u8>());
+        for ptr in ptrs.into_iter().rev() {
+            stack_top = stack_top - size_of::<*const u8>();
+            unsafe { stack_top.store(ptr) };
+        }
+        (stack_top, stack_top.as_ptr())
+    }
+
+    /// Carve the TLS block of a thread from the top of its stack, and initialize it from the TLS template.
+    /// Returns the new stack top and the thread pointer.
+    /// AArch64 uses TLS variant 1: The thread pointer points to a 16-byte TCB, followed by the TLS block.
+    const TCB_SIZE: usize = 16;
+
+    /// Upper bound of the stack space taken by `setup_tls`.
+    pub fn tls_size(tls: Option<TLSTemplate>) -> usize {
+        tls.map_or(0, |tls| {
+            let align = usize::max(tls.align, Self::TCB_SIZE);
+            let offset = (Self::TCB_SIZE + align - 1) & !(align - 1);
+            offset + tls.mem_size + align - 1
+        })
+    }
+
+    fn setup_tls(stack_top: Address, tls: Option<TLSTemplate>) -> (Address, Address) {
+        let tls = match tls {
+            Some(tls) => tls,
+            None => return (stack_top, Address::ZERO),
+        };
+        let align = usize::max(tls.align, Self::TCB_SIZE);
+        let offset = (Self::TCB_SIZE + align - 1) & !(align - 1);
+        let tp = (stack_top - (offset + tls.mem_size)).align_down(align);
+        let block = tp + offset;
+        unsafe {
+            write_bytes(tp.as_mut_ptr::<u8>(), 0, offset);
+            copy_nonoverlapping(tls.start.as_ptr::<u8>(), block.as_mut_ptr(), tls.file_size);
+            write_bytes(
+                (block + tls.file_size).as_mut_ptr::<u8>(),
+                0,
+                tls.mem_size - tls.file_size,
+            );
+        }
+        (tp, tp)
+    }
+
+    /// Allocate a stack area. Returns the stack top.
+    fn map_stack(mm: &MMState, pages: usize, owner: Option<TaskId>) -> Address {
+        let range = {
+            let mut vmas = mm.vmas.lock_uninterruptible();
+            let range = vmas
+                .find_free_range(Page::new(Self::USER_STACK_TOP), pages)
+                .expect("out of user address space");
+            vmas.insert(VMA {
+                range: range.clone(),
+                flags: PageFlags::user_stack_flags(),
+                kind: VMAKind::Stack(owner),
+            })
+            .unwrap();
+            range
+        };
+        // The kernel initializes the top of the stack before entering the user mode
+        let stack_top = range.end.start();
+        handle_page_fault(mm, stack_top - 1, Access::Write);
+        stack_top
+    }
+
+    /// Release the stack the kernel allocated for a secondary thread.
+    pub fn release_thread_stack(proc: &dyn Proc, task: TaskId) {
+        let mm = MMState::of(proc);
+        let stack = mm
+            .vmas
             .lock_uninterruptible()
             .iter()
-            .position(|t| *t == tid)
-            .unwrap();
-        // println!("User stack #{}", i);
-        let user_stack_start = Self::USER_STACK_START + i * Self::USER_STACK_SIZE;
-        for i in 0..Self::USER_STACK_PAGES {
-            let page = Step::forward(Page::<Size4K>::new(user_stack_start), i);
-            let frame = PHYSICAL_MEMORY.acquire::<Size4K>().unwrap();
-            let _guard = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
-            page_table.map(page, frame, PageFlags::user_stack_flags(), &PHYSICAL_MEMORY);
+            .find(|vma| vma.kind == VMAKind::Stack(Some(task)))
+            .map(|vma| vma.range.clone());
+        if let Some(stack) = stack {
+            unmap(mm, stack);
         }
-        user_stack_start + Self::USER_STACK_SIZE
     }
 }
 
 impl Runnable for UserTask {
     fn run(&mut self) -> ! {
         let proc = PROCESS_MANAGER.current_proc().unwrap();
-        let first_thread = proc.tasks().lock().len() == 1;
-        let entry = if first_thread {
+        if let Some(fds) = self.fds.take() {
+            VFS.install_fds(fds);
+        }
+        if let Some((mm, regs)) = self.fork.take() {
+            MMState::of(&*proc).adopt(mm);
+            let task = PROCESS_MANAGER.current_task().unwrap();
+            let context =
+                <TargetArch as Arch>::Context::of(&*task) as *const <TargetArch as Arch>::Context;
+            drop(task);
+            drop(proc);
+            // The task is kept alive by the scheduler while it is running
+            unsafe { (*context).return_to_forked_user(regs) }
+        }
+        let tls = || *MMState::of(&*proc).tls_template.lock_uninterruptible();
+        let (entry, mut stack_top, tp, arg0, arg1, arg2) = if let Some(entry) = self.entry {
+            // The process is spawning a new thread. The entrypoint, argument and stack are passed by the user program.
+            let entry: UserEntry = unsafe { transmute(entry) };
+            if self.stack_top == Address::ZERO {
+                let task = PROCESS_MANAGER.current_task().unwrap().id();
+                let mm = MMState::of(&*proc);
+                self.stack_top = Self::map_stack(mm, Self::THREAD_STACK_PAGES, Some(task));
+            }
+            let (sp, tp) = Self::setup_tls(self.stack_top, tls());
+            (entry, sp, tp, self.arg as isize, 0 as _, 0 as _)
+        } else {
             // First user thread of the process. Initialize the user space first.
             let initializer = UserProcessInitializer(proc.clone());
-            initializer.initialize_user_space(self.elf.as_ref().unwrap())
-        } else {
-            // The process is spawning a new thread. The entrypoint is passed by the user program.
-            unsafe { transmute(self.entry.unwrap()) }
-        };
-        let page_table = MMState::of(&*proc).get_page_table();
-        // Setup user stack
-        let mut stack_top = Self::setup_user_stack(page_table);
-        // Prepare arguments
-        let (arg0, arg1) = if first_thread {
+            let entry = match initializer.initialize_user_space(self.elf.take().unwrap()) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    // Executables are checked by exec and spawn, so this is rare, e.g. out of memory
+                    log!("[kernel] process #{} failed to load: {}", proc.id().0, e);
+                    proc.exit(Error::ENOEXEC.code());
+                    drop(proc);
+                    SCHEDULER.schedule()
+                }
+            };
+            // Setup user stack, TLS and arguments
+            let stack_top = Self::map_stack(MMState::of(&*proc), Self::MAIN_STACK_PAGES, None);
+            let (stack_top, tp) = Self::setup_tls(stack_top, tls());
+            let env = self.env.as_deref().unwrap_or(&[]);
+            let (sp, envp) = Self::push_strings(stack_top, env);
             let args = self.args.as_ref().unwrap();
-            let argc = args.len();
-            let mut ptrs: Vec<*const u8> = Vec::with_capacity(argc);
-            for arg in args {
-                let buf = arg.to_bytes_with_nul();
-                let ptr = stack_top - buf.len();
-                unsafe { copy_nonoverlapping(buf.as_ptr(), ptr.as_mut_ptr(), buf.len()) };
-                ptrs.push(ptr.as_ptr());
-                stack_top = ptr;
-            }
-            for ptr in ptrs {
-                stack_top = stack_top - size_of::<*const u8>();
-                unsafe { stack_top.store(ptr) };
-            }
-            (argc as isize, stack_top.as_ptr::<*const u8>())
-        } else {
-            // TODO: Pass a context pointer
-            (0, 0 as _)
+            let (sp, argv) = Self::push_strings(sp, args);
+            (entry, sp, tp, args.len() as isize, argv, envp)
         };
+        let page_table = MMState::of(&*proc).get_page_table();
+        // Keep the stack pointer 16-byte aligned
+        stack_top = stack_top.align_down(16);
         // Enter usermode
         unsafe {
-            <TargetArch as Arch>::Context::enter_usermode(entry, stack_top, page_table, arg0, arg1)
+            <TargetArch as Arch>::Context::enter_usermode(
+                entry, stack_top, tp, page_table, arg0, arg1, arg2,
+            )
         }
     }
 }
@@ -145,11 +291,11 @@ impl Runnable for UserTask {
 struct UserProcessInitializer(Arc<dyn Proc>);
 
 impl UserProcessInitializer {
-    fn initialize_user_space(&self, elf: &[u8]) -> extern "C" fn(isize, *const *const u8) {
+    fn initialize_user_space(&self, elf: Cow<'static, [u8]>) -> Result<UserEntry, &'static str> {
         // log!("Initialze user space process");
         debug_assert_eq!(self.0.id(), PROCESS_MANAGER.current_proc().unwrap().id());
         // User page table
-        let page_table = {
+        {
             let page_table = PageTable::alloc(&PHYSICAL_MEMORY);
             // Map kernel pages
             let kernel_memory = KERNEL_MEMORY_RANGE;
@@ -158,39 +304,58 @@ impl UserProcessInitializer {
             page_table[index] = PageTable::get()[index].clone();
             self.set_page_table(unsafe { &mut *(page_table as *mut _) });
             PageTable::set(page_table);
-            page_table
-        };
+        }
         // log!("Load ELF");
-        let entry = self.load_elf(page_table, elf);
-        entry
+        self.load_elf(elf)
     }
 
-    fn load_elf(
-        &self,
-        page_table: &mut PageTable,
-        elf_data: &[u8],
-    ) -> extern "C" fn(isize, *const *const u8) {
-        let base = Address::<V>::from(0x200000);
-        let entry = elf_loader::ELFLoader::load(elf_data, &mut |pages| {
-            let start_page = Page::new(base);
-            let num_pages = Page::steps_between(&pages.start, &pages.end).unwrap();
-            for (i, _) in pages.enumerate() {
-                let page = Page::<Size4K>::forward(start_page, i);
-                let frame = PHYSICAL_MEMORY.acquire::<Size4K>().unwrap();
-                let _kernel_page_table = KERNEL_MEMORY_MAPPER.with_kernel_address_space();
-                page_table.map(
-                    page,
-                    frame,
-                    PageFlags::user_code_flags_4k(),
-                    &PHYSICAL_MEMORY,
-                );
-            }
-            PageTable::set(page_table);
-            start_page..Page::<Size4K>::forward(start_page, num_pages)
-        })
-        .unwrap();
+    /// Map the segments of an executable at the bottom of the user space, to be copied from the file on first
+    /// touch. The heap starts above it.
+    fn load_elf(&self, elf: Cow<'static, [u8]>) -> Result<UserEntry, &'static str> {
+        let base = USER_SPACE_MEMORY_RANGE.start;
+        let mut image_end = base;
+        let data = match elf {
+            Cow::Borrowed(data) => ImageData::Resident(data),
+            Cow::Owned(data) => ImageData::Loaded(data.into()),
+        };
+        let mm = MMState::of(&*self.0);
+        let entry = elf_loader::ELFLoader::load_in_place(
+            &data,
+            &mut |pages| {
+                let start_page = Page::new(base);
+                let num_pages = Page::steps_between(&pages.start, &pages.end).unwrap();
+                let range = start_page..Page::<Size4K>::forward(start_page, num_pages);
+                image_end = range.end.start();
+                range
+            },
+            &mut |segment| {
+                let mut prot = PROT_NONE;
+                if segment.readable {
+                    prot |= PROT_READ;
+                }
+                if segment.writable {
+                    prot |= PROT_WRITE;
+                }
+                if segment.executable {
+                    prot |= PROT_EXEC;
+                }
+                let image = ImageMapping {
+                    data: data.clone(),
+                    offset: segment.offset,
+                    file_end: segment.file_end,
+                };
+                vma::map_image(mm, image, segment.pages, vma::prot_flags(prot).unwrap())
+                    .map_err(|_| "failed to map segment")
+            },
+        )?;
         // log!("Entry: {:?}", entry.entry);
-        unsafe { core::mem::transmute(entry.entry) }
+        if UserTask::tls_size(entry.tls) > UserTask::MAX_TLS_SIZE {
+            return Err("thread-local storage is too large");
+        }
+        *mm.tls_template.lock_uninterruptible() = entry.tls;
+        mm.virtual_memory_highwater
+            .store(image_end, Ordering::SeqCst);
+        Ok(unsafe { core::mem::transmute(entry.entry) })
     }
 
     #[inline]
@@ -200,3 +365,53 @@ impl UserProcessInitializer {
             .store(page_table, Ordering::SeqCst)
     }
 }
+
+#[test]
+fn user_thread_local_storage() {
+    // Checks the `#[thread_local]` statics and thread pointers of several threads
+    let mut status = -1;
+    assert!(::syscall::exec("/bin/tlstest", &[], &mut status) > 0);
+    assert_eq!(status, 0);
+}
+
+#[test]
+fn user_fork() {
+    // Checks that the memory of a forked child is a copy of its parent's, and that futexes on its copy-on-write
+    // pages still meet
+    let mut status = -1;
+    assert!(::syscall::exec("/bin/forktest", &[], &mut status) > 0);
+    assert_eq!(status, 0);
+}
+
+#[test]
+fn kill_blocked_process() {
+    use core::sync::atomic::AtomicU32;
+    use core::time::Duration;
+    use syscall::signal::SIGKILL;
+    // `killtest` blocks in `waitpid` on a child that sleeps for 10 seconds
+    let pid = ::syscall::spawn("/bin/killtest", &[], &[], None);
+    assert!(pid > 0);
+    let word = AtomicU32::new(0);
+    ::syscall::futex_wait(&word, 0, Some(Duration::from_millis(500)));
+    let start = TargetArch::uptime();
+    assert!(PROCESS_MANAGER.send_signal(proc::ProcId(pid as _), SIGKILL));
+    let mut status = -1;
+    assert_eq!(::syscall::waitpid(pid as _, &mut status), pid);
+    assert_eq!(status, 128 + SIGKILL as isize);
+    // Killed while waiting, not after the child exits
+    assert!(TargetArch::uptime() - start < Duration::from_secs(5));
+}
+
+#[test]
+fn reject_malformed_executables() {
+    use elf_loader::check_executable;
+    let elf = crate::task::syscall::read_elf("/bin/hello").unwrap();
+    assert_eq!(check_executable(&elf), Ok(()));
+    // Program headers past the end of the file
+    let mut bad = elf.to_vec();
+    let end = bad.len() as u64;
+    bad[32..40].copy_from_slice(&end.to_le_bytes());
+    assert!(check_executable(&bad).is_err());
+    // Truncated section headers
+    assert!(check_executable(&elf[..elf.len() / 2]).is_err());
+}
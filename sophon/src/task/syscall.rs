@@ -1,10 +1,29 @@
+use super::futex::{FutexTable, FUTEXES};
 use super::runnables::UserTask;
-use crate::arch::Arch;
-use crate::modules::PROCESS_MANAGER;
+use super::MMState;
+use crate::arch::{Arch, ArchContext};
+use crate::memory::user::{copy_slice, str_arg, str_array_arg, USER_MEMORY};
+use crate::memory::vma;
+use crate::memory::USER_SPACE_MEMORY_RANGE;
+use crate::modules::{PROCESS_MANAGER, VFS};
 use crate::{arch::TargetArch, modules::SCHEDULER};
+use alloc::borrow::Cow;
+use alloc::ffi::CString;
 use alloc::vec;
-use memory::page::{PageSize, Size4K};
-use syscall::Syscall;
+use alloc::vec::Vec;
+use core::iter::Step;
+use core::mem::{size_of, transmute};
+use core::ops::Range;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+use interrupt::UninterruptibleMutex;
+use memory::address::Address;
+use memory::page::{Page, PageSize, Size4K};
+use proc::{ProcId, TaskId};
+use syscall::module_calls::proc::ProcRequest;
+use syscall::signal::SIG_SETMASK;
+use syscall::user_memory::{copy_from_user, copy_to_user, UserMemory};
+use syscall::{Error, Syscall, FUTEX_UNINTERRUPTIBLE};
 use vfs::{Fd, VFSRequest};
 
 // =====================
@@ -21,7 +40,7 @@ pub fn handle_syscall<const PRIVILEGED: bool>(
 ) -> isize {
     let syscall: Syscall = unsafe { core::mem::transmute(syscall_id) };
     match syscall {
-        Syscall::Log => log(a, b, c, d, e),
+        Syscall::Log => log::<PRIVILEGED>(a, b, c, d, e),
         Syscall::ModuleCall => module_request::<PRIVILEGED>(a, b, c, d, e),
         Syscall::Wait => {
             SCHEDULER.sleep();
@@ -32,17 +51,30 @@ pub fn handle_syscall<const PRIVILEGED: bool>(
             a >> Size4K::LOG_BYTES,
         )
         .map(|r| r.start.start().as_usize() as isize)
-        .unwrap_or(-1),
-        Syscall::Exec => exec(a, b, c, d, e),
+        .unwrap_or(Error::ENOMEM.code()),
+        Syscall::Exec => exec::<PRIVILEGED>(a, b, c, d, e),
         Syscall::Exit => exit(a, b, c, d, e),
         Syscall::ThreadExit => thread_exit(a, b, c, d, e),
         Syscall::Halt => halt(a, b, c, d, e),
+        Syscall::Spawn => spawn::<PRIVILEGED>(a, b, c, d, e),
+        Syscall::WaitPid => waitpid::<PRIVILEGED>(a, b, c, d, e),
+        Syscall::SigReturn => sigreturn(a, b, c, d, e),
+        Syscall::ThreadSpawn => thread_spawn(a, b, c, d, e),
+        Syscall::ThreadJoin => thread_join(a, b, c, d, e),
+        Syscall::FutexWait => futex_wait::<PRIVILEGED>(a, b, c, d, e),
+        Syscall::FutexWake => futex_wake::<PRIVILEGED>(a, b, c, d, e),
+        Syscall::Mmap => mmap(a, b, c, d, e),
+        Syscall::Munmap => munmap(a, b, c, d, e),
+        Syscall::Mprotect => mprotect(a, b, c, d, e),
+        Syscall::Fork => fork(a, b, c, d, e),
     }
 }
 
-fn log(a: usize, _: usize, _: usize, _: usize, _: usize) -> isize {
-    let string_pointer = a as *const &str;
-    let s: &str = unsafe { &*string_pointer };
+fn log<const PRIVILEGED: bool>(a: usize, _: usize, _: usize, _: usize, _: usize) -> isize {
+    let s = match str_arg::<PRIVILEGED>(a) {
+        Some(s) => s,
+        None => return Error::EFAULT.into(),
+    };
     print!("{}", s);
     0
 }
@@ -54,47 +86,324 @@ fn module_request<const PRIVILEGED: bool>(
     d: usize,
     e: usize,
 ) -> isize {
-    let string_pointer = a as *const &str;
-    let s: &str = unsafe { &*string_pointer };
-    crate::modules::raw_module_call(s, PRIVILEGED, [b, c, d, e])
-}
-
-fn exec(a: usize, b: usize, _: usize, _: usize, _: usize) -> isize {
-    let path: &str = unsafe { &*(a as *const &str) };
-    let args: &[&str] = unsafe { &*(b as *const &[&str]) };
-    let mut elf = vec![];
-    let fd = crate::modules::module_call("vfs", false, &VFSRequest::Open(path));
-    if fd < 0 {
-        return -1;
+    let s = match str_arg::<PRIVILEGED>(a) {
+        Some(s) => s,
+        None => return Error::EFAULT.into(),
+    };
+    crate::modules::raw_module_call(&s, PRIVILEGED, [b, c, d, e])
+}
+
+/// Read an executable. Files resident in memory, like the ones in the init-fs, are borrowed instead of copied.
+pub(crate) fn read_elf(path: &str) -> Result<Cow<'static, [u8]>, Error> {
+    let fd = Fd(Error::check(crate::modules::module_call(
+        "vfs",
+        true,
+        &VFSRequest::Open(path),
+    ))? as _);
+    let elf = match VFS.file_node(fd) {
+        Some(node) => match node.fs.resident(&node) {
+            Some(data) => Ok(Cow::Borrowed(data)),
+            None => read_file(fd).map(Cow::Owned),
+        },
+        None => Err(Error::EBADF),
+    };
+    crate::modules::module_call("vfs", true, &VFSRequest::Close(fd));
+    let elf = elf?;
+    if !elf.starts_with(b"\x7fELF") {
+        return Err(Error::ENOEXEC);
+    }
+    Ok(elf)
+}
+
+/// Read an executable for a new process, and check that it can be loaded.
+fn read_executable(path: &str) -> Result<Cow<'static, [u8]>, Error> {
+    let elf = read_elf(path)?;
+    if let Err(e) = elf_loader::check_executable(&elf) {
+        log!("[kernel] {}: {}", path, e);
+        return Err(Error::ENOEXEC);
     }
+    Ok(elf)
+}
+
+fn read_file(fd: Fd) -> Result<Vec<u8>, Error> {
+    let mut data = vec![];
     let mut buf = [0u8; 256];
     loop {
-        let size =
-            crate::modules::module_call("vfs", false, &VFSRequest::Read(Fd(fd as _), &mut buf));
-        if size > 0 {
-            elf.extend_from_slice(&buf[0..size as usize]);
-        } else if size < 0 {
-            return -1;
-        } else {
-            break;
+        let size = crate::modules::module_call("vfs", true, &VFSRequest::Read(fd, &mut buf));
+        match Error::check(size)? {
+            0 => return Ok(data),
+            size => data.extend_from_slice(&buf[0..size]),
+        }
+    }
+}
+
+/// Copy the arguments or environment of a new process. Strings with embedded NULs are rejected.
+fn to_cstrings(strs: &[Cow<str>]) -> Result<Vec<CString>, Error> {
+    strs.iter()
+        .map(|s| CString::new(s.as_bytes()).map_err(|_| Error::EINVAL))
+        .collect()
+}
+
+fn exec<const PRIVILEGED: bool>(a: usize, b: usize, c: usize, _: usize, _: usize) -> isize {
+    let (path, args) = match (str_arg::<PRIVILEGED>(a), str_array_arg::<PRIVILEGED>(b)) {
+        (Some(path), Some(args)) => (path, args),
+        _ => return Error::EFAULT.into(),
+    };
+    if !PRIVILEGED && !USER_MEMORY.check(c, size_of::<isize>(), true) {
+        return Error::EFAULT.into();
+    }
+    let args = match to_cstrings(&args) {
+        Ok(args) => args,
+        Err(e) => return e.into(),
+    };
+    let elf = match read_executable(&path) {
+        Ok(elf) => elf,
+        Err(e) => return e.into(),
+    };
+    let proc = UserTask::spawn_user_process(elf, args, vec![], None);
+    match PROCESS_MANAGER.wait_for_child(Some(proc.id())) {
+        Ok((pid, status)) => {
+            // Check again: Another thread may have unmapped the status while waiting.
+            if PRIVILEGED {
+                unsafe { *(c as *mut isize) = status };
+            } else if copy_to_user(&USER_MEMORY, c, status).is_none() {
+                return Error::EFAULT.into();
+            }
+            pid.0 as _
         }
+        Err(e) => e.into(),
     }
-    crate::modules::module_call("vfs", false, &VFSRequest::Close(Fd(fd as _)));
-    let proc = UserTask::spawn_user_process(elf, args);
-    proc.wait_for_completion();
+}
+
+fn spawn<const PRIVILEGED: bool>(a: usize, b: usize, c: usize, d: usize, _: usize) -> isize {
+    let (path, args, env) = match (
+        str_arg::<PRIVILEGED>(a),
+        str_array_arg::<PRIVILEGED>(b),
+        str_array_arg::<PRIVILEGED>(c),
+    ) {
+        (Some(path), Some(args), Some(env)) => (path, args, env),
+        _ => return Error::EFAULT.into(),
+    };
+    let (args, env) = match (to_cstrings(&args), to_cstrings(&env)) {
+        (Ok(args), Ok(env)) => (args, env),
+        (Err(e), _) | (_, Err(e)) => return e.into(),
+    };
+    let fds: Option<Cow<[[u32; 2]]>> = if PRIVILEGED {
+        unsafe { *(d as *const Option<&[[u32; 2]]>) }.map(Cow::Borrowed)
+    } else {
+        match copy_from_user::<[usize; 2]>(&USER_MEMORY, d) {
+            // `None`
+            Some([0, _]) => None,
+            Some([ptr, len]) => match copy_slice(ptr, len) {
+                Some(fds) => Some(Cow::Owned(fds)),
+                None => return Error::EFAULT.into(),
+            },
+            None => return Error::EFAULT.into(),
+        }
+    };
+    // `Fd` is a transparent wrapper of `u32`
+    let fds: Option<&[(Fd, Fd)]> = fds.as_deref().map(|fds| unsafe { transmute(fds) });
+    let fds = match VFS.capture_fds(fds) {
+        Some(fds) => fds,
+        None => return Error::EBADF.into(),
+    };
+    let elf = match read_executable(&path) {
+        Ok(elf) => elf,
+        Err(e) => return e.into(),
+    };
+    let proc = UserTask::spawn_user_process(elf, args, env, Some(fds));
     proc.id().0 as _
 }
 
-fn exit(_: usize, _: usize, _: usize, _: usize, _: usize) -> isize {
-    PROCESS_MANAGER.current_proc().unwrap().exit();
+fn fork(_: usize, _: usize, _: usize, _: usize, _: usize) -> isize {
+    let fds = match VFS.capture_fds(None) {
+        Some(fds) => fds,
+        None => return Error::EBADF.into(),
+    };
+    let proc = PROCESS_MANAGER.current_proc().unwrap();
+    let mm = MMState::of(&*proc).fork();
+    let task = PROCESS_MANAGER.current_task().unwrap();
+    let regs = <TargetArch as Arch>::Context::of(&*task).capture_user_frame();
+    drop(task);
+    let child = PROCESS_MANAGER.spawn(box UserTask::new_fork(mm, regs, fds));
+    child.id().0 as _
+}
+
+fn waitpid<const PRIVILEGED: bool>(a: usize, b: usize, _: usize, _: usize, _: usize) -> isize {
+    if !PRIVILEGED && !USER_MEMORY.check(b, size_of::<isize>(), true) {
+        return Error::EFAULT.into();
+    }
+    let child = if a == 0 { None } else { Some(ProcId(a)) };
+    match PROCESS_MANAGER.wait_for_child(child) {
+        Ok((pid, status)) => {
+            // Check again: Another thread may have unmapped the status while waiting.
+            if PRIVILEGED {
+                unsafe { *(b as *mut isize) = status };
+            } else if copy_to_user(&USER_MEMORY, b, status).is_none() {
+                return Error::EFAULT.into();
+            }
+            pid.0 as _
+        }
+        Err(e) => e.into(),
+    }
+}
+
+fn exit(a: usize, _: usize, _: usize, _: usize, _: usize) -> isize {
+    PROCESS_MANAGER.current_proc().unwrap().exit(a as _);
     SCHEDULER.schedule()
 }
 
+fn thread_spawn(a: usize, b: usize, c: usize, _: usize, _: usize) -> isize {
+    let proc = PROCESS_MANAGER.current_proc().unwrap();
+    // A null stack top asks the kernel to allocate the stack
+    if c != 0 {
+        let stack_top = Address::from(c);
+        if !USER_SPACE_MEMORY_RANGE.contains(&(stack_top - 1)) {
+            return Error::EINVAL.into();
+        }
+        // The kernel initializes the TLS block below the stack top
+        let tls = *MMState::of(&*proc).tls_template.lock_uninterruptible();
+        let tls_size = UserTask::tls_size(tls);
+        if !USER_MEMORY.check(c.wrapping_sub(tls_size), tls_size, true) {
+            return Error::EFAULT.into();
+        }
+    }
+    let task = proc.spawn_task(box UserTask::new_companion(a as _, b, Address::from(c)));
+    task.id().0 as _
+}
+
+fn thread_join(a: usize, _: usize, _: usize, _: usize, _: usize) -> isize {
+    let current = PROCESS_MANAGER.current_task().unwrap();
+    match PROCESS_MANAGER.get_task_by_id(TaskId(a)) {
+        Some(task) if task.id() == current.id() => Error::EDEADLK.into(),
+        Some(task) if task.proc().id() != current.proc().id() => Error::ESRCH.into(),
+        Some(task) => {
+            drop(current);
+            task.wait_for_completion();
+            0
+        }
+        // Already exited
+        None => 0,
+    }
+}
+
 fn thread_exit(_: usize, _: usize, _: usize, _: usize, _: usize) -> isize {
     // Note: `Task::current()` must be dropped before calling `schedule`.
-    PROCESS_MANAGER.current_task().unwrap().exit();
+    let task = PROCESS_MANAGER.current_task().unwrap();
+    UserTask::release_thread_stack(&*task.proc(), task.id());
+    task.exit();
+    drop(task);
     SCHEDULER.schedule()
 }
+fn sigreturn(_: usize, _: usize, _: usize, _: usize, _: usize) -> isize {
+    let task = PROCESS_MANAGER.current_task().unwrap();
+    let context = <TargetArch as Arch>::Context::of(&*task);
+    match unsafe { context.return_from_signal_handler() } {
+        Some((x0, mask)) => {
+            // Unblock the signal of the handler
+            let set_mask = ProcRequest::SigProcMask(SIG_SETMASK, mask);
+            crate::modules::module_call("pm", true, &set_mask);
+            x0
+        }
+        None => Error::EINVAL.into(),
+    }
+}
+
 fn halt(a: usize, _: usize, _: usize, _: usize, _: usize) -> isize {
     TargetArch::halt(a as _)
 }
+
+fn futex_wait<const PRIVILEGED: bool>(a: usize, b: usize, c: usize, d: usize, _: usize) -> isize {
+    let key = match FutexTable::key::<PRIVILEGED>(a) {
+        Some(key) => key,
+        None => return Error::EFAULT.into(),
+    };
+    // User words are copied with checks, since other threads may unmap them meanwhile
+    let load = || {
+        if PRIVILEGED && !USER_SPACE_MEMORY_RANGE.contains(&Address::from(a)) {
+            Some(unsafe { &*(a as *const AtomicU32) }.load(Ordering::SeqCst))
+        } else {
+            copy_from_user::<u32>(&USER_MEMORY, a)
+        }
+    };
+    let timeout = match c {
+        usize::MAX => None,
+        ns => Some(Duration::from_nanos(ns as _)),
+    };
+    // Only the kernel may wait uninterruptibly. Signals of user programs are delivered when the syscall returns.
+    let interruptible = !PRIVILEGED || d & FUTEX_UNINTERRUPTIBLE == 0;
+    FUTEXES.wait(key, load, b as _, timeout, interruptible)
+}
+
+fn futex_wake<const PRIVILEGED: bool>(a: usize, b: usize, _: usize, _: usize, _: usize) -> isize {
+    match FutexTable::key::<PRIVILEGED>(a) {
+        Some(key) => FUTEXES.wake(key, b) as _,
+        None => Error::EFAULT.into(),
+    }
+}
+
+/// Pages of a user memory range. The range must start at a page boundary.
+fn user_pages(start: usize, size: usize) -> Result<Range<Page>, Error> {
+    let end = start
+        .checked_add(size)
+        .ok_or(Error::EINVAL)?
+        .checked_add(Size4K::MASK)
+        .ok_or(Error::EINVAL)?
+        & !Size4K::MASK;
+    if size == 0
+        || start & Size4K::MASK != 0
+        || start < USER_SPACE_MEMORY_RANGE.start.as_usize()
+        || end > USER_SPACE_MEMORY_RANGE.end.as_usize()
+    {
+        return Err(Error::EINVAL);
+    }
+    Ok(Page::new(Address::from(start))..Page::new(Address::from(end)))
+}
+
+fn mmap(a: usize, b: usize, c: usize, _: usize, _: usize) -> isize {
+    let flags = match vma::prot_flags(c) {
+        Some(flags) => flags,
+        None => return Error::EINVAL.into(),
+    };
+    let proc = PROCESS_MANAGER.current_proc().unwrap();
+    let mm = MMState::of(&*proc);
+    let result = if a == 0 {
+        let pages = (b.saturating_add(Size4K::MASK)) >> Size4K::LOG_BYTES;
+        if pages == 0 {
+            return Error::EINVAL.into();
+        }
+        vma::map_anonymous(mm, None, pages, flags)
+    } else {
+        user_pages(a, b).and_then(|range| {
+            let pages = Page::steps_between(&range.start, &range.end).unwrap();
+            vma::map_anonymous(mm, Some(range.start), pages, flags)
+        })
+    };
+    match result {
+        Ok(range) => range.start.start().as_usize() as _,
+        Err(e) => e.into(),
+    }
+}
+
+fn munmap(a: usize, b: usize, _: usize, _: usize, _: usize) -> isize {
+    let range = match user_pages(a, b) {
+        Ok(range) => range,
+        Err(e) => return e.into(),
+    };
+    let proc = PROCESS_MANAGER.current_proc().unwrap();
+    vma::unmap(MMState::of(&*proc), range);
+    0
+}
+
+fn mprotect(a: usize, b: usize, c: usize, _: usize, _: usize) -> isize {
+    let (range, flags) = match (user_pages(a, b), vma::prot_flags(c)) {
+        (Ok(range), Some(flags)) => (range, flags),
+        (Err(e), _) => return e.into(),
+        (_, None) => return Error::EINVAL.into(),
+    };
+    let proc = PROCESS_MANAGER.current_proc().unwrap();
+    match vma::protect(MMState::of(&*proc), range, flags) {
+        Ok(()) => 0,
+        Err(e) => e.into(),
+    }
+}
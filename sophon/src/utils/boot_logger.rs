@@ -11,6 +11,11 @@ pub fn init(mmio: Address) {
     }
 }
 
+/// Switch back to the boot logger, after the module providing the system logger is unloaded.
+pub fn restore() {
+    unsafe { log::init(&BOOT_LOG) }
+}
+
 struct BootLogger(Option<Address>);
 
 impl BootLogger {
@@ -0,0 +1,98 @@
+#![feature(default_alloc_error_handler)]
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user;
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+use syscall::Error;
+
+/// In the data segment
+static mut GLOBAL: usize = 1;
+
+/// A futex word alone on its page, which is not written after the fork until `futex_after_fork`.
+#[repr(align(4096))]
+struct PageAligned(AtomicU32);
+
+static WORD: PageAligned = PageAligned(AtomicU32::new(0));
+
+/// Read the values from memory
+fn values(heap: &usize, stack: &usize) -> [usize; 3] {
+    use core::ptr::read_volatile;
+    unsafe {
+        [
+            read_volatile(&GLOBAL),
+            read_volatile(heap),
+            read_volatile(stack),
+        ]
+    }
+}
+
+/// Wait on `WORD` in a new thread, and wake it up from the current one. The page of the word is copy-on-write,
+/// and copied by the first write. Both threads must still find the same futex.
+fn futex_after_fork() -> bool {
+    let waiter = user::thread::spawn(|| {
+        while WORD.0.load(Ordering::SeqCst) == 0 {
+            let result = syscall::futex_wait(&WORD.0, 0, Some(Duration::from_secs(2)));
+            if result == Error::ETIMEDOUT.code() {
+                // The wake-up is lost
+                return false;
+            }
+        }
+        true
+    });
+    // Give the waiter time to block
+    syscall::futex_wait(&AtomicU32::new(0), 0, Some(Duration::from_millis(100)));
+    WORD.0.store(1, Ordering::SeqCst);
+    syscall::futex_wake(&WORD.0, 1);
+    waiter.join()
+}
+
+/// The child gets a copy of the parent's memory and working directory. Writes of either process are not seen
+/// by the other one, and futexes of the child still work on copy-on-write pages.
+#[no_mangle]
+pub extern "C" fn _start(_argc: isize, _argv: *const *const u8) -> isize {
+    user::sys::chdir("/etc").unwrap();
+    let mut heap = Box::new(1usize);
+    let mut stack = 1usize;
+    let pid = user::sys::fork().unwrap();
+    if pid == 0 {
+        // The parent writes concurrently, but the child keeps the values at the time of the fork
+        if values(&heap, &stack) != [1; 3] {
+            user::sys::exit(1)
+        }
+        if user::sys::cwd().as_deref() != Ok("/etc") {
+            user::sys::exit(2)
+        }
+        unsafe { GLOBAL = 2 };
+        *heap = 2;
+        stack = 2;
+        if values(&heap, &stack) != [2; 3] {
+            user::sys::exit(3)
+        }
+        if !futex_after_fork() {
+            user::sys::exit(4)
+        }
+        user::sys::exit(0)
+    }
+    unsafe { GLOBAL = 3 };
+    *heap = 3;
+    stack = 3;
+    let mut status = -1;
+    assert_eq!(user::sys::waitpid(pid, &mut status), Ok(pid));
+    if status != 0 {
+        println!("forktest: child failed with status {}", status);
+        user::sys::exit(1)
+    }
+    if values(&heap, &stack) != [3; 3] {
+        println!("forktest: the child's writes are seen by the parent");
+        user::sys::exit(1)
+    }
+    println!("forktest: ok");
+    user::sys::exit(0)
+}
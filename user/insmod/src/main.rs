@@ -0,0 +1,25 @@
+#![feature(default_alloc_error_handler)]
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user;
+
+use core::ffi::CStr;
+
+#[no_mangle]
+pub extern "C" fn _start(argc: isize, argv: *const *const u8) -> isize {
+    if argc == 0 {
+        println!("usage: insmod <path>");
+        user::sys::exit(1)
+    }
+    let c_str: &CStr = unsafe { CStr::from_ptr(argv.read() as _) };
+    let path = c_str.to_str().unwrap().trim();
+    match user::sys::insmod(path) {
+        Ok(_) => user::sys::exit(0),
+        Err(e) => {
+            println!("insmod: {}: {}", path, e);
+            user::sys::exit(1)
+        }
+    }
+}
@@ -0,0 +1,30 @@
+#![feature(default_alloc_error_handler)]
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user;
+
+use core::sync::atomic::AtomicU32;
+use core::time::Duration;
+
+/// How long the child keeps the parent waiting
+const CHILD_SLEEP: Duration = Duration::from_secs(10);
+
+/// Blocks in `waitpid` on a sleeping child, until the process is killed.
+#[no_mangle]
+pub extern "C" fn _start(_argc: isize, _argv: *const *const u8) -> isize {
+    let pid = user::sys::fork().unwrap();
+    if pid == 0 {
+        static WORD: AtomicU32 = AtomicU32::new(0);
+        syscall::futex_wait(&WORD, 0, Some(CHILD_SLEEP));
+        user::sys::exit(0)
+    }
+    let mut status = -1;
+    let result = user::sys::waitpid(pid, &mut status);
+    println!(
+        "killtest: waitpid returned {:?} before the process is killed",
+        result
+    );
+    user::sys::exit(1)
+}
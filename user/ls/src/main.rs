@@ -19,7 +19,13 @@ pub extern "C" fn _start(argc: isize, argv: *const *const u8) -> isize {
         let c_str: &CStr = unsafe { CStr::from_ptr(argv.read() as _) };
         c_str.to_str().unwrap().trim()
     };
-    let dir = user::sys::open(path).expect("ERROR: No such file or directory");
+    let dir = match user::sys::open(path) {
+        Ok(dir) => dir,
+        Err(e) => {
+            println!("ls: {}: {}", path, e);
+            user::sys::exit(1)
+        }
+    };
     for i in 0..100 {
         if let Ok(Some(x)) = user::sys::readdir(dir, i) {
             let child_path = if path == "/" {
@@ -27,15 +33,20 @@ pub extern "C" fn _start(argc: isize, argv: *const *const u8) -> isize {
             } else {
                 format!("{}/{}", path, x)
             };
-            let fd = user::sys::open(&child_path).unwrap();
-            if user::sys::readdir(fd, 0).is_ok() {
-                println!("{}/", x);
-            } else {
-                println!("{}", x);
+            match user::sys::open(&child_path) {
+                Ok(fd) => {
+                    if user::sys::readdir(fd, 0).is_ok() {
+                        println!("{}/", x);
+                    } else {
+                        println!("{}", x);
+                    }
+                    let _ = user::sys::close(fd);
+                }
+                Err(e) => println!("ls: {}: {}", child_path, e),
             }
         } else {
             break;
         }
     }
-    user::sys::exit()
+    user::sys::exit(0)
 }
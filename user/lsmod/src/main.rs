@@ -0,0 +1,35 @@
+#![feature(default_alloc_error_handler)]
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user;
+
+extern crate alloc;
+
+use alloc::vec;
+
+#[no_mangle]
+pub extern "C" fn _start(_argc: isize, _argv: *const *const u8) -> isize {
+    let mut buf = vec![0u8; 4096];
+    let len = match user::sys::lsmod(&mut buf) {
+        Ok(len) => len,
+        Err(e) => {
+            println!("lsmod: {}", e);
+            user::sys::exit(1)
+        }
+    };
+    println!("{:<16} {:>4} {:>8} {:>8}", "Module", "Id", "Size", "Calls");
+    let text = core::str::from_utf8(&buf[..len]).unwrap();
+    for line in text.lines() {
+        let mut fields = line.split(' ');
+        let (name, id, size, calls) = (
+            fields.next().unwrap_or(""),
+            fields.next().unwrap_or(""),
+            fields.next().unwrap_or(""),
+            fields.next().unwrap_or(""),
+        );
+        println!("{:<16} {:>4} {:>8} {:>8}", name, id, size, calls);
+    }
+    user::sys::exit(0)
+}
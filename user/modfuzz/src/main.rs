@@ -0,0 +1,89 @@
+#![feature(default_alloc_error_handler)]
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user;
+
+use dev::DevRequest;
+use syscall::module_calls::proc::ProcRequest;
+use user::sys::{Error, Fd, ModuleRequest, VFSRequest};
+
+/// Modules and the request ids they handle.
+const MODULES: &[(&str, &[usize])] = &[
+    ("vfs", <VFSRequest as ModuleRequest>::IDS),
+    ("pm", <ProcRequest as ModuleRequest>::IDS),
+    ("dev", <DevRequest as ModuleRequest>::IDS),
+    ("hello", &[]),
+];
+
+const ROUNDS: usize = 1000;
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 as _
+    }
+}
+
+fn id<'a>(request: &'a impl ModuleRequest<'a>) -> usize {
+    request.as_raw().id()
+}
+
+fn expect(module: &str, args: [usize; 4], expected: Error) {
+    let ret = user::sys::raw_module_call(module, args);
+    if ret != expected.code() {
+        println!(
+            "modfuzz: {}#{} {:x?}: returned {}",
+            module, args[0], args, ret
+        );
+        user::sys::exit(1)
+    }
+}
+
+/// Send module calls with random request ids and arguments. None of them should bring down the kernel.
+#[no_mangle]
+pub extern "C" fn _start(_argc: isize, _argv: *const *const u8) -> isize {
+    let mut rng = XorShift(0x5eed_1234_abcd_0001);
+    expect("no-such-module", [0; 4], Error::ENOSYS);
+    for _ in 0..ROUNDS {
+        let (module, known) = MODULES[rng.next() % MODULES.len()];
+        // Mostly small ids, close to the valid ones
+        let id = if rng.next() % 4 == 0 {
+            rng.next()
+        } else {
+            rng.next() % 32
+        };
+        if known.contains(&id) {
+            continue;
+        }
+        expect(
+            module,
+            [id, rng.next(), rng.next(), rng.next()],
+            Error::ENOSYS,
+        );
+    }
+    // VFS requests with a pointer argument
+    let pointer_requests = [
+        id(&VFSRequest::Open("")),
+        id(&VFSRequest::Read(Fd::STDIN, &mut [])),
+        id(&VFSRequest::Write(Fd::STDOUT, &[])),
+        id(&VFSRequest::ReadDir(Fd::STDIN, 0, &mut [])),
+        id(&VFSRequest::GetCwd(&mut [])),
+        id(&VFSRequest::SetCwd("")),
+    ];
+    for _ in 0..ROUNDS {
+        let id = pointer_requests[rng.next() % pointer_requests.len()];
+        expect(
+            "vfs",
+            [id, rng.next(), rng.next(), rng.next()],
+            Error::EFAULT,
+        );
+    }
+    println!("modfuzz: ok");
+    user::sys::exit(0)
+}
@@ -0,0 +1,64 @@
+#![feature(default_alloc_error_handler)]
+#![feature(thread_local)]
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user;
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::arch::asm;
+
+const THREADS: usize = 4;
+
+/// Initialized from the TLS template (`.tdata`)
+#[thread_local]
+static mut VALUE: usize = 0x5eed;
+
+/// Zero-initialized (`.tbss`)
+#[thread_local]
+static mut BUFFER: [usize; 16] = [0; 16];
+
+fn thread_pointer() -> usize {
+    let tp: usize;
+    unsafe { asm!("mrs {}, tpidr_el0", out(reg) tp) };
+    tp
+}
+
+/// Check the initial values, then store `id` and check that no other thread overwrote it.
+/// Returns the thread pointer.
+fn check(id: usize) -> usize {
+    unsafe {
+        assert_eq!(VALUE, 0x5eed);
+        assert!(BUFFER.iter().all(|x| *x == 0));
+        VALUE = id;
+        BUFFER.fill(id);
+        // Give the other threads time to run
+        for _ in 0..100000 {
+            core::hint::spin_loop();
+        }
+        assert_eq!(core::ptr::read_volatile(&VALUE), id);
+        assert!(core::ptr::read_volatile(&BUFFER).iter().all(|x| *x == id));
+    }
+    thread_pointer()
+}
+
+/// Threads get their own copies of `#[thread_local]` statics, and their own thread pointers.
+#[no_mangle]
+pub extern "C" fn _start(_argc: isize, _argv: *const *const u8) -> isize {
+    let threads: Vec<_> = (1..=THREADS)
+        .map(|id| user::thread::spawn(move || check(id)))
+        .collect();
+    let mut pointers: Vec<_> = threads.into_iter().map(|t| t.join()).collect();
+    pointers.push(check(THREADS + 1));
+    pointers.sort();
+    pointers.dedup();
+    if pointers.len() != THREADS + 1 || pointers[0] == 0 {
+        println!("tlstest: thread pointers are not distinct: {:x?}", pointers);
+        user::sys::exit(1)
+    }
+    println!("tlstest: ok");
+    user::sys::exit(0)
+}
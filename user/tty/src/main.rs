@@ -8,8 +8,11 @@ extern crate alloc;
 extern crate user;
 
 use alloc::{borrow::ToOwned, format, string::String, vec, vec::Vec};
+use user::signal::{SigHandler, SIGINT};
 use user::sys::Fd;
 
+const TTY_NICE: isize = -5;
+
 struct TTY {}
 
 impl TTY {
@@ -68,12 +71,9 @@ impl TTY {
             }
             "cd" => {
                 if args.len() == 1 {
-                    match user::sys::chdir(&args[0]) {
-                        Ok(_) => {}
-                        Err(_) => {
-                            println!("cd: no such file or directory");
-                        }
-                    };
+                    if let Err(e) = user::sys::chdir(&args[0]) {
+                        println!("cd: {}: {}", args[0], e);
+                    }
                 } else {
                     println!("Usage: cd <path>");
                 }
@@ -86,35 +86,65 @@ impl TTY {
         }
     }
 
-    fn exec_external_cmd(&self, cmd: &str, args: &[&str]) {
+    fn exec_external_cmd(&self, cmd: &str, args: &[&str], env: &[&str], background: bool) {
         let cmd = if !cmd.starts_with("/") && !cmd.starts_with(".") {
             format!("/bin/{}", cmd)
         } else {
             cmd.to_owned()
         };
-        if user::sys::exec(&cmd, args) == -1 {
-            // FIXME
-            println!("ERROR: command not found");
+        let pid = match user::sys::spawn(&cmd, args, env, None) {
+            Ok(pid) => pid,
+            Err(e) => {
+                println!("{}: {}", cmd, e);
+                return;
+            }
+        };
+        if background {
+            println!("[{}]", pid);
+        } else {
+            // Ctrl-C goes to the foreground job until it exits
+            let _ = user::signal::set_foreground(pid);
+            let mut status = 0;
+            let _ = user::sys::waitpid(pid, &mut status);
+            let _ = user::signal::set_foreground(0);
+            if status != 0 {
+                println!("{}: exited with status {}", cmd, status);
+            }
         }
     }
 
     pub fn run(&self) {
         println!("[[Sophon TTY]]");
+        // Keep the shell responsive when there are batch jobs running
+        let _ = user::sys::set_priority(0, TTY_NICE);
+        let _ = user::signal::signal(SIGINT, SigHandler::Ignore);
+        let _ = user::signal::set_foreground(0);
         loop {
             let cmd = self.prompt();
             // println!("{:?}", cmd);
 
-            let segments = cmd
+            let mut segments = cmd
                 .split(" ")
                 .map(|s| s.trim())
                 .filter(|s| !s.is_empty())
                 .collect::<Vec<_>>();
+            // `cmd args... &` runs in background
+            let background = segments.last() == Some(&"&");
+            if background {
+                segments.pop();
+            }
+            // Leading `KEY=VALUE` assignments are passed to the command as environment variables
+            let num_vars = segments.iter().take_while(|s| s.contains('=')).count();
+            let (env, segments) = segments.split_at(num_vars);
+            if segments.is_empty() {
+                continue;
+            }
             let cmd = segments[0];
             let args = &segments[1..];
             if self.is_internal_cmd(cmd) {
                 self.exec_internal_cmd(cmd, args)
             } else {
-                self.exec_external_cmd(cmd, args)
+                self.exec_external_cmd(cmd, args, env, background)
             }
         }
     }
@@ -124,5 +154,5 @@ impl TTY {
 pub extern "C" fn _start(_argc: isize, _argv: *const *const u8) -> isize {
     let tty = TTY::new();
     tty.run();
-    user::sys::exit()
+    user::sys::exit(0)
 }